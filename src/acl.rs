@@ -0,0 +1,262 @@
+extern crate toml;
+
+use super::auth;
+use super::filter::matches_pattern;
+use super::interceptor::Interceptor;
+use super::protos;
+use super::status::{Code, Status};
+use super::tls::PeerIdentity;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// The identity used for callers that didn't authenticate in any way.
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    roles: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    bindings: Vec<Binding>,
+}
+
+#[derive(Deserialize)]
+struct Binding {
+    identity: String,
+    roles: Vec<String>,
+}
+
+/// Maps caller identities to the methods they may call.
+///
+/// The configuration is TOML. Roles are lists of method patterns and bindings grant roles
+/// to identity patterns, where both kinds of pattern may contain `*` wildcards:
+///
+/// ```toml
+/// [roles]
+/// admin = ["admin.*"]
+/// reader = ["Get*", "Echo"]
+///
+/// [[bindings]]
+/// identity = "CN=ops*"
+/// roles = ["admin", "reader"]
+/// ```
+///
+/// A caller's identities are the identity it authenticated as with a token, the subject
+/// and subject alternative names of its client certificate, or `anonymous` if it has none.
+pub struct Acl {
+    // (identity pattern, method patterns granted to it)
+    grants: Vec<(String, Vec<String>)>,
+}
+
+impl Acl {
+    pub fn parse(config: &str) -> io::Result<Self> {
+        let config: Config = toml::from_str(config)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("invalid acl: {}", e)))?;
+
+        let mut grants = Vec::new();
+        for binding in config.bindings {
+            let mut methods = Vec::new();
+            for role in binding.roles.iter() {
+                match config.roles.get(role) {
+                    Some(m) => methods.extend(m.iter().cloned()),
+                    None => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("invalid acl: unknown role {}", role),
+                        ))
+                    }
+                }
+            }
+            grants.push((binding.identity, methods));
+        }
+        Ok(Acl { grants })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Acl::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Whether any of the identities may call `method`.
+    pub fn allows(self: &Self, identities: &[String], method: &str) -> bool {
+        self.grants.iter().any(|(pattern, methods)| {
+            identities.iter().any(|i| matches_pattern(pattern, i))
+                && methods.iter().any(|m| matches_pattern(m, method))
+        })
+    }
+}
+
+/// Collects every identity the server attached to a message.
+pub fn identities(msg: &protos::Message) -> Vec<String> {
+    let mut identities = Vec::new();
+    if let Some(identity) = msg.get_annotations().get(auth::IDENTITY_ANNOTATION) {
+        identities.push(identity.clone());
+    }
+    if let Some(peer) = PeerIdentity::of(msg) {
+        identities.push(peer.subject);
+        identities.extend(peer.sans);
+    }
+    if identities.is_empty() {
+        identities.push(ANONYMOUS.to_string());
+    }
+    identities
+}
+
+/// An ACL loaded from a file that can be swapped out while the server is running.
+///
+/// Implements `Interceptor`, rejecting calls that aren't allowed with PERMISSION_DENIED.
+/// Add it to the server's interceptors so requests are rejected before they are
+/// dispatched.
+pub struct AclStore {
+    path: PathBuf,
+    current: RwLock<Arc<Acl>>,
+}
+
+impl AclStore {
+    pub fn load(path: &Path) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(AclStore {
+            path: path.to_path_buf(),
+            current: RwLock::new(Arc::new(Acl::load(path)?)),
+        }))
+    }
+
+    /// Reloads the file. If it fails to load the current ACL stays in place.
+    pub fn reload(self: &Self) -> io::Result<()> {
+        let acl = Acl::load(&self.path)?;
+        *self.current.write().unwrap() = Arc::new(acl);
+        Ok(())
+    }
+
+    pub fn current(self: &Self) -> Arc<Acl> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Spawns a thread that reloads the file whenever its modification time changes.
+    pub fn watch(store: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let store = store.clone();
+        thread::spawn(move || {
+            let modified = |p: &Path| -> Option<SystemTime> {
+                fs::metadata(p).and_then(|m| m.modified()).ok()
+            };
+            let mut last = modified(&store.path);
+            loop {
+                thread::sleep(interval);
+                let current = modified(&store.path);
+                if current != last {
+                    last = current;
+                    match store.reload() {
+                        Ok(_) => println!("reloaded acl from {}", store.path.display()),
+                        Err(e) => println!("failed to reload acl: {}", e),
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl Interceptor for AclStore {
+    fn before(self: &Self, request: &protos::Message) -> Result<(), Status> {
+        if self.current().allows(&identities(request), request.get_method()) {
+            Ok(())
+        } else {
+            Err(Status::new(
+                Code::PermissionDenied,
+                &format!("not allowed to call {}", request.get_method()),
+            ))
+        }
+    }
+}
+
+impl Interceptor for Arc<AclStore> {
+    fn before(self: &Self, request: &protos::Message) -> Result<(), Status> {
+        (**self).before(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::{identities, Acl, AclStore};
+    use crate::auth;
+    use crate::interceptor;
+    use crate::protos;
+    use crate::server;
+    use crate::tls::PeerIdentity;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const CONFIG: &str = r#"
+        [roles]
+        admin = ["admin.*"]
+        reader = ["Get*", "Echo"]
+
+        [[bindings]]
+        identity = "CN=ops*"
+        roles = ["admin", "reader"]
+
+        [[bindings]]
+        identity = "svc.*"
+        roles = ["reader"]
+
+        [[bindings]]
+        identity = "anonymous"
+        roles = ["reader"]
+    "#;
+
+    #[test]
+    fn verify_acl() {
+        let acl = Acl::parse(CONFIG).unwrap();
+        let ops = ["CN=ops-1".to_string()];
+        let svc = ["svc.payments".to_string()];
+
+        assert!(acl.allows(&ops, "admin.reload"));
+        assert!(acl.allows(&ops, "GetUser"));
+        assert!(acl.allows(&svc, "Echo"));
+        assert!(!acl.allows(&svc, "admin.reload"));
+        assert!(!acl.allows(&["mallory".to_string()], "Echo"));
+        assert!(Acl::parse("[[bindings]]\nidentity = \"a\"\nroles = [\"missing\"]").is_err());
+    }
+
+    #[test]
+    fn verify_identities() {
+        let mut m = protos::Message::new();
+        assert_eq!(identities(&m), vec!["anonymous".to_string()]);
+
+        m.mut_annotations()
+            .insert(auth::IDENTITY_ANNOTATION.to_string(), "svc.payments".to_string());
+        PeerIdentity {
+            subject: "CN=ops-1".to_string(),
+            sans: vec!["ops-1.test".to_string()],
+        }
+        .annotate(&mut m);
+        assert_eq!(identities(&m), vec!["svc.payments", "CN=ops-1", "ops-1.test"]);
+    }
+
+    #[test]
+    fn verify_spoofed_identity() {
+        let path = env::temp_dir().join(format!("rplay-acl-{}.toml", process::id()));
+        fs::write(&path, CONFIG).unwrap();
+        let chain = interceptor::Chain::new().with(AclStore::load(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // Without an authenticator nothing vouches for the identity the client claims, so
+        // the call is treated as anonymous.
+        let mut m = protos::Message::new();
+        m.set_method("admin.reload".to_string());
+        m.mut_annotations()
+            .insert(auth::IDENTITY_ANNOTATION.to_string(), "CN=ops-1".to_string());
+        server::strip_identities(&mut m);
+        assert_eq!(identities(&m), vec!["anonymous".to_string()]);
+        assert!(server::admit(None, &chain, &mut m).is_err());
+
+        m.set_method("Echo".to_string());
+        assert!(server::admit(None, &chain, &mut m).is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+extern crate hex;
+extern crate hmac;
+extern crate jsonwebtoken;
+extern crate sha2;
+
+use self::hmac::{Hmac, Mac};
+use self::jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use self::sha2::Sha256;
+use super::protos;
+use super::status::{Code, Status};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Annotation carrying the caller's credentials, as `Bearer <token>`.
+pub const AUTHORIZATION_ANNOTATION: &str = "authorization";
+/// Annotation carrying the identity the server authenticated the caller as.
+pub const IDENTITY_ANNOTATION: &str = "identity";
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Validates a bearer token, returning the identity of its holder.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(self: &Self, token: &str) -> Result<String, Status>;
+}
+
+fn unauthenticated(msg: &str) -> Status {
+    Status::new(Code::Unauthenticated, msg)
+}
+
+/// Authenticates the message using its authorization annotation.
+///
+/// On success the credentials are replaced by the authenticated identity, so handlers and
+/// interceptors see who is calling without seeing their secrets. Any identity annotation
+/// sent by the caller is discarded.
+pub fn authenticate(
+    authenticator: &dyn Authenticator,
+    msg: &mut protos::Message,
+) -> Result<(), Status> {
+    let annotations = msg.mut_annotations();
+    annotations.remove(IDENTITY_ANNOTATION);
+    let credentials = annotations
+        .remove(AUTHORIZATION_ANNOTATION)
+        .ok_or_else(|| unauthenticated("missing credentials"))?;
+    if !credentials.starts_with(BEARER_PREFIX) {
+        return Err(unauthenticated("expected a bearer token"));
+    }
+
+    let identity = authenticator.authenticate(&credentials[BEARER_PREFIX.len()..])?;
+    annotations.insert(IDENTITY_ANNOTATION.to_string(), identity);
+    Ok(())
+}
+
+/// Attaches a bearer token to an outgoing message.
+pub fn attach_token(msg: &mut protos::Message, token: &str) {
+    msg.mut_annotations().insert(
+        AUTHORIZATION_ANNOTATION.to_string(),
+        format!("{}{}", BEARER_PREFIX, token),
+    );
+}
+
+/// A fixed set of tokens, each mapped to an identity.
+pub struct StaticTokens {
+    tokens: HashMap<String, String>,
+}
+
+impl StaticTokens {
+    pub fn new() -> Self {
+        StaticTokens {
+            tokens: HashMap::new(),
+        }
+    }
+
+    pub fn with(mut self: Self, token: &str, identity: &str) -> Self {
+        self.tokens.insert(token.to_string(), identity.to_string());
+        self
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(self: &Self, token: &str) -> Result<String, Status> {
+        self.tokens
+            .get(token)
+            .cloned()
+            .ok_or_else(|| unauthenticated("unknown token"))
+    }
+}
+
+/// Tokens of the form `<identity>.<expiry>.<signature>`, where expiry is in seconds since
+/// the epoch and signature is the hex encoded HMAC-SHA256 of `<identity>.<expiry>`.
+pub struct HmacTokens {
+    key: Vec<u8>,
+}
+
+impl HmacTokens {
+    pub fn new(key: &[u8]) -> Self {
+        HmacTokens { key: key.to_vec() }
+    }
+
+    fn mac(self: &Self, payload: &str) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length.
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).unwrap();
+        mac.input(payload.as_bytes());
+        mac
+    }
+
+    /// Issues a token for `identity` that is valid for `ttl`.
+    pub fn sign(self: &Self, identity: &str, ttl: Duration) -> String {
+        let expiry = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let payload = format!("{}.{}", identity, expiry);
+        let signature = hex::encode(self.mac(&payload).result().code());
+        format!("{}.{}", payload, signature)
+    }
+}
+
+impl Authenticator for HmacTokens {
+    fn authenticate(self: &Self, token: &str) -> Result<String, Status> {
+        let malformed = || unauthenticated("malformed token");
+        // The identity itself may contain dots, so split from the right.
+        let mut parts = token.rsplitn(3, '.');
+        let signature = parts.next().ok_or_else(malformed)?;
+        let expiry = parts.next().ok_or_else(malformed)?;
+        let identity = parts.next().ok_or_else(malformed)?;
+
+        let signature = hex::decode(signature).map_err(|_| malformed())?;
+        let payload = &token[..identity.len() + 1 + expiry.len()];
+        self.mac(payload)
+            .verify(&signature)
+            .map_err(|_| unauthenticated("invalid token signature"))?;
+
+        let expiry: u64 = expiry.parse().map_err(|_| malformed())?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if now >= expiry {
+            return Err(unauthenticated("token expired"));
+        }
+        Ok(identity.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// JSON web tokens verified with a local key. The `sub` claim is used as the identity and
+/// the `exp` claim is required.
+pub struct Jwt {
+    key: DecodingKey<'static>,
+    validation: Validation,
+}
+
+impl Jwt {
+    /// Verifies HS256 tokens signed with a shared secret.
+    pub fn hs256(secret: &[u8]) -> Self {
+        Jwt {
+            key: DecodingKey::from_secret(secret).into_static(),
+            validation: Validation::new(Algorithm::HS256),
+        }
+    }
+
+    /// Verifies RS256 tokens against a PEM encoded RSA public key.
+    pub fn rs256_pem(public_key: &[u8]) -> Result<Self, String> {
+        let key = DecodingKey::from_rsa_pem(public_key).map_err(|e| e.to_string())?;
+        Ok(Jwt {
+            key: key.into_static(),
+            validation: Validation::new(Algorithm::RS256),
+        })
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate(self: &Self, token: &str) -> Result<String, Status> {
+        jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims.sub)
+            .map_err(|e| unauthenticated(&format!("invalid token: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::jsonwebtoken::{self, EncodingKey, Header};
+    use crate::auth::{
+        attach_token, authenticate, Authenticator, HmacTokens, Jwt, StaticTokens,
+        IDENTITY_ANNOTATION,
+    };
+    use crate::protos;
+    use crate::status::Code;
+    use serde::Serialize;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn verify_static_tokens() {
+        let tokens = StaticTokens::new().with("secret", "alice");
+
+        let mut m = protos::Message::new();
+        attach_token(&mut m, "secret");
+        m.mut_annotations()
+            .insert(IDENTITY_ANNOTATION.to_string(), "mallory".to_string());
+        authenticate(&tokens, &mut m).unwrap();
+        assert_eq!(m.get_annotations().get(IDENTITY_ANNOTATION).unwrap(), "alice");
+        assert_eq!(m.get_annotations().len(), 1);
+
+        let mut m = protos::Message::new();
+        attach_token(&mut m, "wrong");
+        assert_eq!(
+            authenticate(&tokens, &mut m).unwrap_err().code,
+            Code::Unauthenticated
+        );
+
+        let mut m = protos::Message::new();
+        assert_eq!(
+            authenticate(&tokens, &mut m).unwrap_err().code,
+            Code::Unauthenticated
+        );
+    }
+
+    #[test]
+    fn verify_hmac_tokens() {
+        let tokens = HmacTokens::new(b"key");
+        let token = tokens.sign("svc.payments", Duration::from_secs(60));
+        assert_eq!(tokens.authenticate(&token).unwrap(), "svc.payments");
+
+        let expired = tokens.sign("svc.payments", Duration::from_secs(0));
+        assert!(tokens.authenticate(&expired).is_err());
+
+        let forged = token.replacen("payments", "billing", 1);
+        assert!(tokens.authenticate(&forged).is_err());
+        assert!(HmacTokens::new(b"other").authenticate(&token).is_err());
+        assert!(tokens.authenticate("garbage").is_err());
+    }
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: String,
+        exp: u64,
+    }
+
+    #[test]
+    fn verify_jwt() {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let claims = TestClaims {
+            sub: "bob".to_string(),
+            exp,
+        };
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        assert_eq!(Jwt::hs256(b"secret").authenticate(&token).unwrap(), "bob");
+        assert!(Jwt::hs256(b"other").authenticate(&token).is_err());
+    }
+}
@@ -1,63 +1,406 @@
 extern crate protobuf;
 
+use super::auth;
+use super::compression;
+use super::frame;
+use super::handshake;
+use super::heartbeat;
+use super::net::{Address, Connection, Stream};
 use super::protos;
-use mio::tcp::TcpStream;
+use super::tls;
 use mio::*;
 use protobuf::Message;
+use std::cmp;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Optional behaviour for a `Client`.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// When set, the connection is wrapped in a TLS session.
+    pub tls: Option<tls::ClientTls>,
+    /// When set, this bearer token is attached to every message.
+    pub token: Option<String>,
+    /// When set, the server is pinged and the connection dropped once it stops answering.
+    pub heartbeat: Option<heartbeat::Options>,
+    /// Whether to reconnect, and send the message again, when the connection is lost.
+    pub reconnect: bool,
+    /// When set, messages are compressed if the server supports one of the algorithms.
+    pub compression: Option<compression::Options>,
+    /// Whether to checksum every frame if the server supports it.
+    pub checksums: bool,
+}
+
+impl Options {
+    // The features to advertise in the handshake.
+    fn features(self: &Self) -> u32 {
+        let compression = self.compression.as_ref().map_or(0, |c| c.features());
+        if self.checksums {
+            compression | handshake::FEATURE_CHECKSUM
+        } else {
+            compression
+        }
+    }
+}
+
+fn envelope<T: protobuf::Message>(name: &String, msg: &T, options: &Options) -> protos::Message {
+    let mut wrapper = protos::Message::new();
+    wrapper.set_method(name.clone());
+    wrapper.set_body(msg.write_to_bytes().unwrap());
+    wrapper
+        .mut_annotations()
+        .insert(String::from("name"), name.to_string());
+    if let Some(ref token) = options.token {
+        auth::attach_token(&mut wrapper, token);
+    }
+    wrapper
+}
+
+// Adds a checksum to an encoded frame if both ends agreed on them.
+fn finish(frame: Vec<u8>, checksums: bool) -> Vec<u8> {
+    if checksums {
+        frame::add_checksum(frame)
+    } else {
+        frame
+    }
+}
+
+// Wraps the connection in a TLS session if the options ask for one.
+fn secure(stream: Box<dyn Connection>, options: &Options) -> io::Result<Box<dyn Connection>> {
+    match options.tls {
+        Some(ref t) => Ok(Box::new(tls::TlsConnection::new(
+            tls::TlsStream::client(stream, t),
+        )?)),
+        None => Ok(stream),
+    }
+}
+
+fn connect(addr: &Address, options: &Options) -> io::Result<Box<dyn Connection>> {
+    secure(Box::new(Stream::connect(addr)?), options)
+}
+
+// The longest we wait between reconnect attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
 
 pub struct Client {}
 
 impl Client {
-    pub fn send<T: protobuf::Message>(addr: &String, name: &String, msg: &T) {
-        let poll = Poll::new().unwrap();
-        let stream = TcpStream::connect(&addr.parse().unwrap()).unwrap();
+    pub fn send_with_options<T: protobuf::Message>(
+        addr: &str,
+        name: &String,
+        msg: &T,
+        options: &Options,
+    ) {
+        let address: Address = addr.parse().unwrap();
+        let mut backoff = Duration::from_millis(100);
+        loop {
+            let e = match Client::run(&address, name, msg, options) {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            println!("connection to {} lost: {}", address, e);
+            if !options.reconnect {
+                return;
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            println!("reconnecting to {}", address);
+        }
+    }
+
+    /// Sends a message over an established connection, e.g. one opened through a
+    /// `memory::MemoryConnector`, and returns the first response to it. The connection
+    /// isn't reestablished when it is lost, whatever `options.reconnect` says.
+    pub fn call<T: protobuf::Message>(
+        stream: Box<dyn Connection>,
+        name: &String,
+        msg: &T,
+        options: &Options,
+    ) -> io::Result<protos::Message> {
+        let mut response = None;
+        Client::exchange(secure(stream, options)?, name, msg, options, &mut |wrapper| {
+            response = Some(wrapper);
+            false
+        })?;
+        Ok(response.expect("stopped without a response"))
+    }
+
+    // Sends the message over a new connection and prints the responses, until the
+    // connection fails.
+    fn run<T: protobuf::Message>(
+        address: &Address,
+        name: &String,
+        msg: &T,
+        options: &Options,
+    ) -> io::Result<()> {
+        let stream = connect(address, options)?;
+        Client::exchange(stream, name, msg, options, &mut |wrapper| {
+            let mut ping = protos::Ping::new();
+            let mut cis = protobuf::CodedInputStream::from_bytes(wrapper.get_body());
+            ping.merge_from(&mut cis).unwrap();
+            println!("received ping with data {}", ping.get_data());
+            true
+        })
+    }
+
+    // Sends the message once the handshake is done and hands every response to
+    // `on_response`, until it returns false or the connection fails.
+    fn exchange<T: protobuf::Message>(
+        mut stream: Box<dyn Connection>,
+        name: &String,
+        msg: &T,
+        options: &Options,
+        on_response: &mut dyn FnMut(protos::Message) -> bool,
+    ) -> io::Result<()> {
+        let poll = Poll::new()?;
         poll.register(
-            &stream,
+            &*stream,
             Token(0),
             Ready::writable() | Ready::readable(),
             PollOpt::edge(),
         ).unwrap();
 
+        let mut heartbeat = options
+            .heartbeat
+            .clone()
+            .map(|h| heartbeat::Tracker::new(h, Instant::now()));
+        let tick = options.heartbeat.as_ref().map(|h| h.tick());
+
         let mut events = Events::with_capacity(1024);
-        let mut sent = false;
+        let mut decoder = frame::Decoder::new(frame::DEFAULT_MAX_FRAME_SIZE);
+        let hello = handshake::Hello::new(
+            options.features(),
+            frame::DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut compressor = None;
+        let mut checksums = false;
+        let mut hello_sent = false;
+        let mut negotiated = None;
         loop {
-            poll.poll(&mut events, None).unwrap();
+            poll.poll(&mut events, tick).unwrap();
 
             for e in events.iter() {
-                if !sent && e.readiness().is_writable() {
-                    let data = msg.write_to_bytes().unwrap();
-                    let mut wrapper = protos::Message::new();
-                    wrapper.set_body(data);
-                    wrapper
-                        .mut_annotations()
-                        .insert(String::from("name"), name.to_string());
-                    let msg_bytes = wrapper.write_to_bytes().unwrap();
-                    let buffers: [&IoVec; 1] = [msg_bytes.as_slice().into()];
-                    stream.write_bufs(&buffers).unwrap();
-                    sent = true;
+                if !hello_sent && e.readiness().is_writable() {
+                    stream.write_all(&hello.encode())?;
+                    hello_sent = true;
                 }
                 if e.readiness().is_readable() {
-                    let mut buffer = vec![0; 256];
-                    let mut size: usize;
-                    {
-                        {
-                            let mut buffer_ref: &mut [u8] = &mut buffer;
-                            let mut buffers: [&mut IoVec; 1] = [buffer_ref.into()];
-                            size = stream.read_bufs(&mut buffers).unwrap();
-                            println!("received {} bytes", size);
+                    let mut buffer = [0; 4096];
+                    // The frames that arrived before the server closed the connection are
+                    // still handled, e.g. a preface explaining why it rejected us.
+                    let mut eof = false;
+                    loop {
+                        match stream.read(&mut buffer) {
+                            Ok(0) => {
+                                eof = true;
+                                break;
+                            }
+                            Ok(size) => {
+                                println!("received {} bytes", size);
+                                decoder.extend(&buffer[..size]);
+                            }
+                            // A TLS stream may only have received handshake data.
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    if let Some(ref mut h) = heartbeat {
+                        h.received(Instant::now());
+                    }
+
+                    while let Some(f) = decoder.next_frame()? {
+                        if negotiated.is_none() {
+                            let n = handshake::Hello::decode(&f)
+                                .and_then(|remote| handshake::negotiate(&hello, &remote))?;
+                            decoder.set_max_frame_size(n.max_frame_size);
+                            checksums = n.supports(handshake::FEATURE_CHECKSUM);
+                            decoder.set_require_checksums(checksums);
+                            negotiated = Some(n);
+                            compressor = compression::choose(n.features).map(|algorithm| {
+                                compression::Compressor {
+                                    algorithm,
+                                    threshold: options
+                                        .compression
+                                        .as_ref()
+                                        .map_or(0, |c| c.threshold),
+                                }
+                            });
+
+                            let wrapper = envelope(name, msg, options);
+                            let data = compression::encode(&wrapper, compressor.as_ref());
+                            stream.write_all(&finish(data, checksums))?;
+                            continue;
+                        }
+                        if f.is_ping() {
+                            let pong = finish(frame::pong(&f.payload), checksums);
+                            stream.write_all(&pong)?;
+                            continue;
+                        }
+                        if f.is_pong() {
+                            continue;
+                        }
+
+                        let algorithm = compressor.as_ref().map(|c| c.algorithm);
+                        let max_size = decoder.max_frame_size();
+                        let wrapper = compression::decode_message(&f, algorithm, max_size)?;
+                        if !on_response(wrapper) {
+                            return Ok(());
                         }
-                        buffer.truncate(size);
-                        let mut wrapper = protos::Message::new();
-                        let mut cis = protobuf::CodedInputStream::from_bytes(&buffer);
-                        wrapper.merge_from(&mut cis).unwrap();
-
-                        let mut ping = protos::Ping::new();
-                        let mut cis = protobuf::CodedInputStream::from_bytes(wrapper.get_body());
-                        ping.merge_from(&mut cis).unwrap();
-                        println!("received ping with data {}", ping.get_data());
                     }
+                    if eof {
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+                    }
+                }
+            }
+
+            let action = match heartbeat {
+                Some(ref mut h) if negotiated.is_some() => h.poll(Instant::now()),
+                _ => continue,
+            };
+            match action {
+                heartbeat::Action::Wait => {}
+                heartbeat::Action::SendPing => {
+                    stream.write_all(&finish(frame::ping(&[]), checksums))?;
+                }
+                heartbeat::Action::Dead => {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "server missed heartbeats",
+                    ))
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::Api;
+    use crate::client::{Client, Options};
+    use crate::dispatcher::Dispatcher;
+    use crate::frame;
+    use crate::handshake::{Hello, PROTOCOL_VERSION};
+    use crate::memory;
+    use crate::protos;
+    use crate::server;
+    use protobuf::Message;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    struct EchoApi {}
+
+    impl Api<EchoApi> for EchoApi {
+        fn create_tls_api(self: &Self) -> EchoApi {
+            EchoApi {}
+        }
+    }
+
+    // Starts a server answering every message with its own method and body, reachable only
+    // through the returned connector.
+    fn serve() -> memory::MemoryConnector {
+        let (transport, connector) = memory::listen();
+        thread::spawn(move || {
+            let mut server = server::Server::bind_with_transports(
+                &[],
+                vec![Box::new(transport)],
+                server::Options::default(),
+            )
+            .unwrap();
+            let (sender, receiver) = mpsc::sync_channel(16);
+            server.add_listener(sender);
+            let _dispatcher = Dispatcher::new(
+                receiver,
+                2,
+                |msg: &protos::Message, _: &mut EchoApi| -> protos::Message {
+                    let mut m = protos::Message::new();
+                    m.set_method(msg.get_method().to_string());
+                    m.set_body(msg.get_body().to_vec());
+                    m
+                },
+                &EchoApi {},
+            );
+            server.start();
+        });
+        connector
+    }
+
+    fn ping(data: &str) -> protos::Ping {
+        let mut ping = protos::Ping::new();
+        ping.set_data(data.to_string());
+        ping
+    }
+
+    fn data(response: &protos::Message) -> String {
+        let mut ping = protos::Ping::new();
+        ping.merge_from_bytes(response.get_body()).unwrap();
+        ping.get_data().to_string()
+    }
+
+    #[test]
+    fn verify_call() {
+        let connector = serve();
+        let stream = Box::new(connector.connect());
+        let response = Client::call(
+            stream,
+            &"Echo".to_string(),
+            &ping("hello"),
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(response.get_method(), "Echo");
+        assert_eq!(data(&response), "hello");
+    }
+
+    #[test]
+    fn verify_incompatible_server() {
+        let (client_end, mut server_end) = memory::pair();
+        thread::spawn(move || {
+            // Waits for the client's preface, answers with one sharing no version with it
+            // and hangs up, like a server rejecting the client.
+            let mut buffer = [0; 64];
+            while let Err(ref e) = server_end.read(&mut buffer) {
+                assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                thread::sleep(Duration::from_millis(1));
+            }
+            let mut hello = Hello::new(0, frame::DEFAULT_MAX_FRAME_SIZE);
+            hello.min_version = PROTOCOL_VERSION + 1;
+            hello.max_version = PROTOCOL_VERSION + 1;
+            server_end.write_all(&hello.encode()).unwrap();
+        });
+        let e = Client::call(
+            Box::new(client_end),
+            &"Echo".to_string(),
+            &ping("hello"),
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().contains("incompatible protocol versions"));
+    }
+
+    #[test]
+    fn verify_concurrent_calls() {
+        let connector = serve();
+        let options = Options {
+            checksums: true,
+            ..Default::default()
+        };
+        let calls: Vec<_> = (0..4)
+            .map(|i| {
+                let stream = Box::new(connector.connect());
+                let options = options.clone();
+                thread::spawn(move || {
+                    let msg = ping(&i.to_string());
+                    Client::call(stream, &"Echo".to_string(), &msg, &options).unwrap()
+                })
+            })
+            .collect();
+        for (i, call) in calls.into_iter().enumerate() {
+            assert_eq!(data(&call.join().unwrap()), i.to_string());
+        }
+    }
+}
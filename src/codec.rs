@@ -0,0 +1,179 @@
+extern crate rmp_serde;
+extern crate serde_cbor;
+extern crate serde_json;
+
+use super::json;
+use super::protos;
+use super::status::{Code, Status};
+use protobuf::Message;
+
+/// Names the codec a message body is encoded with, protobuf when it is missing.
+pub const CONTENT_TYPE_ANNOTATION: &str = "content-type";
+
+/// Turns message bodies into protobuf messages and back.
+pub trait Codec: Send + Sync {
+    fn content_type(self: &Self) -> &'static str;
+
+    fn decode(self: &Self, data: &[u8], into: &mut dyn Message) -> Result<(), String>;
+
+    fn encode(self: &Self, msg: &dyn Message) -> Result<Vec<u8>, String>;
+}
+
+/// The protobuf binary encoding.
+pub struct Protobuf;
+
+impl Codec for Protobuf {
+    fn content_type(self: &Self) -> &'static str {
+        "application/x-protobuf"
+    }
+
+    fn decode(self: &Self, data: &[u8], into: &mut dyn Message) -> Result<(), String> {
+        into.merge_from_bytes(data).map_err(|e| e.to_string())
+    }
+
+    fn encode(self: &Self, msg: &dyn Message) -> Result<Vec<u8>, String> {
+        msg.write_to_bytes().map_err(|e| e.to_string())
+    }
+}
+
+/// The protobuf JSON mapping.
+pub struct Json;
+
+impl Codec for Json {
+    fn content_type(self: &Self) -> &'static str {
+        "application/json"
+    }
+
+    fn decode(self: &Self, data: &[u8], into: &mut dyn Message) -> Result<(), String> {
+        from_value(
+            serde_json::from_slice(data).map_err(|e| e.to_string())?,
+            into,
+        )
+    }
+
+    fn encode(self: &Self, msg: &dyn Message) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(&to_value(msg)?).map_err(|e| e.to_string())
+    }
+}
+
+// MessagePack and CBOR bodies hold the same structure as the JSON mapping, so they are
+// transcoded to and from it.
+fn from_value(value: serde_json::Value, into: &mut dyn Message) -> Result<(), String> {
+    json::merge_from_value(into, &value)
+}
+
+fn to_value(msg: &dyn Message) -> Result<serde_json::Value, String> {
+    Ok(json::to_value(msg))
+}
+
+/// MessagePack, structured like the protobuf JSON mapping.
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn content_type(self: &Self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn decode(self: &Self, data: &[u8], into: &mut dyn Message) -> Result<(), String> {
+        from_value(
+            rmp_serde::from_slice(data).map_err(|e| e.to_string())?,
+            into,
+        )
+    }
+
+    fn encode(self: &Self, msg: &dyn Message) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(&to_value(msg)?).map_err(|e| e.to_string())
+    }
+}
+
+/// CBOR, structured like the protobuf JSON mapping.
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn content_type(self: &Self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn decode(self: &Self, data: &[u8], into: &mut dyn Message) -> Result<(), String> {
+        from_value(
+            serde_cbor::from_slice(data).map_err(|e| e.to_string())?,
+            into,
+        )
+    }
+
+    fn encode(self: &Self, msg: &dyn Message) -> Result<Vec<u8>, String> {
+        serde_cbor::to_vec(&to_value(msg)?).map_err(|e| e.to_string())
+    }
+}
+
+static CODECS: [&dyn Codec; 4] = [&Protobuf, &Json, &MessagePack, &Cbor];
+
+/// Looks up the codec for a content type, ignoring parameters like `; charset=utf-8`.
+pub fn for_content_type(content_type: &str) -> Option<&'static dyn Codec> {
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+    if content_type == "application/protobuf" {
+        return Some(&Protobuf);
+    }
+    CODECS
+        .iter()
+        .find(|c| c.content_type() == content_type)
+        .cloned()
+}
+
+/// The codec the body of a message is encoded with.
+pub fn of(msg: &protos::Message) -> Result<&'static dyn Codec, Status> {
+    match msg.get_annotations().get(CONTENT_TYPE_ANNOTATION) {
+        None => Ok(&Protobuf),
+        Some(ct) => for_content_type(ct).ok_or_else(|| {
+            Status::new(
+                Code::InvalidArgument,
+                &format!("unsupported content type {}", ct),
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec;
+    use crate::protos;
+
+    #[test]
+    fn verify_round_trip() {
+        let mut ping = protos::Ping::new();
+        ping.set_data("hello".to_string());
+
+        for ct in [
+            "application/x-protobuf",
+            "application/json",
+            "application/msgpack",
+            "application/cbor",
+        ]
+            .iter()
+        {
+            let c = codec::for_content_type(ct).unwrap();
+            let encoded = c.encode(&ping).unwrap();
+            let mut decoded = protos::Ping::new();
+            c.decode(&encoded, &mut decoded).unwrap();
+            assert_eq!(decoded, ping);
+        }
+    }
+
+    #[test]
+    fn verify_json_body() {
+        let c = codec::for_content_type("application/json; charset=utf-8").unwrap();
+        let mut ping = protos::Ping::new();
+        c.decode(br#"{"data": "hi"}"#, &mut ping).unwrap();
+        assert_eq!(ping.get_data(), "hi");
+    }
+
+    #[test]
+    fn verify_unknown_content_type() {
+        let mut m = protos::Message::new();
+        m.mut_annotations().insert(
+            codec::CONTENT_TYPE_ANNOTATION.to_string(),
+            "text/plain".to_string(),
+        );
+        assert!(codec::of(&m).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+extern crate flate2;
+extern crate lz4_flex;
+extern crate zstd;
+
+use self::flate2::read::GzDecoder;
+use self::flate2::write::GzEncoder;
+use super::frame;
+use super::handshake;
+use super::protos;
+use protobuf::Message;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+// In order of preference. Both ends pick the first algorithm they have in common, so
+// they always agree.
+const ALGORITHMS: [Algorithm; 3] = [Algorithm::Zstd, Algorithm::Lz4, Algorithm::Gzip];
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn too_large(max_size: usize) -> io::Error {
+    invalid_data(format!(
+        "decompressed frame exceeds the limit of {} bytes",
+        max_size
+    ))
+}
+
+impl Algorithm {
+    /// The handshake feature advertising support for this algorithm.
+    pub fn feature(self: &Self) -> u32 {
+        match *self {
+            Algorithm::Gzip => handshake::FEATURE_GZIP,
+            Algorithm::Lz4 => handshake::FEATURE_LZ4,
+            Algorithm::Zstd => handshake::FEATURE_ZSTD,
+        }
+    }
+
+    pub fn compress(self: &Self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Algorithm::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Algorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Algorithm::Zstd => zstd::stream::encode_all(data, 0),
+        }
+    }
+
+    /// Decompresses `data`, failing if it would grow larger than `max_size`.
+    pub fn decompress(self: &Self, data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let limit = max_size as u64 + 1;
+        match *self {
+            Algorithm::Gzip => {
+                GzDecoder::new(data).take(limit).read_to_end(&mut out)?;
+            }
+            Algorithm::Lz4 => {
+                // The uncompressed size comes first, check it before allocating anything.
+                if data.len() < 4 {
+                    return Err(invalid_data("truncated lz4 frame".to_string()));
+                }
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if size > max_size {
+                    return Err(too_large(max_size));
+                }
+                out = lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| invalid_data(format!("failed to decompress: {:?}", e)))?;
+            }
+            Algorithm::Zstd => {
+                zstd::stream::Decoder::new(data)?
+                    .take(limit)
+                    .read_to_end(&mut out)?;
+            }
+        }
+        if out.len() > max_size {
+            return Err(too_large(max_size));
+        }
+        Ok(out)
+    }
+}
+
+/// Settings for compressing frames.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The algorithms we are willing to use.
+    pub algorithms: Vec<Algorithm>,
+    /// Messages smaller than this many bytes are sent uncompressed.
+    pub threshold: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            algorithms: ALGORITHMS.to_vec(),
+            threshold: 1024,
+        }
+    }
+}
+
+impl Options {
+    /// The handshake features to advertise.
+    pub fn features(self: &Self) -> u32 {
+        if self.algorithms.is_empty() {
+            return 0;
+        }
+        self.algorithms
+            .iter()
+            .fold(handshake::FEATURE_COMPRESSION, |f, a| f | a.feature())
+    }
+}
+
+/// Picks the algorithm to use from the features both ends agreed on, if any.
+pub fn choose(features: u32) -> Option<Algorithm> {
+    if features & handshake::FEATURE_COMPRESSION == 0 {
+        return None;
+    }
+    ALGORITHMS
+        .iter()
+        .find(|a| features & a.feature() != 0)
+        .cloned()
+}
+
+/// Compresses the frames sent on a connection.
+#[derive(Clone, Debug)]
+pub struct Compressor {
+    pub algorithm: Algorithm,
+    pub threshold: usize,
+}
+
+impl Compressor {
+    /// Encodes a message into a frame, compressing it if it is large enough and
+    /// compression actually makes it smaller.
+    pub fn encode(self: &Self, msg: &protos::Message) -> Vec<u8> {
+        let payload = msg.write_to_bytes().unwrap();
+        if payload.len() >= self.threshold {
+            match self.algorithm.compress(&payload) {
+                Ok(ref compressed) if compressed.len() < payload.len() => {
+                    return frame::encode_payload(frame::FLAG_COMPRESSED, compressed)
+                }
+                Ok(_) => {}
+                Err(e) => println!("failed to compress: {}", e),
+            }
+        }
+        frame::encode_payload(0, &payload)
+    }
+}
+
+/// Encodes a message into a frame, compressing it when there is a compressor.
+pub fn encode(msg: &protos::Message, compressor: Option<&Compressor>) -> Vec<u8> {
+    match compressor {
+        Some(c) => c.encode(msg),
+        None => frame::encode(msg),
+    }
+}
+
+/// Decodes the message in a frame, decompressing it with the algorithm agreed on for the
+/// connection if it is compressed.
+pub fn decode_message(
+    f: &frame::Frame,
+    algorithm: Option<Algorithm>,
+    max_size: usize,
+) -> io::Result<protos::Message> {
+    if !f.is_compressed() {
+        return frame::decode_message(&f.payload);
+    }
+    match algorithm {
+        Some(a) => frame::decode_message(&a.decompress(&f.payload, max_size)?),
+        None => Err(invalid_data(
+            "received a compressed frame without agreeing on compression".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{choose, decode_message, Algorithm, Compressor, Options};
+    use crate::frame::Decoder;
+    use crate::handshake;
+    use crate::protos;
+
+    #[test]
+    fn verify_round_trip() {
+        let mut m = protos::Message::new();
+        m.set_body(vec![7; 4096]);
+
+        for algorithm in [Algorithm::Gzip, Algorithm::Lz4, Algorithm::Zstd].iter() {
+            let compressor = Compressor {
+                algorithm: *algorithm,
+                threshold: 1024,
+            };
+            let mut decoder = Decoder::new(1 << 20);
+            decoder.extend(&compressor.encode(&m));
+            let f = decoder.next_frame().unwrap().unwrap();
+            assert!(f.is_compressed());
+            assert!(f.payload.len() < 4096);
+            assert_eq!(decode_message(&f, Some(*algorithm), 1 << 20).unwrap(), m);
+
+            // Decompressing past the frame size limit fails.
+            assert!(decode_message(&f, Some(*algorithm), 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn verify_threshold() {
+        let mut m = protos::Message::new();
+        m.set_body(vec![7; 16]);
+        let compressor = Compressor {
+            algorithm: Algorithm::Zstd,
+            threshold: 1024,
+        };
+        let mut decoder = Decoder::new(1024);
+        decoder.extend(&compressor.encode(&m));
+        assert!(!decoder.next_frame().unwrap().unwrap().is_compressed());
+    }
+
+    #[test]
+    fn verify_choose() {
+        let gzip_only = Options {
+            algorithms: vec![Algorithm::Gzip],
+            ..Default::default()
+        };
+        let features = Options::default().features() & gzip_only.features();
+        assert_eq!(choose(features), Some(Algorithm::Gzip));
+        assert_eq!(choose(Options::default().features()), Some(Algorithm::Zstd));
+        assert_eq!(choose(handshake::FEATURE_ZSTD), None);
+    }
+}
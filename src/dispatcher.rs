@@ -1,18 +1,80 @@
+extern crate crossbeam_deque;
+
+use self::crossbeam_deque::{Injector, Stealer, Worker};
 use super::api;
+use super::executor;
+use super::interceptor;
 use super::protos;
 use super::server;
-use std::collections::HashMap;
+use super::shedding;
+use std::cell::RefCell;
+use std::future::Future;
+use std::iter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// The future returned by an async handler. It is polled on the worker thread that
+/// created it, so it doesn't need to be `Send`.
+///
+/// Async handlers are given the worker's thread local api as an `Rc<RefCell<T>>` they
+/// can keep in the future. Every request in flight on the worker shares it, so borrow it
+/// between awaits rather than across one.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = protos::Message>>>;
+
+/// Controls how the receive thread hands messages to the workers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Every message is sent to a single worker over its own channel, and messages
+    /// belonging to a session always end up on the same worker.
+    Session,
+    /// Messages without a session are pushed onto a shared queue that the workers
+    /// steal from, so no single worker becomes a bottleneck. Messages with a session
+    /// are still pinned to a worker.
+    WorkStealing,
+}
+
+/// Optional behaviour for a `Dispatcher`.
+pub struct Options {
+    pub mode: Mode,
+    /// Interceptors run by the workers around every handler invocation.
+    pub interceptors: Arc<interceptor::Chain>,
+    /// How many requests a single async worker works on concurrently. Only used by
+    /// dispatchers created with `new_async`.
+    pub max_in_flight: usize,
+    /// How many requests without a session may wait on the shared queue in
+    /// `Mode::WorkStealing`. Once it is full the dispatcher stops taking requests off its
+    /// receiver, which pushes back on the server.
+    pub max_queued: usize,
+    /// When set, requests are shed once they spend too long queued, see
+    /// `shedding::Shedder`.
+    pub load_shedding: Option<shedding::Options>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            mode: Mode::Session,
+            interceptors: Arc::new(interceptor::Chain::new()),
+            max_in_flight: 64,
+            max_queued: 1024,
+            load_shedding: None,
+        }
+    }
+}
 
 pub struct Dispatcher {
     _receive_thread: JoinHandle<()>,
 }
 
 impl Dispatcher {
+    #[cfg(test)]
     pub fn new<F, S, A, T>(
         receiver: Receiver<(Arc<protos::Message>, S)>,
         num_workers: u32,
@@ -25,89 +87,494 @@ impl Dispatcher {
         A: api::Api<T>,
         T: Send + 'static,
     {
-        let mut ready_channels: Vec<Receiver<bool>> = Vec::new();
-        let mut sender_channels: Vec<Sender<(Arc<protos::Message>, S)>> = Vec::new();
-        let mut threads: Vec<JoinHandle<()>> = Vec::new();
-
-        let mut handler = f;
-
-        for _ in 0..num_workers {
-            let (ready_sender, ready_receiver) = mpsc::channel();
-            ready_channels.push(ready_receiver);
-            let (work_sender, work_receiver) = mpsc::channel();
-            sender_channels.push(work_sender);
-
-            let mut tls_api = api.create_tls_api();
-            threads.push(thread::spawn(move || {
-                let mut api = tls_api;
-                ready_sender.send(true).unwrap();
-                loop {
-                    match work_receiver.recv() {
-                        Ok((msg, sender)) => {
-                            let response = handler(&msg, &mut api);
-                            sender.send(Arc::new(response)).unwrap();
-                            match ready_sender.send(true) {
+        Dispatcher::with_options(receiver, num_workers, f, api, Options::default())
+    }
+
+    pub fn with_options<F, S, A, T>(
+        receiver: Receiver<(Arc<protos::Message>, S)>,
+        num_workers: u32,
+        f: F,
+        api: &A,
+        options: Options,
+    ) -> Self
+    where
+        F: Send + Sync + 'static + Copy + FnMut(&protos::Message, &mut T) -> protos::Message,
+        S: server::MessageSender + Send + Clone + 'static,
+        A: api::Api<T>,
+        T: Send + 'static,
+    {
+        let shedder = shedder(&options);
+        Dispatcher {
+            _receive_thread: match options.mode {
+                Mode::Session => {
+                    spawn_session(receiver, num_workers, f, api, options.interceptors, shedder)
+                }
+                Mode::WorkStealing => spawn_work_stealing(
+                    receiver,
+                    num_workers,
+                    f,
+                    api,
+                    options.interceptors,
+                    shedder,
+                    options.max_queued,
+                ),
+            },
+        }
+    }
+
+    /// Creates a dispatcher whose handler returns a future. Each worker drives the futures
+    /// it creates on its own executor, so a single worker can wait on many requests at
+    /// once while still owning its thread local api, see `HandlerFuture`. Messages are
+    /// always routed as in `Mode::Session`.
+    pub fn new_async<F, S, A, T>(
+        receiver: Receiver<(Arc<protos::Message>, S)>,
+        num_workers: u32,
+        f: F,
+        api: &A,
+        options: Options,
+    ) -> Self
+    where
+        F: Send
+            + Sync
+            + 'static
+            + Copy
+            + FnMut(Arc<protos::Message>, Rc<RefCell<T>>) -> HandlerFuture,
+        S: server::MessageSender + Send + Clone + 'static,
+        A: api::Api<T>,
+        T: Send + 'static,
+    {
+        Dispatcher {
+            _receive_thread: spawn_async(receiver, num_workers, f, api, options),
+        }
+    }
+}
+
+fn shedder(options: &Options) -> Option<Arc<shedding::Shedder>> {
+    options
+        .load_shedding
+        .clone()
+        .map(|o| Arc::new(shedding::Shedder::new(o)))
+}
+
+// Whether a request about to be queued should be, rather than shed right away.
+fn admit_early(shedder: &Option<Arc<shedding::Shedder>>, msg: &protos::Message) -> bool {
+    shedder.as_ref().is_none_or(|s| s.admit_early(msg))
+}
+
+// Whether a request that was queued at `enqueued` should be handled, rather than shed.
+fn admit(
+    shedder: &Option<Arc<shedding::Shedder>>,
+    msg: &protos::Message,
+    enqueued: Instant,
+) -> bool {
+    shedder.as_ref().is_none_or(|s| s.admit(msg, enqueued))
+}
+
+// Messages sent from the session router to a worker.
+enum Work<S> {
+    // A request along with when it was queued.
+    Request(Arc<protos::Message>, S, Instant),
+    // Wakes up the async task with the given id.
+    Wake(usize),
+    // The router is gone, finish up and exit.
+    Shutdown,
+}
+
+fn spawn_session<F, S, A, T>(
+    receiver: Receiver<(Arc<protos::Message>, S)>,
+    num_workers: u32,
+    f: F,
+    api: &A,
+    interceptors: Arc<interceptor::Chain>,
+    shedder: Option<Arc<shedding::Shedder>>,
+) -> JoinHandle<()>
+where
+    F: Send + Sync + 'static + Copy + FnMut(&protos::Message, &mut T) -> protos::Message,
+    S: server::MessageSender + Send + Clone + 'static,
+    A: api::Api<T>,
+    T: Send + 'static,
+{
+    // Workers post their index here whenever they can take on another request.
+    let (ready_sender, ready_receiver) = mpsc::channel();
+    let mut sender_channels: Vec<Sender<Work<S>>> = Vec::new();
+    let mut threads: Vec<JoinHandle<()>> = Vec::new();
+
+    let mut handler = f;
+
+    for i in 0..num_workers as usize {
+        let ready_sender = ready_sender.clone();
+        let (work_sender, work_receiver) = mpsc::channel();
+        sender_channels.push(work_sender);
+
+        let interceptors = interceptors.clone();
+        let shedder = shedder.clone();
+        let tls_api = api.create_tls_api();
+        threads.push(thread::spawn(move || {
+            let mut api = tls_api;
+            ready_sender.send(i).unwrap();
+            loop {
+                match work_receiver.recv() {
+                    Ok(Work::Request(msg, sender, enqueued)) => {
+                        let response = if admit(&shedder, &msg, enqueued) {
+                            interceptors.intercept(&msg, || handler(&msg, &mut api))
+                        } else {
+                            shedding::rejection()
+                        };
+                        sender.send(Arc::new(response)).unwrap();
+                        let _ = ready_sender.send(i);
+                    }
+                    Ok(Work::Wake(_)) => {}
+                    Ok(Work::Shutdown) | Err(_) => break,
+                }
+            }
+        }));
+    }
+
+    route_sessions(receiver, ready_receiver, sender_channels, shedder)
+}
+
+fn spawn_async<F, S, A, T>(
+    receiver: Receiver<(Arc<protos::Message>, S)>,
+    num_workers: u32,
+    f: F,
+    api: &A,
+    options: Options,
+) -> JoinHandle<()>
+where
+    F: Send + Sync + 'static + Copy + FnMut(Arc<protos::Message>, Rc<RefCell<T>>) -> HandlerFuture,
+    S: server::MessageSender + Send + Clone + 'static,
+    A: api::Api<T>,
+    T: Send + 'static,
+{
+    // Workers post their index here whenever they can take on another request.
+    let (ready_sender, ready_receiver) = mpsc::channel();
+    let mut sender_channels: Vec<Sender<Work<S>>> = Vec::new();
+    let mut threads: Vec<JoinHandle<()>> = Vec::new();
+
+    let mut handler = f;
+    let shedder = shedder(&options);
+
+    for i in 0..num_workers as usize {
+        let ready_sender = ready_sender.clone();
+        let (work_sender, work_receiver) = mpsc::channel();
+
+        // Tasks are woken by posting their id back onto the worker's own channel, so a
+        // single blocking recv covers both new requests and progress on old ones.
+        let wake_sender = Mutex::new(work_sender.clone());
+        sender_channels.push(work_sender);
+
+        let interceptors = options.interceptors.clone();
+        let max_in_flight = options.max_in_flight;
+        let shedder = shedder.clone();
+        let tls_api = api.create_tls_api();
+        threads.push(thread::spawn(move || {
+            let api = Rc::new(RefCell::new(tls_api));
+            let mut executor = executor::Executor::new(move |id| {
+                let _ = wake_sender.lock().unwrap().send(Work::Wake(id));
+            });
+            for _ in 0..max_in_flight {
+                ready_sender.send(i).unwrap();
+            }
+
+            let mut shutting_down = false;
+            while !(shutting_down && executor.is_empty()) {
+                match work_receiver.recv() {
+                    Ok(Work::Request(msg, sender, enqueued)) => {
+                        if !admit(&shedder, &msg, enqueued) {
+                            let _ = sender.send(Arc::new(shedding::rejection()));
+                            let _ = ready_sender.send(i);
+                            continue;
+                        }
+
+                        let (ran, rejected) = interceptors.run_before(&msg);
+                        if let Some(mut response) = rejected {
+                            interceptors.run_after(ran, &msg, &mut response);
+                            let _ = sender.send(Arc::new(response));
+                            let _ = ready_sender.send(i);
+                            continue;
+                        }
+
+                        let future = handler(msg.clone(), api.clone());
+                        let interceptors = interceptors.clone();
+                        let ready_sender = ready_sender.clone();
+                        executor.spawn(Box::pin(async move {
+                            let mut response = future.await;
+                            interceptors.run_after(ran, &msg, &mut response);
+                            match sender.send(Arc::new(response)) {
                                 Ok(_) => {}
-                                Err(_) => {}
+                                Err(_) => println!("failed to send response"),
                             }
-                        }
-                        Err(_) => break,
+                            let _ = ready_sender.send(i);
+                        }));
                     }
+                    Ok(Work::Wake(id)) => executor.poll(id),
+                    Ok(Work::Shutdown) => shutting_down = true,
+                    // The executor holds on to a sender, so this can't happen.
+                    Err(_) => break,
                 }
-            }));
+            }
+        }));
+    }
+
+    route_sessions(receiver, ready_receiver, sender_channels, shedder)
+}
+
+// Spawns the thread that reads messages off the receiver and hands them to workers that
+// have signaled they are ready, keeping each session on the same worker. While no worker
+// is ready messages wait on the receiver, pushing back on the server.
+fn route_sessions<S>(
+    receiver: Receiver<(Arc<protos::Message>, S)>,
+    ready: Receiver<usize>,
+    sender_channels: Vec<Sender<Work<S>>>,
+    shedder: Option<Arc<shedding::Shedder>>,
+) -> JoinHandle<()>
+where
+    S: server::MessageSender + Send + Clone + 'static,
+{
+    thread::spawn(move || {
+        // Keep reading messages off receiver. If the other end is destroyed,
+        // simply stop looping as we're about to be clened up.
+        while let Ok((msg, sender)) = receiver.recv() {
+            // Time spent waiting for a ready worker counts as time in queue.
+            let received = Instant::now();
+            if !admit_early(&shedder, &msg) {
+                let _ = sender.send(Arc::new(shedding::rejection()));
+                continue;
+            }
+
+            let session = msg.as_ref().get_session();
+            if session == 0 {
+                let i = match ready.recv() {
+                    Ok(i) => i,
+                    // Every worker is gone.
+                    Err(_) => break,
+                };
+                sender_channels[i]
+                    .send(Work::Request(msg, sender, received))
+                    .unwrap();
+            } else {
+                sender_channels[session as usize % sender_channels.len()]
+                    .send(Work::Request(msg, sender, received))
+                    .unwrap();
+            }
         }
 
-        Dispatcher {
-            _receive_thread: thread::spawn(move || {
-                let mut stream_sessions: HashMap<u64, usize> = HashMap::new();
-                // Keep reading messages off receiver. If the other end is destroyed,
-                // simply stop looping as we're about to be clened up.
-                loop {
-                    match receiver.recv() {
-                        Ok((msg, sender)) => {
-                            let session = msg.as_ref().get_session();
-                            if session == 0 {
-                                let mut s = 0;
-                                while stream_sessions.contains_key(&s) {
-                                    s += 1;
-                                }
-                                for (i, w) in ready_channels.iter().enumerate() {
-                                    match w.try_recv() {
-                                        Ok(_) => {
-                                            stream_sessions.insert(s, i);
-                                            sender_channels[i]
-                                                .send((msg.clone(), sender.clone()))
-                                                .unwrap();
-                                            break;
-                                        }
-                                        Err(_) => {}
-                                    }
-                                }
-                            } else {
-                                sender_channels[session as usize]
-                                    .send((msg.clone(), sender.clone()))
-                                    .unwrap();
+        for s in sender_channels.iter() {
+            let _ = s.send(Work::Shutdown);
+        }
+    })
+}
+
+// A request waiting for a work stealing worker, along with when it was queued.
+type Queued<S> = (Arc<protos::Message>, S, Instant);
+
+// The work stealing threads that are parked, so whoever gives them something to do knows
+// whom to unpark.
+#[derive(Default)]
+struct Sleepers {
+    // Workers without work.
+    workers: Mutex<Vec<thread::Thread>>,
+    // The receive thread, while the queue is full.
+    router: Mutex<Option<thread::Thread>>,
+}
+
+impl Sleepers {
+    // Wakes one idle worker, if any.
+    fn wake_worker(self: &Self) {
+        if let Some(t) = self.workers.lock().unwrap().pop() {
+            t.unpark();
+        }
+    }
+
+    // Wakes the receive thread if it is waiting for room in the queue.
+    fn wake_router(self: &Self) {
+        if let Some(t) = self.router.lock().unwrap().take() {
+            t.unpark();
+        }
+    }
+}
+
+fn spawn_work_stealing<F, S, A, T>(
+    receiver: Receiver<(Arc<protos::Message>, S)>,
+    num_workers: u32,
+    f: F,
+    api: &A,
+    interceptors: Arc<interceptor::Chain>,
+    shedder: Option<Arc<shedding::Shedder>>,
+    max_queued: usize,
+) -> JoinHandle<()>
+where
+    F: Send + Sync + 'static + Copy + FnMut(&protos::Message, &mut T) -> protos::Message,
+    S: server::MessageSender + Send + Clone + 'static,
+    A: api::Api<T>,
+    T: Send + 'static,
+{
+    // Requests on the shared queue or the workers' local queues.
+    let queued = Arc::new(AtomicUsize::new(0));
+    let injector: Arc<Injector<Queued<S>>> = Arc::new(Injector::new());
+    let locals: Vec<Worker<Queued<S>>> = (0..num_workers).map(|_| Worker::new_fifo()).collect();
+    let stealers: Arc<Vec<Stealer<Queued<S>>>> =
+        Arc::new(locals.iter().map(|w| w.stealer()).collect());
+
+    let sleepers = Arc::new(Sleepers::default());
+
+    let mut pinned_channels: Vec<Sender<Queued<S>>> = Vec::new();
+    let mut threads: Vec<JoinHandle<()>> = Vec::new();
+
+    let mut handler = f;
+
+    for local in locals {
+        let (pinned_sender, pinned_receiver) = mpsc::channel();
+        pinned_channels.push(pinned_sender);
+
+        let injector = injector.clone();
+        let stealers = stealers.clone();
+        let queued = queued.clone();
+        let sleepers = sleepers.clone();
+        let interceptors = interceptors.clone();
+        let shedder = shedder.clone();
+        let tls_api = api.create_tls_api();
+        threads.push(thread::spawn(move || {
+            let mut api = tls_api;
+            let take_work = || {
+                let work = find_work(&local, &injector, &stealers);
+                if work.is_some() {
+                    let left = queued.fetch_sub(1, Ordering::SeqCst) - 1;
+                    if left + 1 == max_queued {
+                        sleepers.wake_router();
+                    }
+                    // Part of what is left may sit in our local queue while we are busy,
+                    // so have an idle sibling come and steal it.
+                    if left > 0 {
+                        sleepers.wake_worker();
+                    }
+                }
+                work
+            };
+            let mut handle = |msg: &protos::Message, enqueued: Instant| {
+                if admit(&shedder, msg, enqueued) {
+                    interceptors.intercept(msg, || handler(msg, &mut api))
+                } else {
+                    shedding::rejection()
+                }
+            };
+            loop {
+                // Session traffic goes first so it isn't starved by stateless work.
+                let pinned = match pinned_receiver.try_recv() {
+                    Ok(work) => Some(work),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => {
+                        // The receive thread is gone, finish whatever is left and stop.
+                        match take_work() {
+                            Some((msg, sender, enqueued)) => {
+                                let response = handle(&msg, enqueued);
+                                let _ = sender.send(Arc::new(response));
+                                continue;
                             }
+                            None => break,
                         }
-                        Err(_) => break,
+                    }
+                };
+
+                match pinned.or_else(take_work) {
+                    Some((msg, sender, enqueued)) => {
+                        let response = handle(&msg, enqueued);
+                        match sender.send(Arc::new(response)) {
+                            Ok(_) => {}
+                            Err(_) => println!("failed to send response"),
+                        }
+                    }
+                    None => {
+                        // Look at the queue again while registered as idle, so work queued
+                        // in between isn't missed.
+                        let mut idle = sleepers.workers.lock().unwrap();
+                        if queued.load(Ordering::SeqCst) > 0 {
+                            continue;
+                        }
+                        let current = thread::current();
+                        if !idle.iter().any(|t| t.id() == current.id()) {
+                            idle.push(current);
+                        }
+                        drop(idle);
+                        thread::park();
                     }
                 }
-            }),
-        }
+            }
+        }));
     }
+
+    let workers: Vec<thread::Thread> = threads.iter().map(|t| t.thread().clone()).collect();
+
+    thread::spawn(move || {
+        while let Ok((msg, sender)) = receiver.recv() {
+            if !admit_early(&shedder, &msg) {
+                let _ = sender.send(Arc::new(shedding::rejection()));
+                continue;
+            }
+
+            let session = msg.as_ref().get_session();
+            if session == 0 {
+                // Leave requests on the receiver while the queue is full.
+                while queued.load(Ordering::SeqCst) >= max_queued {
+                    let mut router = sleepers.router.lock().unwrap();
+                    // A worker may have made room before we registered.
+                    if queued.load(Ordering::SeqCst) < max_queued {
+                        break;
+                    }
+                    *router = Some(thread::current());
+                    drop(router);
+                    thread::park();
+                }
+                queued.fetch_add(1, Ordering::SeqCst);
+                injector.push((msg, sender, Instant::now()));
+                sleepers.wake_worker();
+            } else {
+                let i = session as usize % pinned_channels.len();
+                pinned_channels[i]
+                    .send((msg, sender, Instant::now()))
+                    .unwrap();
+                workers[i].unpark();
+            }
+        }
+
+        // Dropping the pinned channels tells the workers to drain and exit, make sure
+        // none of them are left sleeping.
+        drop(pinned_channels);
+        for w in workers.iter() {
+            w.unpark();
+        }
+    })
+}
+
+// Finds the next piece of work for a worker: first from its own queue, then from the
+// shared queue and finally by stealing from the other workers.
+fn find_work<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
+    local.pop().or_else(|| {
+        iter::repeat_with(|| {
+            global
+                .steal_batch_and_pop(local)
+                .or_else(|| stealers.iter().map(|s| s.steal()).collect())
+        }).find(|s| !s.is_retry())
+            .and_then(|s| s.success())
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use api::Api;
-    use dispatcher;
-    use mpsc::SendError;
-    use protos;
-    use server::MessageSender;
+    use crate::api::Api;
+    use crate::dispatcher;
+    use crate::protos;
+    use crate::server::MessageSender;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
     use std::result;
-    use std::sync::mpsc::{Receiver, Sender};
+    use std::sync::mpsc::{Receiver, SendError, Sender};
     use std::sync::{mpsc, Arc, Condvar, Mutex};
+    use std::task::{Context, Poll, Waker};
     use std::thread;
+    use std::time::{Duration, Instant};
 
     struct TestSender {}
 
@@ -137,11 +604,11 @@ mod tests {
 
     impl TlsTestApi {
         fn handle(self: &Self, msg: &protos::Message) {
-            if msg.get_method() == "blocked".to_string() {
-                let &(ref lock, ref cvar) = &*self.cvar_pair;
+            if msg.get_method() == "blocked" {
+                let (lock, cvar) = &*self.cvar_pair;
                 {
                     let l = lock.lock().unwrap();
-                    let _ = cvar.wait(l).unwrap();
+                    let _guard = cvar.wait(l).unwrap();
                 }
             }
             self.sender
@@ -177,6 +644,18 @@ mod tests {
 
     impl TestDispatcer {
         fn new(num_workers: u32) -> TestDispatcer {
+            TestDispatcer::with_mode(num_workers, dispatcher::Mode::Session)
+        }
+
+        fn with_mode(num_workers: u32, mode: dispatcher::Mode) -> TestDispatcer {
+            let options = dispatcher::Options {
+                mode,
+                ..Default::default()
+            };
+            TestDispatcer::with_options(num_workers, options)
+        }
+
+        fn with_options(num_workers: u32, options: dispatcher::Options) -> TestDispatcer {
             let (api_sender, api_receiver) = mpsc::channel();
             let (sender, receiver) = mpsc::channel();
 
@@ -186,14 +665,15 @@ mod tests {
                 cvar_pair: pair.clone(),
             };
             TestDispatcer {
-                _dispatcher: dispatcher::Dispatcher::new(
+                _dispatcher: dispatcher::Dispatcher::with_options(
                     receiver,
                     num_workers,
                     move |msg: &protos::Message, api: &mut TlsTestApi| -> protos::Message {
-                        api.handle(&msg);
+                        api.handle(msg);
                         protos::Message::new()
                     },
                     &api,
+                    options,
                 ),
                 dispatch_sender: sender,
                 test_receiver: api_receiver,
@@ -213,16 +693,13 @@ mod tests {
 
         fn handle_blocked(self: &Self) -> (protos::Message, thread::ThreadId) {
             loop {
-                let &(ref lock, ref cvar) = &*self.condvar_pair;
+                let (lock, cvar) = &*self.condvar_pair;
                 {
-                    let _ = lock.lock().unwrap();
+                    let _guard = lock.lock().unwrap();
                     cvar.notify_one();
                 }
-                match self.test_receiver.try_recv() {
-                    Ok((h, t)) => {
-                        return (h, t);
-                    }
-                    Err(_) => {}
+                if let Ok((h, t)) = self.test_receiver.try_recv() {
+                    return (h, t);
                 }
             }
         }
@@ -268,4 +745,250 @@ mod tests {
         // Verify that first and second was handled on separate threads.
         assert_ne!(t1, t2);
     }
+
+    #[test]
+    fn verify_sessions_beyond_workers() {
+        let test_dispatcher = TestDispatcer::new(2);
+
+        // Session ids come from clients, so they may exceed the number of workers. Each
+        // one still sticks to a single worker.
+        for session in [7, 7, 1000].iter() {
+            let mut m = protos::Message::new();
+            m.set_method(session.to_string());
+            m.set_session(*session);
+            test_dispatcher.dispatch_msg(&m);
+        }
+
+        let handled: Vec<_> = (0..3).map(|_| test_dispatcher.recv_handled()).collect();
+        let mut methods: Vec<&str> = handled.iter().map(|(h, _)| h.get_method()).collect();
+        methods.sort();
+        assert_eq!(methods, vec!["1000", "7", "7"]);
+        let threads: Vec<_> = handled
+            .iter()
+            .filter(|(h, _)| h.get_method() == "7")
+            .map(|(_, t)| t)
+            .collect();
+        assert_eq!(threads[0], threads[1]);
+    }
+
+    #[test]
+    fn verify_queued_while_busy() {
+        let test_dispatcher = TestDispatcer::new(1);
+
+        // The only worker is busy, so the second message waits for it instead of being
+        // turned away.
+        for method in ["blocked", "queued"].iter() {
+            let mut m = protos::Message::new();
+            m.set_method(method.to_string());
+            test_dispatcher.dispatch_msg(&m);
+        }
+
+        let (h, _) = test_dispatcher.handle_blocked();
+        assert_eq!(h.get_method(), "blocked".to_string());
+        let (h, _) = test_dispatcher.recv_handled();
+        assert_eq!(h.get_method(), "queued".to_string());
+    }
+
+    #[test]
+    fn verify_work_stealing() {
+        let test_dispatcher = TestDispatcer::with_mode(2, dispatcher::Mode::WorkStealing);
+
+        {
+            let mut m = protos::Message::new();
+            m.set_method("blocked".to_string());
+            test_dispatcher.dispatch_msg(&m);
+        }
+
+        // Queue up several messages behind the blocked one, they should all be picked
+        // up by the worker that isn't blocked.
+        for _ in 0..3 {
+            let mut m = protos::Message::new();
+            m.set_method("not blocked".to_string());
+            test_dispatcher.dispatch_msg(&m);
+        }
+
+        let mut not_blocked_thread = None;
+        for _ in 0..3 {
+            let (h, t) = test_dispatcher.recv_handled();
+            assert_eq!(h.get_method(), "not blocked".to_string());
+            if let Some(prev) = not_blocked_thread {
+                assert_eq!(prev, t);
+            }
+            not_blocked_thread = Some(t);
+        }
+
+        let (h, t) = test_dispatcher.handle_blocked();
+        assert_eq!(h.get_method(), "blocked".to_string());
+        assert_ne!(Some(t), not_blocked_thread);
+    }
+
+    #[test]
+    fn verify_full_queue() {
+        let options = dispatcher::Options {
+            mode: dispatcher::Mode::WorkStealing,
+            max_queued: 1,
+            ..Default::default()
+        };
+        let test_dispatcher = TestDispatcer::with_options(1, options);
+
+        // The receive thread keeps waiting for the worker to make room in the queue.
+        for i in 0..20 {
+            let mut m = protos::Message::new();
+            m.set_method(i.to_string());
+            test_dispatcher.dispatch_msg(&m);
+        }
+        for i in 0..20 {
+            let (h, _) = test_dispatcher
+                .test_receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap();
+            assert_eq!(h.get_method(), i.to_string());
+        }
+    }
+
+    // Compares the throughput of the two modes. Run with
+    // `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_modes() {
+        const NUM_WORKERS: u32 = 4;
+        const NUM_MESSAGES: u64 = 100_000;
+
+        for mode in [dispatcher::Mode::Session, dispatcher::Mode::WorkStealing].iter() {
+            let test_dispatcher = TestDispatcer::with_mode(NUM_WORKERS, *mode);
+            let start = Instant::now();
+            for _ in 0..NUM_MESSAGES {
+                let mut m = protos::Message::new();
+                m.set_method("bench".to_string());
+                test_dispatcher.dispatch_msg(&m);
+            }
+            for _ in 0..NUM_MESSAGES {
+                test_dispatcher.recv_handled();
+            }
+            let elapsed = start.elapsed();
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            println!(
+                "{:?}: {} messages in {:?} ({:.0} msg/s)",
+                mode,
+                NUM_MESSAGES,
+                elapsed,
+                NUM_MESSAGES as f64 / secs
+            );
+        }
+    }
+
+    #[derive(Clone)]
+    struct ChannelSender {
+        sender: Sender<Arc<protos::Message>>,
+    }
+
+    impl MessageSender for ChannelSender {
+        fn send(
+            self: &Self,
+            msg: Arc<protos::Message>,
+        ) -> result::Result<(), SendError<Arc<protos::Message>>> {
+            self.sender.send(msg)
+        }
+    }
+
+    // A future that stays pending until the test opens it.
+    #[derive(Clone)]
+    struct Gate {
+        state: Arc<Mutex<(bool, Option<Waker>)>>,
+    }
+
+    impl Gate {
+        fn open(self: &Self) {
+            let mut state = self.state.lock().unwrap();
+            state.0 = true;
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Future for Gate {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            let mut state = self.state.lock().unwrap();
+            if state.0 {
+                Poll::Ready(())
+            } else {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    // A thread local api that counts the requests it served and can be waited on.
+    struct GatedApi {
+        gate: Gate,
+        served: usize,
+    }
+
+    impl GatedApi {
+        fn wait(self: &Self) -> Gate {
+            self.gate.clone()
+        }
+    }
+
+    impl Api<GatedApi> for Gate {
+        fn create_tls_api(self: &Self) -> GatedApi {
+            GatedApi {
+                gate: self.clone(),
+                served: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn verify_async_overlap() {
+        let gate = Gate {
+            state: Arc::new(Mutex::new((false, None))),
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+
+        let handler = |msg: Arc<protos::Message>, api: Rc<RefCell<GatedApi>>| {
+            Box::pin(async move {
+                if msg.get_method() == "gated" {
+                    let wait = api.borrow().wait();
+                    wait.await;
+                }
+                // Both requests share the worker's api, and see what the other one did.
+                let mut api = api.borrow_mut();
+                api.served += 1;
+                let mut response = protos::Message::new();
+                response.set_method(format!("{} {}", msg.get_method(), api.served));
+                response
+            }) as dispatcher::HandlerFuture
+        };
+        let _dispatcher = dispatcher::Dispatcher::new_async(
+            receiver,
+            1,
+            handler,
+            &gate,
+            dispatcher::Options::default(),
+        );
+
+        for method in ["gated", "not gated"].iter() {
+            let mut m = protos::Message::new();
+            m.set_method(method.to_string());
+            sender
+                .send((
+                    Arc::new(m),
+                    ChannelSender {
+                        sender: response_sender.clone(),
+                    },
+                ))
+                .unwrap();
+        }
+
+        // The single worker is waiting on the gated request but still handles the next one.
+        assert_eq!(response_receiver.recv().unwrap().get_method(), "not gated 1");
+
+        gate.open();
+        assert_eq!(response_receiver.recv().unwrap().get_method(), "gated 2");
+    }
 }
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Wake, Waker};
+
+pub type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A minimal single threaded executor.
+///
+/// The executor never blocks or decides when to poll on its own. Whenever a task is
+/// woken, `notify` is called with the task id (possibly from another thread) and the
+/// owner is expected to call `poll` with that id on the executor's thread.
+pub struct Executor {
+    tasks: HashMap<usize, Task>,
+    next_id: usize,
+    notify: Arc<dyn Fn(usize) + Send + Sync>,
+}
+
+struct TaskWaker {
+    id: usize,
+    notify: Arc<dyn Fn(usize) + Send + Sync>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        (self.notify)(self.id)
+    }
+}
+
+impl Executor {
+    pub fn new<N>(notify: N) -> Self
+    where
+        N: Fn(usize) + Send + Sync + 'static,
+    {
+        Executor {
+            tasks: HashMap::new(),
+            next_id: 0,
+            notify: Arc::new(notify),
+        }
+    }
+
+    /// Adds a task and polls it for the first time.
+    pub fn spawn(self: &mut Self, task: Task) {
+        // Ids are never reused, so a late wake up for a finished task is simply ignored.
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.tasks.insert(id, task);
+        self.poll(id);
+    }
+
+    /// Polls the task with the given id, dropping it once it completes.
+    pub fn poll(self: &mut Self, id: usize) {
+        let done = match self.tasks.get_mut(&id) {
+            Some(task) => {
+                let waker = Waker::from(Arc::new(TaskWaker {
+                    id,
+                    notify: self.notify.clone(),
+                }));
+                let mut cx = Context::from_waker(&waker);
+                task.as_mut().poll(&mut cx).is_ready()
+            }
+            None => return,
+        };
+
+        if done {
+            self.tasks.remove(&id);
+        }
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.tasks.is_empty()
+    }
+}
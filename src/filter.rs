@@ -0,0 +1,110 @@
+use super::protos;
+
+/// Selects which messages are delivered to a listener.
+///
+/// A message matches if its method matches any of the method patterns (or no method
+/// patterns were given) and every annotation pattern matches. Patterns may contain `*`
+/// wildcards, e.g. `admin.*`.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    methods: Vec<String>,
+    annotations: Vec<(String, String)>,
+}
+
+impl Filter {
+    /// Creates a filter that matches every message.
+    pub fn new() -> Self {
+        Filter {
+            methods: Vec::new(),
+            annotations: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn method(mut self: Self, pattern: &str) -> Self {
+        self.methods.push(pattern.to_string());
+        self
+    }
+
+    /// Requires the annotation `key` to be present with a value matching `pattern`.
+    #[cfg(test)]
+    pub fn annotation(mut self: Self, key: &str, pattern: &str) -> Self {
+        self.annotations.push((key.to_string(), pattern.to_string()));
+        self
+    }
+
+    pub fn matches(self: &Self, msg: &protos::Message) -> bool {
+        let method_matches = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|p| matches_pattern(p, msg.get_method()));
+
+        method_matches && self.annotations.iter().all(|(k, p)| {
+            match msg.get_annotations().get(k) {
+                Some(v) => matches_pattern(p, v),
+                None => false,
+            }
+        })
+    }
+}
+
+/// Matches `s` against a pattern where `*` matches any sequence of characters.
+pub fn matches_pattern(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one element.
+    let first = parts.next().unwrap();
+    if !s.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &s[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        // No wildcard, the whole string has to match.
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.ends_with(last)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{matches_pattern, Filter};
+    use crate::protos;
+
+    #[test]
+    fn verify_patterns() {
+        assert!(matches_pattern("Echo", "Echo"));
+        assert!(!matches_pattern("Echo", "Echoes"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("admin.*", "admin.reload"));
+        assert!(!matches_pattern("admin.*", "user.reload"));
+        assert!(matches_pattern("*.reload", "admin.reload"));
+        assert!(matches_pattern("a*b*c", "aXXbYYc"));
+        assert!(!matches_pattern("a*b*c", "aXXcYYb"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn verify_filter() {
+        let mut m = protos::Message::new();
+        m.set_method("admin.reload".to_string());
+        m.mut_annotations()
+            .insert("tenant".to_string(), "internal".to_string());
+
+        assert!(Filter::new().matches(&m));
+        assert!(Filter::new().method("Echo").method("admin.*").matches(&m));
+        assert!(!Filter::new().method("Echo").matches(&m));
+        assert!(Filter::new().annotation("tenant", "int*").matches(&m));
+        assert!(!Filter::new().annotation("tenant", "external").matches(&m));
+        assert!(!Filter::new().annotation("region", "*").matches(&m));
+    }
+}
@@ -0,0 +1,263 @@
+extern crate crc32c;
+
+use super::protos;
+use protobuf::Message;
+use std::io;
+use std::io::ErrorKind;
+
+/// Every frame starts with the payload length as a 4 byte big endian integer, followed by
+/// a flags byte.
+pub const HEADER_LEN: usize = 5;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// Marks a heartbeat ping. Control frames carry an opaque payload instead of a message.
+pub const FLAG_PING: u8 = 0x01;
+/// Marks the answer to a ping, echoing its payload.
+pub const FLAG_PONG: u8 = 0x02;
+/// Marks the protocol preface, see `handshake::Hello`.
+pub const FLAG_HELLO: u8 = 0x04;
+/// Marks a message compressed with the algorithm agreed on for the connection.
+pub const FLAG_COMPRESSED: u8 = 0x08;
+/// Marks a payload ending in a big endian CRC32C of the rest of the payload.
+pub const FLAG_CHECKSUM: u8 = 0x10;
+
+const KNOWN_FLAGS: u8 = FLAG_PING | FLAG_PONG | FLAG_HELLO | FLAG_COMPRESSED | FLAG_CHECKSUM;
+const CHECKSUM_LEN: usize = 4;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+pub struct Frame {
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_ping(self: &Self) -> bool {
+        self.flags & FLAG_PING != 0
+    }
+
+    pub fn is_pong(self: &Self) -> bool {
+        self.flags & FLAG_PONG != 0
+    }
+
+    pub fn is_hello(self: &Self) -> bool {
+        self.flags & FLAG_HELLO != 0
+    }
+
+    pub fn is_compressed(self: &Self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+}
+
+pub fn encode_payload(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    let len = payload.len() as u32;
+    buf.extend_from_slice(&[
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+        flags,
+    ]);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Encodes a message into a single frame.
+pub fn encode(msg: &protos::Message) -> Vec<u8> {
+    encode_payload(0, &msg.write_to_bytes().unwrap())
+}
+
+/// Appends a checksum to an encoded frame.
+pub fn add_checksum(mut frame: Vec<u8>) -> Vec<u8> {
+    let crc = self::crc32c::crc32c(&frame[HEADER_LEN..]);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    let len = (frame.len() - HEADER_LEN) as u32;
+    frame[..4].copy_from_slice(&len.to_be_bytes());
+    frame[4] |= FLAG_CHECKSUM;
+    frame
+}
+
+pub fn ping(payload: &[u8]) -> Vec<u8> {
+    encode_payload(FLAG_PING, payload)
+}
+
+pub fn pong(payload: &[u8]) -> Vec<u8> {
+    encode_payload(FLAG_PONG, payload)
+}
+
+pub fn decode_message(payload: &[u8]) -> io::Result<protos::Message> {
+    let mut cis = protobuf::CodedInputStream::from_bytes(payload);
+    let mut m = protos::Message::new();
+    m.merge_from(&mut cis)
+        .map_err(|e| invalid_data(format!("failed to parse message: {}", e)))?;
+    Ok(m)
+}
+
+/// Splits a stream of bytes into frames.
+pub struct Decoder {
+    buf: Vec<u8>,
+    max_frame_size: usize,
+    require_checksums: bool,
+}
+
+impl Decoder {
+    pub fn new(max_frame_size: usize) -> Self {
+        Decoder {
+            buf: Vec::new(),
+            max_frame_size,
+            require_checksums: false,
+        }
+    }
+
+    pub fn max_frame_size(self: &Self) -> usize {
+        self.max_frame_size
+    }
+
+    pub fn set_max_frame_size(self: &mut Self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Rejects frames without a checksum, once both ends agreed to send them.
+    pub fn set_require_checksums(self: &mut Self, require: bool) {
+        self.require_checksums = require;
+    }
+
+    pub fn extend(self: &mut Self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame, if any. Frames larger than the maximum frame size
+    /// are rejected as soon as their header arrives, and frames whose checksum doesn't
+    /// match are rejected too. Checksums are removed from the frames returned.
+    pub fn next_frame(self: &mut Self) -> io::Result<Option<Frame>> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = ((self.buf[0] as usize) << 24)
+            | ((self.buf[1] as usize) << 16)
+            | ((self.buf[2] as usize) << 8)
+            | (self.buf[3] as usize);
+        if len > self.max_frame_size {
+            return Err(invalid_data(format!(
+                "frame of {} bytes exceeds the limit of {} bytes",
+                len, self.max_frame_size
+            )));
+        }
+        let mut flags = self.buf[4];
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(invalid_data(format!("unsupported frame flags {:#x}", flags)));
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let mut payload = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+
+        if flags & FLAG_CHECKSUM != 0 {
+            if payload.len() < CHECKSUM_LEN {
+                return Err(invalid_data("frame too short for its checksum".to_string()));
+            }
+            let split = payload.len() - CHECKSUM_LEN;
+            let expected = u32::from_be_bytes([
+                payload[split],
+                payload[split + 1],
+                payload[split + 2],
+                payload[split + 3],
+            ]);
+            payload.truncate(split);
+            if self::crc32c::crc32c(&payload) != expected {
+                return Err(invalid_data("frame checksum mismatch".to_string()));
+            }
+            flags &= !FLAG_CHECKSUM;
+        } else if self.require_checksums {
+            return Err(invalid_data("frame is missing its checksum".to_string()));
+        }
+        Ok(Some(Frame { flags, payload }))
+    }
+
+    /// Whether part of a frame has been received.
+    pub fn has_partial(self: &Self) -> bool {
+        !self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame::{add_checksum, decode_message, encode, ping, pong, Decoder};
+    use crate::protos;
+
+    #[test]
+    fn verify_round_trip() {
+        let mut m = protos::Message::new();
+        m.set_method("Echo".to_string());
+        let mut bytes = encode(&m);
+        bytes.extend(encode(&m));
+
+        // Feed the frames one byte at a time.
+        let mut decoder = Decoder::new(1024);
+        let mut decoded = Vec::new();
+        for b in bytes.iter() {
+            decoder.extend(&[*b]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(decode_message(&frame.payload).unwrap());
+            }
+        }
+
+        assert_eq!(decoded, vec![m.clone(), m]);
+        assert!(!decoder.has_partial());
+    }
+
+    #[test]
+    fn verify_control_frames() {
+        let mut decoder = Decoder::new(1024);
+        decoder.extend(&ping(b"1"));
+        decoder.extend(&pong(b"1"));
+
+        let p = decoder.next_frame().unwrap().unwrap();
+        assert!(p.is_ping() && !p.is_pong());
+        assert_eq!(p.payload, b"1".to_vec());
+        assert!(decoder.next_frame().unwrap().unwrap().is_pong());
+    }
+
+    #[test]
+    fn verify_checksums() {
+        let mut m = protos::Message::new();
+        m.set_method("Echo".to_string());
+        let bytes = add_checksum(encode(&m));
+
+        let mut decoder = Decoder::new(1024);
+        decoder.set_require_checksums(true);
+        decoder.extend(&bytes);
+        let f = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(decode_message(&f.payload).unwrap(), m);
+
+        // Flip a bit in the body.
+        let mut corrupted = bytes.clone();
+        corrupted[8] ^= 0x1;
+        decoder.extend(&corrupted);
+        assert!(decoder.next_frame().is_err());
+
+        let mut decoder = Decoder::new(1024);
+        decoder.set_require_checksums(true);
+        decoder.extend(&encode(&m));
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn verify_max_frame_size() {
+        let mut m = protos::Message::new();
+        m.set_body(vec![0; 64]);
+        let bytes = encode(&m);
+
+        let mut decoder = Decoder::new(16);
+        // The header alone is enough to reject the frame.
+        decoder.extend(&bytes[..5]);
+        assert!(decoder.next_frame().is_err());
+    }
+}
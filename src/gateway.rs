@@ -0,0 +1,584 @@
+extern crate httparse;
+extern crate serde_json;
+
+use super::auth;
+use super::codec;
+use super::protos;
+use super::ratelimit;
+use super::server;
+use super::status::{Code, Status};
+use std::collections::HashMap;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// The most header bytes we buffer for a single request.
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Optional behaviour for an `HttpGateway`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Requests with a larger body are rejected with 413.
+    pub max_body_size: usize,
+    /// How long to wait for a response before answering with 504.
+    pub request_timeout: Duration,
+    /// Connections that don't send anything for this long are closed.
+    pub idle_timeout: Duration,
+    /// Every connection is served by its own thread. Once this many are open new
+    /// connections are answered with 503 and closed.
+    pub max_connections: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_body_size: 4 * 1024 * 1024,
+            request_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            max_connections: 256,
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    // Header names are lower case.
+    headers: HashMap<String, String>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: &Status) -> Self {
+        let body = serde_json::json!({
+            "code": status.code.as_str(),
+            "message": status.message,
+        });
+        Response {
+            status: status.code.http_status(),
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    // Turns an rplay response into an HTTP response. Failed calls get a JSON body
+    // describing the status instead of the response body.
+    fn of(msg: &protos::Message) -> Self {
+        let status = Status::of(msg);
+        let mut response = if status.code == Code::Ok {
+            let content_type = msg
+                .get_annotations()
+                .get(codec::CONTENT_TYPE_ANNOTATION)
+                .cloned()
+                .unwrap_or_else(|| "application/json".to_string());
+            Response {
+                status: 200,
+                headers: vec![("Content-Type", content_type)],
+                body: msg.get_body().to_vec(),
+            }
+        } else {
+            Response::error(&status)
+        };
+
+        let retry_after = msg
+            .get_annotations()
+            .get(ratelimit::RETRY_AFTER_ANNOTATION)
+            .and_then(|ms| ms.parse::<u64>().ok());
+        if let Some(ms) = retry_after {
+            let secs = ms.div_ceil(1000);
+            response.headers.push(("Retry-After", secs.to_string()));
+        }
+        response
+    }
+
+    fn write_to(self: &Self, stream: &mut dyn Write, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for &(name, ref value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        head.push_str(&format!("Connection: {}\r\n\r\n", connection));
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        499 => "Client Closed Request",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+fn plain(status: u16) -> Response {
+    Response {
+        status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Maps `POST /<method>` onto a message for that method, carrying the body as is.
+fn to_message(
+    request: &Request,
+    body: Vec<u8>,
+    peer: &SocketAddr,
+) -> Result<protos::Message, Response> {
+    if request.method != "POST" {
+        let mut response = plain(405);
+        response.headers.push(("Allow", "POST".to_string()));
+        return Err(response);
+    }
+    let method = request.path.split('?').next().unwrap_or("");
+    let method = method.trim_start_matches('/');
+    if method.is_empty() {
+        return Err(Response::error(&Status::new(
+            Code::NotFound,
+            "expected POST /<method>",
+        )));
+    }
+
+    let mut m = protos::Message::new();
+    m.set_method(method.to_string());
+    m.set_body(body);
+    let content_type = request
+        .headers
+        .get("content-type")
+        .cloned()
+        .unwrap_or_else(|| "application/json".to_string());
+    let annotations = m.mut_annotations();
+    annotations.insert(codec::CONTENT_TYPE_ANNOTATION.to_string(), content_type);
+    if let Some(authorization) = request.headers.get("authorization") {
+        annotations.insert(
+            auth::AUTHORIZATION_ANNOTATION.to_string(),
+            authorization.clone(),
+        );
+    }
+    annotations.insert(server::PEER_ADDR_ANNOTATION.to_string(), peer.to_string());
+    Ok(m)
+}
+
+// Called with the response to a call.
+type Done = Box<dyn FnOnce(Arc<protos::Message>) + Send>;
+
+/// Calls methods through a server's listeners from outside its connections, and matches
+/// the responses up with the calls waiting on them.
+pub struct Calls {
+    inlet: server::Inlet,
+    next_id: AtomicUsize,
+    // What to do with the response to each call still waiting on one, by id.
+    pending: Arc<Mutex<HashMap<usize, Done>>>,
+}
+
+impl Calls {
+    pub fn new(server: &server::Server) -> Self {
+        let (inlet, responses) = server.inlet();
+        let pending = Arc::new(Mutex::new(HashMap::<usize, Done>::new()));
+
+        let waiting = pending.clone();
+        thread::spawn(move || {
+            while let Some((id, msg)) = responses.recv() {
+                // Late responses to calls that timed out, and extra responses from other
+                // listeners, are dropped.
+                let done = waiting.lock().unwrap().remove(&id);
+                if let Some(done) = done {
+                    done(msg);
+                }
+            }
+        });
+
+        Calls {
+            inlet,
+            next_id: AtomicUsize::new(0),
+            pending,
+        }
+    }
+
+    /// Dispatches a message without waiting for its response, `done` is called with the
+    /// response once it arrives, or with the rejection if the message isn't dispatched.
+    /// Returns the call's id, for `cancel`.
+    pub fn start<F>(self: &Self, m: protos::Message, done: F) -> usize
+    where
+        F: FnOnce(Arc<protos::Message>) + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(id, Box::new(done));
+
+        if let Err(rejected) = self.inlet.submit(id, m) {
+            let done = self.pending.lock().unwrap().remove(&id);
+            if let Some(done) = done {
+                done(Arc::new(rejected));
+            }
+        }
+        id
+    }
+
+    /// Stops waiting for the response to a call, it is dropped if it still arrives.
+    pub fn cancel(self: &Self, id: usize) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    /// Dispatches a message and waits for its response. Rejections and timeouts are
+    /// returned as responses carrying the corresponding status.
+    pub fn call(self: &Self, m: protos::Message, timeout: Duration) -> Arc<protos::Message> {
+        let (sender, receiver) = mpsc::channel();
+        let id = self.start(m, move |response| {
+            let _ = sender.send(response);
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(response) => response,
+            Err(_) => {
+                self.cancel(id);
+                Arc::new(timed_out().to_message())
+            }
+        }
+    }
+}
+
+/// The status of a call that got no response in time.
+pub fn timed_out() -> Status {
+    Status::new(Code::DeadlineExceeded, "timed out waiting for a response")
+}
+
+/// Serves HTTP/1.1 in front of a `Server`: `POST /<method>` with a JSON body calls
+/// `<method>` through the server's listeners, exactly like a message arriving on one of
+/// its connections would, and answers with the response body. The HTTP status is derived
+/// from the rplay status, and failed calls are answered with a JSON body holding the
+/// status code and message.
+///
+/// The `Content-Type` header selects a different codec for the body, and the
+/// `Authorization` header is passed on for authentication.
+pub struct HttpGateway {
+    local_addr: SocketAddr,
+    _accept_thread: JoinHandle<()>,
+}
+
+impl HttpGateway {
+    pub fn bind(addr: &SocketAddr, server: &server::Server, options: Options) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let calls = Arc::new(Calls::new(server));
+        let open = Arc::new(AtomicUsize::new(0));
+
+        let accept_thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("failed to accept: {}", e);
+                        continue;
+                    }
+                };
+                if open.load(Ordering::SeqCst) >= options.max_connections {
+                    let status = Status::new(Code::Unavailable, "too many connections");
+                    let _ = stream.set_write_timeout(Some(options.idle_timeout));
+                    let _ = Response::error(&status).write_to(&mut stream, false);
+                    continue;
+                }
+                open.fetch_add(1, Ordering::SeqCst);
+
+                let calls = calls.clone();
+                let options = options.clone();
+                let open = open.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(&mut stream, &calls, &options) {
+                        if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::TimedOut {
+                            println!("http connection failed: {}", e);
+                        }
+                    }
+                    // Make room before closing, so a client can reconnect right away.
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Ok(HttpGateway {
+            local_addr,
+            _accept_thread: accept_thread,
+        })
+    }
+
+    /// The address the gateway accepts connections on.
+    pub fn local_addr(self: &Self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+// Reads the head of the next request, leaving anything after it in `buf`. Returns None
+// if the connection was closed between requests.
+fn read_head(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<Result<Request, Response>>> {
+    loop {
+        let parsed = {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(buf) {
+                Ok(httparse::Status::Complete(len)) => {
+                    let headers: HashMap<String, String> = req
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            (
+                                h.name.to_lowercase(),
+                                String::from_utf8_lossy(h.value).into_owned(),
+                            )
+                        })
+                        .collect();
+                    let close = headers
+                        .get("connection")
+                        .is_some_and(|c| c.eq_ignore_ascii_case("close"));
+                    Some((
+                        len,
+                        Request {
+                            method: req.method.unwrap_or("").to_string(),
+                            path: req.path.unwrap_or("").to_string(),
+                            keep_alive: req.version == Some(1) && !close,
+                            headers,
+                        },
+                    ))
+                }
+                Ok(httparse::Status::Partial) => None,
+                Err(_) => return Ok(Some(Err(plain(400)))),
+            }
+        };
+        if let Some((len, request)) = parsed {
+            buf.drain(..len);
+            return Ok(Some(Ok(request)));
+        }
+
+        if buf.len() > MAX_HEADER_SIZE {
+            return Ok(Some(Err(plain(431))));
+        }
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn serve(stream: &mut TcpStream, calls: &Calls, options: &Options) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(options.idle_timeout))?;
+    let mut buf = Vec::new();
+    loop {
+        let request = match read_head(stream, &mut buf)? {
+            Some(Ok(request)) => request,
+            Some(Err(response)) => return response.write_to(stream, false),
+            None => return Ok(()),
+        };
+
+        if request.headers.contains_key("transfer-encoding") {
+            return plain(411).write_to(stream, false);
+        }
+        let len = match request.headers.get("content-length") {
+            Some(len) => match len.trim().parse::<usize>() {
+                Ok(len) => len,
+                Err(_) => return plain(400).write_to(stream, false),
+            },
+            None => 0,
+        };
+        if len > options.max_body_size {
+            return plain(413).write_to(stream, false);
+        }
+        while buf.len() < len {
+            let mut chunk = [0; 4096];
+            let n = stream.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body: Vec<u8> = buf.drain(..len).collect();
+
+        let response = match to_message(&request, body, &peer) {
+            Ok(m) => Response::of(&calls.call(m, options.request_timeout)),
+            Err(response) => response,
+        };
+        response.write_to(stream, request.keep_alive)?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::Api;
+    use crate::dispatcher::Dispatcher;
+    use crate::gateway::{to_message, HttpGateway, Options, Request, Response};
+    use crate::protos;
+    use crate::ratelimit::RETRY_AFTER_ANNOTATION;
+    use crate::server;
+    use crate::status::{Code, Status};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::thread;
+
+    struct EchoApi {}
+
+    impl Api<EchoApi> for EchoApi {
+        fn create_tls_api(self: &Self) -> EchoApi {
+            EchoApi {}
+        }
+    }
+
+    // Binds a gateway in front of a server whose dispatcher answers `Echo` with the
+    // request body.
+    fn gateway(options: Options) -> HttpGateway {
+        let mut server = server::Server::bind(&[], server::Options::default()).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(16);
+        server.add_listener(sender);
+        let gateway = HttpGateway::bind(&"127.0.0.1:0".parse().unwrap(), &server, options)
+            .unwrap();
+        thread::spawn(move || {
+            let _dispatcher = Dispatcher::new(
+                receiver,
+                2,
+                |msg: &protos::Message, _: &mut EchoApi| -> protos::Message {
+                    if msg.get_method() != "Echo" {
+                        return Status::new(Code::Unimplemented, "no such method").to_message();
+                    }
+                    let mut m = protos::Message::new();
+                    m.set_body(msg.get_body().to_vec());
+                    m
+                },
+                &EchoApi {},
+            );
+            server.start();
+        });
+        gateway
+    }
+
+    // Sends a single request and returns the whole response.
+    fn post(stream: &mut TcpStream, path: &str, body: &str) -> String {
+        let request = format!(
+            "POST {} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        let mut headers = HashMap::new();
+        headers.insert("authorization".to_string(), "Bearer t".to_string());
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+            keep_alive: true,
+        }
+    }
+
+    #[test]
+    fn verify_to_message() {
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let m = to_message(&request("POST", "/Echo?x=1"), b"{}".to_vec(), &peer)
+            .ok()
+            .unwrap();
+        assert_eq!(m.get_method(), "Echo");
+        assert_eq!(m.get_body(), b"{}");
+        assert_eq!(m.get_annotations()["content-type"], "application/json");
+        assert_eq!(m.get_annotations()["authorization"], "Bearer t");
+
+        let get = to_message(&request("GET", "/Echo"), Vec::new(), &peer);
+        assert_eq!(get.err().unwrap().status, 405);
+        let root = to_message(&request("POST", "/"), Vec::new(), &peer);
+        assert_eq!(root.err().unwrap().status, 404);
+    }
+
+    #[test]
+    fn verify_response_status() {
+        let mut ok = protos::Message::new();
+        ok.set_body(b"{}".to_vec());
+        let response = Response::of(&ok);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"{}".to_vec());
+
+        let limited = Status::new(Code::ResourceExhausted, "slow down")
+            .with_annotation(RETRY_AFTER_ANNOTATION, "1500")
+            .to_message();
+        let response = Response::of(&limited);
+        assert_eq!(response.status, 429);
+        assert!(response
+            .headers
+            .contains(&("Retry-After", "2".to_string())));
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("RESOURCE_EXHAUSTED"));
+    }
+
+    #[test]
+    fn verify_gateway() {
+        let gateway = gateway(Options::default());
+        let mut stream = TcpStream::connect(gateway.local_addr()).unwrap();
+        let response = post(&mut stream, "/Echo", r#"{"data":"hello"}"#);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"data\":\"hello\"}"));
+
+        let mut stream = TcpStream::connect(gateway.local_addr()).unwrap();
+        let response = post(&mut stream, "/Missing", "{}");
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(response.contains("UNIMPLEMENTED"));
+    }
+
+    #[test]
+    fn verify_max_connections() {
+        let gateway = gateway(Options {
+            max_connections: 1,
+            ..Default::default()
+        });
+        let mut open = TcpStream::connect(gateway.local_addr()).unwrap();
+        // The gateway answers without waiting for a request.
+        let mut rejected = TcpStream::connect(gateway.local_addr()).unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // Once the first connection is done there is room again.
+        let response = post(&mut open, "/Echo", "{}");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let mut stream = TcpStream::connect(gateway.local_addr()).unwrap();
+        let response = post(&mut stream, "/Echo", "{}");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
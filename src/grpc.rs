@@ -0,0 +1,1159 @@
+extern crate hpack;
+
+use super::auth;
+use super::codec;
+use super::compression;
+use super::gateway;
+use super::gateway::Calls;
+use super::protos;
+use super::server;
+use super::status::{Code, Status};
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Every HTTP/2 connection starts with this, before the first frame.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+
+// Frame types.
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags.
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// Settings.
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes.
+const PROTOCOL_ERROR: u32 = 0x1;
+const REFUSED_STREAM: u32 = 0x7;
+
+// Until the peer's settings say otherwise.
+const DEFAULT_WINDOW: i64 = 65_535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+// The most header block bytes we buffer for a single request.
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+// Every gRPC message is prefixed with a compressed flag and its length.
+const MESSAGE_HEADER_LEN: usize = 5;
+
+/// Optional behaviour for a `GrpcGateway`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Requests with a larger message are rejected with RESOURCE_EXHAUSTED.
+    pub max_message_size: usize,
+    /// The most calls a single connection may have in flight.
+    pub max_concurrent_streams: u32,
+    /// How long to wait for a response when the client doesn't send a `grpc-timeout`.
+    pub default_timeout: Duration,
+    /// Maps gRPC methods, written `package.Service/Method`, onto rplay methods. Methods
+    /// that aren't listed call the rplay method with the gRPC method's full name.
+    pub methods: HashMap<String, String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_message_size: 4 * 1024 * 1024,
+            max_concurrent_streams: 100,
+            default_timeout: Duration::from_secs(30),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+fn read_frame(r: &mut dyn Read) -> io::Result<Frame> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    r.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    // We never raise SETTINGS_MAX_FRAME_SIZE above the default.
+    if len > DEFAULT_MAX_FRAME_SIZE {
+        return Err(protocol_error("frame exceeds the maximum frame size"));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)?;
+    Ok(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+// Writes the whole frame at once, connections have Nagle's algorithm turned off so
+// separate writes would go out as separate packets.
+fn write_frame(w: &mut dyn Write, kind: u8, flags: u8, id: u32, payload: &[u8]) -> io::Result<()> {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    w.write_all(&frame)
+}
+
+// Strips the padding, and for HEADERS the priority fields, off a frame's payload.
+fn strip(f: &Frame) -> io::Result<&[u8]> {
+    let mut p = &f.payload[..];
+    let mut pad = 0;
+    if f.flags & PADDED != 0 {
+        if p.is_empty() {
+            return Err(protocol_error("padded frame without a pad length"));
+        }
+        pad = p[0] as usize;
+        p = &p[1..];
+    }
+    if f.kind == HEADERS && f.flags & PRIORITY != 0 {
+        if p.len() < 5 {
+            return Err(protocol_error("truncated priority fields"));
+        }
+        p = &p[5..];
+    }
+    if pad > p.len() {
+        return Err(protocol_error("padding exceeds the frame payload"));
+    }
+    Ok(&p[..p.len() - pad])
+}
+
+/// Picks the full gRPC method name, `package.Service/Method`, out of a path.
+fn method_name(path: &str) -> Option<&str> {
+    let name = path.strip_prefix('/')?;
+    let (service, method) = name.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    Some(name)
+}
+
+/// Parses a `grpc-timeout` header, e.g. `100m` for 100 milliseconds.
+fn parse_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Percent-encodes a `grpc-message` trailer.
+fn encode_message(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (0x20..=0x7e).contains(&b) && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+// The body codec a gRPC content type asks for: `application/grpc+json` is JSON, and
+// plain `application/grpc` is protobuf.
+fn codec_content_type(content_type: &str) -> Option<String> {
+    let subtype = if content_type == "application/grpc" {
+        "proto"
+    } else if let Some(subtype) = content_type.strip_prefix("application/grpc+") {
+        subtype
+    } else if content_type.starts_with("application/grpc;") {
+        "proto"
+    } else {
+        return None;
+    };
+    let subtype = subtype.split(';').next().unwrap_or("").trim();
+    let content_type = match subtype {
+        "proto" => "application/x-protobuf".to_string(),
+        other => format!("application/{}", other),
+    };
+    codec::for_content_type(&content_type).map(|_| content_type)
+}
+
+// A request whose headers have been received.
+struct Request {
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(self: &Self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| v.as_str())
+    }
+
+    // The content type to answer with.
+    fn content_type(self: &Self) -> String {
+        self.header("content-type")
+            .unwrap_or("application/grpc")
+            .to_string()
+    }
+
+    // Maps the call onto an rplay message, along with how long to wait for it.
+    fn to_message(
+        self: &Self,
+        peer: &SocketAddr,
+        options: &Options,
+    ) -> Result<(protos::Message, Duration), Status> {
+        if self.header(":method") != Some("POST") {
+            return Err(Status::new(Code::Unimplemented, "only POST is supported"));
+        }
+        let path = self.header(":path").unwrap_or("");
+        let method = method_name(path).ok_or_else(|| {
+            Status::new(Code::Unimplemented, &format!("malformed method name {}", path))
+        })?;
+        let method = options.methods.get(method).map_or(method, |m| m.as_str());
+        let content_type = self.header("content-type").unwrap_or("");
+        let body_content_type = codec_content_type(content_type).ok_or_else(|| {
+            Status::new(
+                Code::InvalidArgument,
+                &format!("unsupported content type {}", content_type),
+            )
+        })?;
+        let algorithm = match self.header("grpc-encoding") {
+            None | Some("identity") => None,
+            Some("gzip") => Some(compression::Algorithm::Gzip),
+            Some(other) => {
+                let message = format!("unsupported grpc-encoding {}", other);
+                return Err(Status::new(Code::Unimplemented, &message));
+            }
+        };
+
+        let b = &self.body;
+        if b.len() < MESSAGE_HEADER_LEN {
+            return Err(Status::new(Code::InvalidArgument, "missing request message"));
+        }
+        let len = u32::from_be_bytes([b[1], b[2], b[3], b[4]]) as usize;
+        if b.len() != MESSAGE_HEADER_LEN + len {
+            return Err(Status::new(
+                Code::Unimplemented,
+                "expected exactly one request message, streaming is not supported",
+            ));
+        }
+        let data = &b[MESSAGE_HEADER_LEN..];
+        let body = match (b[0], algorithm) {
+            (0, _) => data.to_vec(),
+            (1, Some(a)) => a.decompress(data, options.max_message_size).map_err(|e| {
+                let message = format!("failed to decompress request: {}", e);
+                Status::new(Code::ResourceExhausted, &message)
+            })?,
+            _ => {
+                return Err(Status::new(
+                    Code::Internal,
+                    "compressed request without a grpc-encoding",
+                ))
+            }
+        };
+
+        let mut m = protos::Message::new();
+        m.set_method(method.to_string());
+        m.set_body(body);
+        let annotations = m.mut_annotations();
+        annotations.insert(codec::CONTENT_TYPE_ANNOTATION.to_string(), body_content_type);
+        if let Some(authorization) = self.header("authorization") {
+            annotations.insert(
+                auth::AUTHORIZATION_ANNOTATION.to_string(),
+                authorization.to_string(),
+            );
+        }
+        annotations.insert(server::PEER_ADDR_ANNOTATION.to_string(), peer.to_string());
+
+        let timeout = self
+            .header("grpc-timeout")
+            .and_then(parse_timeout)
+            .unwrap_or(options.default_timeout);
+        Ok((m, timeout))
+    }
+}
+
+// The trailers ending a call with the given status.
+fn trailers(status: &Status) -> Vec<(String, String)> {
+    let mut trailers = vec![("grpc-status".to_string(), (status.code as u32).to_string())];
+    if !status.message.is_empty() {
+        trailers.push(("grpc-message".to_string(), encode_message(&status.message)));
+    }
+    trailers
+}
+
+// What the reader and the calls in flight ask a connection's writer to do.
+enum Event {
+    // A frame to send as is, like acknowledgements and window updates.
+    Frame(u8, u8, u32, Vec<u8>),
+    // The peer's new initial stream window and maximum frame size, acknowledged once
+    // applied.
+    Settings(Option<i64>, Option<usize>),
+    // The peer opened a send window, of the connection for stream 0.
+    WindowUpdate(u32, i64),
+    // The peer reset a stream.
+    Reset(u32),
+    // A request was received completely, and will be answered with the content type.
+    Open(u32, String),
+    // The call answering a stream was started, and should be answered by the deadline.
+    Started(u32, usize, Instant),
+    Response(u32, Arc<protos::Message>),
+    // Answers a stream without a response body.
+    Status(u32, Status),
+    // The connection is done, with the GOAWAY payload to send if it failed.
+    Close(Option<Vec<u8>>),
+}
+
+// A stream the writer has yet to finish answering.
+struct Stream {
+    content_type: String,
+    window: i64,
+    // The call answering the stream and when it times out, until its response arrives.
+    call: Option<(usize, Instant)>,
+    // The response data along with how much of it was sent, and the trailers to send
+    // after it.
+    data: Vec<u8>,
+    sent: usize,
+    trailers: Option<Vec<(String, String)>>,
+}
+
+// The sending half of a connection. It runs on its own thread so waiting for the peer
+// to open its send windows, or to read, never holds up the reader.
+struct Writer {
+    stream: TcpStream,
+    encoder: hpack::Encoder<'static>,
+    calls: Arc<Calls>,
+    max_frame_size: usize,
+    initial_window: i64,
+    // The connection's send window.
+    window: i64,
+    streams: HashMap<u32, Stream>,
+    // How many streams are open, shared with the reader which refuses new ones once
+    // there are too many.
+    open: Arc<AtomicUsize>,
+}
+
+impl Writer {
+    fn run(self: &mut Self, events: Receiver<Event>) -> io::Result<()> {
+        let result = self.handle_all(&events);
+        // Nobody is left to answer, so stop waiting for responses.
+        for s in self.streams.values() {
+            if let Some((call, _)) = s.call {
+                self.calls.cancel(call);
+            }
+        }
+        result
+    }
+
+    fn handle_all(self: &mut Self, events: &Receiver<Event>) -> io::Result<()> {
+        loop {
+            let now = Instant::now();
+            self.expire(now)?;
+            let event = match self.next_deadline() {
+                Some(deadline) => match events.recv_timeout(deadline - now) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                },
+                None => match events.recv() {
+                    Ok(event) => event,
+                    Err(_) => return Ok(()),
+                },
+            };
+
+            match event {
+                Event::Frame(kind, flags, id, payload) => self.frame(kind, flags, id, &payload)?,
+                Event::Settings(window, max_frame_size) => {
+                    if let Some(window) = window {
+                        let delta = window - self.initial_window;
+                        for s in self.streams.values_mut() {
+                            s.window += delta;
+                        }
+                        self.initial_window = window;
+                    }
+                    if let Some(max_frame_size) = max_frame_size {
+                        self.max_frame_size = max_frame_size;
+                    }
+                    self.frame(SETTINGS, ACK, 0, &[])?;
+                    self.flush_all()?;
+                }
+                Event::WindowUpdate(0, increment) => {
+                    self.window += increment;
+                    self.flush_all()?;
+                }
+                Event::WindowUpdate(id, increment) => {
+                    if let Some(s) = self.streams.get_mut(&id) {
+                        s.window += increment;
+                    }
+                    self.flush(id)?;
+                }
+                Event::Reset(id) => {
+                    if let Some(s) = self.streams.remove(&id) {
+                        self.closed(s);
+                    }
+                }
+                Event::Open(id, content_type) => {
+                    let s = Stream {
+                        content_type,
+                        window: self.initial_window,
+                        call: None,
+                        data: Vec::new(),
+                        sent: 0,
+                        trailers: None,
+                    };
+                    self.streams.insert(id, s);
+                }
+                Event::Started(id, call, deadline) => match self.streams.get_mut(&id) {
+                    Some(s) if s.trailers.is_none() => s.call = Some((call, deadline)),
+                    // Already answered or reset.
+                    _ => self.calls.cancel(call),
+                },
+                Event::Response(id, response) => self.respond(id, &response)?,
+                Event::Status(id, status) => self.respond_status(id, &status)?,
+                Event::Close(goaway) => {
+                    if let Some(goaway) = goaway {
+                        self.frame(GOAWAY, 0, 0, &goaway)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn frame(self: &mut Self, kind: u8, flags: u8, id: u32, payload: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.stream, kind, flags, id, payload)
+    }
+
+    fn headers(
+        self: &mut Self,
+        id: u32,
+        headers: &[(String, String)],
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let block = self.encoder.encode(
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_bytes())),
+        );
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            if chunks.peek().is_none() {
+                return self.frame(kind, flags | END_HEADERS, id, chunk);
+            }
+            self.frame(kind, flags, id, chunk)?;
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    fn next_deadline(self: &Self) -> Option<Instant> {
+        self.streams
+            .values()
+            .filter_map(|s| s.call.map(|(_, deadline)| deadline))
+            .min()
+    }
+
+    // Answers the calls that are out of time.
+    fn expire(self: &mut Self, now: Instant) -> io::Result<()> {
+        let expired: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|&(_, s)| s.call.is_some_and(|(_, deadline)| deadline <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.respond_status(id, &gateway::timed_out())?;
+        }
+        Ok(())
+    }
+
+    // Forgets a stream that is done.
+    fn closed(self: &Self, s: Stream) {
+        if let Some((call, _)) = s.call {
+            self.calls.cancel(call);
+        }
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Answers a call that failed before producing a response body, in a single
+    // HEADERS frame.
+    fn respond_status(self: &mut Self, id: u32, status: &Status) -> io::Result<()> {
+        let s = match self.streams.remove(&id) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        if s.trailers.is_some() {
+            // Already sending a response.
+            self.streams.insert(id, s);
+            return Ok(());
+        }
+        let mut headers = vec![
+            (":status".to_string(), "200".to_string()),
+            ("content-type".to_string(), s.content_type.clone()),
+        ];
+        headers.extend(trailers(status));
+        self.closed(s);
+        self.headers(id, &headers, true)
+    }
+
+    // Starts sending the response to a call.
+    fn respond(self: &mut Self, id: u32, response: &protos::Message) -> io::Result<()> {
+        let status = Status::of(response);
+        if status.code != Code::Ok {
+            return self.respond_status(id, &status);
+        }
+        let content_type = match self.streams.get_mut(&id) {
+            Some(s) if s.trailers.is_none() => {
+                let body = response.get_body();
+                s.data.push(0);
+                s.data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                s.data.extend_from_slice(body);
+                s.trailers = Some(trailers(&status));
+                s.call = None;
+                s.content_type.clone()
+            }
+            _ => return Ok(()),
+        };
+        let headers = [
+            (":status".to_string(), "200".to_string()),
+            ("content-type".to_string(), content_type),
+        ];
+        self.headers(id, &headers, false)?;
+        self.flush(id)
+    }
+
+    // Sends as much of a stream's response as the send windows allow, and the trailers
+    // once all of it is sent.
+    fn flush(self: &mut Self, id: u32) -> io::Result<()> {
+        loop {
+            let chunk = match self.streams.get_mut(&id) {
+                Some(s) if s.trailers.is_some() => {
+                    if s.sent == s.data.len() {
+                        let s = self.streams.remove(&id).unwrap();
+                        let trailers = s.trailers.clone().unwrap();
+                        self.closed(s);
+                        return self.headers(id, &trailers, true);
+                    }
+                    let available = cmp::min(s.window, self.window);
+                    if available <= 0 {
+                        return Ok(());
+                    }
+                    let n = cmp::min(
+                        cmp::min(available as usize, self.max_frame_size),
+                        s.data.len() - s.sent,
+                    );
+                    s.window -= n as i64;
+                    s.sent += n;
+                    s.data[s.sent - n..s.sent].to_vec()
+                }
+                _ => return Ok(()),
+            };
+            self.window -= chunk.len() as i64;
+            self.frame(DATA, 0, id, &chunk)?;
+        }
+    }
+
+    fn flush_all(self: &mut Self) -> io::Result<()> {
+        let ids: Vec<u32> = self.streams.keys().cloned().collect();
+        for id in ids {
+            self.flush(id)?;
+        }
+        Ok(())
+    }
+}
+
+// The receiving half of a connection.
+struct Reader {
+    events: Sender<Event>,
+    open: Arc<AtomicUsize>,
+    calls: Arc<Calls>,
+    options: Options,
+    peer: SocketAddr,
+    decoder: hpack::Decoder<'static>,
+    // Requests still receiving their body.
+    requests: HashMap<u32, Request>,
+    // The stream and flags of a header block continued in CONTINUATION frames.
+    continuing: Option<(u32, u8)>,
+    block: Vec<u8>,
+    last_stream: u32,
+}
+
+impl Reader {
+    fn run(self: &mut Self, r: &mut dyn Read) -> io::Result<()> {
+        loop {
+            let f = read_frame(r)?;
+            if let Some((id, _)) = self.continuing {
+                if f.kind != CONTINUATION || f.stream_id != id {
+                    return Err(protocol_error("expected a CONTINUATION frame"));
+                }
+            }
+            match f.kind {
+                HEADERS => {
+                    if f.stream_id == 0 {
+                        return Err(protocol_error("HEADERS frame on stream 0"));
+                    }
+                    self.block = strip(&f)?.to_vec();
+                    self.continuing = Some((f.stream_id, f.flags));
+                    if f.flags & END_HEADERS != 0 {
+                        self.end_headers()?;
+                    }
+                }
+                CONTINUATION => {
+                    if self.continuing.is_none() {
+                        return Err(protocol_error("unexpected CONTINUATION frame"));
+                    }
+                    self.block.extend_from_slice(&f.payload);
+                    if self.block.len() > MAX_HEADER_BLOCK {
+                        return Err(protocol_error("header block too large"));
+                    }
+                    if f.flags & END_HEADERS != 0 {
+                        self.end_headers()?;
+                    }
+                }
+                DATA => self.data(&f)?,
+                SETTINGS if f.flags & ACK == 0 => self.settings(&f.payload)?,
+                PING if f.flags & ACK == 0 => self.send(Event::Frame(PING, ACK, 0, f.payload)),
+                WINDOW_UPDATE => {
+                    if f.payload.len() != 4 {
+                        return Err(protocol_error("malformed WINDOW_UPDATE frame"));
+                    }
+                    let p = &f.payload;
+                    let increment = u32::from_be_bytes([p[0], p[1], p[2], p[3]]) & 0x7fff_ffff;
+                    self.send(Event::WindowUpdate(f.stream_id, increment as i64));
+                }
+                RST_STREAM => {
+                    // Streams still receiving their body aren't known to the writer yet.
+                    if self.requests.remove(&f.stream_id).is_some() {
+                        self.open.fetch_sub(1, Ordering::SeqCst);
+                    } else {
+                        self.send(Event::Reset(f.stream_id));
+                    }
+                }
+                GOAWAY => return Ok(()),
+                // PRIORITY, acknowledgements and unknown frames.
+                _ => {}
+            }
+        }
+    }
+
+    // Hands an event to the writer. It only goes away once the connection fails, which
+    // the reader finds out about soon enough.
+    fn send(self: &Self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    // Checks the peer's settings and passes on the ones the writer cares about.
+    fn settings(self: &Self, payload: &[u8]) -> io::Result<()> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(protocol_error("malformed settings frame"));
+        }
+        let mut window = None;
+        let mut max_frame_size = None;
+        for s in payload.chunks(6) {
+            let id = u16::from_be_bytes([s[0], s[1]]);
+            let value = u32::from_be_bytes([s[2], s[3], s[4], s[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => window = Some(value as i64),
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if value < DEFAULT_MAX_FRAME_SIZE as u32 || value > 0x00ff_ffff {
+                        return Err(protocol_error("invalid maximum frame size"));
+                    }
+                    max_frame_size = Some(value as usize);
+                }
+                _ => {}
+            }
+        }
+        self.send(Event::Settings(window, max_frame_size));
+        Ok(())
+    }
+
+    fn end_headers(self: &mut Self) -> io::Result<()> {
+        let (id, flags) = self.continuing.take().unwrap();
+        // The decoder has to see every header block, even ones we end up ignoring, to keep
+        // its table in sync with the peer.
+        let headers = self
+            .decoder
+            .decode(&self.block)
+            .map_err(|e| protocol_error(&format!("failed to decode headers: {:?}", e)))?;
+
+        if !self.requests.contains_key(&id) {
+            if id % 2 == 0 || id <= self.last_stream {
+                return Err(protocol_error("invalid stream id"));
+            }
+            self.last_stream = id;
+            // Only the reader opens streams, so the count can't go past the limit.
+            if self.open.load(Ordering::SeqCst) >= self.options.max_concurrent_streams as usize {
+                let refused = REFUSED_STREAM.to_be_bytes().to_vec();
+                self.send(Event::Frame(RST_STREAM, 0, id, refused));
+                return Ok(());
+            }
+            self.open.fetch_add(1, Ordering::SeqCst);
+            let headers = headers
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        String::from_utf8_lossy(&name).into_owned(),
+                        String::from_utf8_lossy(&value).into_owned(),
+                    )
+                })
+                .collect();
+            self.requests.insert(
+                id,
+                Request {
+                    headers,
+                    body: Vec::new(),
+                },
+            );
+        }
+        // Anything else is a trailer, which only matters for ending the stream.
+        if flags & END_STREAM != 0 {
+            self.finish(id);
+        }
+        Ok(())
+    }
+
+    fn data(self: &mut Self, f: &Frame) -> io::Result<()> {
+        let len = f.payload.len() as u32;
+        // Bodies are bounded by max_message_size rather than flow control, so the windows
+        // are reopened right away.
+        if len > 0 {
+            self.send(Event::Frame(WINDOW_UPDATE, 0, 0, len.to_be_bytes().to_vec()));
+        }
+        let data = strip(f)?;
+        let too_large = match self.requests.get_mut(&f.stream_id) {
+            Some(request) => {
+                request.body.extend_from_slice(data);
+                request.body.len() > self.options.max_message_size + MESSAGE_HEADER_LEN
+            }
+            // The stream was reset, refused or already answered.
+            None => return Ok(()),
+        };
+        if too_large {
+            let request = self.requests.remove(&f.stream_id).unwrap();
+            let status = Status::new(Code::ResourceExhausted, "request message too large");
+            self.send(Event::Open(f.stream_id, request.content_type()));
+            self.send(Event::Status(f.stream_id, status));
+            return Ok(());
+        }
+        if f.flags & END_STREAM != 0 {
+            self.finish(f.stream_id);
+        } else if len > 0 {
+            let increment = len.to_be_bytes().to_vec();
+            self.send(Event::Frame(WINDOW_UPDATE, 0, f.stream_id, increment));
+        }
+        Ok(())
+    }
+
+    // Calls the method for a request that has been received completely. The writer
+    // answers it once the response arrives.
+    fn finish(self: &mut Self, id: u32) {
+        let request = match self.requests.remove(&id) {
+            Some(request) => request,
+            None => return,
+        };
+        self.send(Event::Open(id, request.content_type()));
+        match request.to_message(&self.peer, &self.options) {
+            Ok((m, timeout)) => {
+                let events = self.events.clone();
+                let call = self.calls.start(m, move |response| {
+                    let _ = events.send(Event::Response(id, response));
+                });
+                self.send(Event::Started(id, call, Instant::now() + timeout));
+            }
+            Err(status) => self.send(Event::Status(id, status)),
+        }
+    }
+}
+
+fn serve(stream: TcpStream, calls: Arc<Calls>, options: Options) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    // Responses are written as several small frames, which shouldn't wait on each other.
+    stream.set_nodelay(true)?;
+    let mut r = BufReader::new(stream.try_clone()?);
+    let mut preface = [0; 24];
+    r.read_exact(&mut preface)?;
+    if &preface[..] != PREFACE {
+        return Err(protocol_error("peer didn't start with the HTTP/2 preface"));
+    }
+
+    let open = Arc::new(AtomicUsize::new(0));
+    let mut writer = Writer {
+        stream: stream.try_clone()?,
+        encoder: hpack::Encoder::new(),
+        calls: calls.clone(),
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        initial_window: DEFAULT_WINDOW,
+        window: DEFAULT_WINDOW,
+        streams: HashMap::new(),
+        open: open.clone(),
+    };
+    let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+    settings.extend_from_slice(&options.max_concurrent_streams.to_be_bytes());
+    writer.frame(SETTINGS, 0, 0, &settings)?;
+    let (events, receiver) = mpsc::channel();
+    let writer_thread = thread::spawn(move || {
+        if let Err(e) = writer.run(receiver) {
+            println!("failed to write to grpc connection: {}", e);
+            // Stops the reader too.
+            let _ = writer.stream.shutdown(Shutdown::Both);
+        }
+    });
+
+    let mut reader = Reader {
+        events,
+        open,
+        calls,
+        options,
+        peer,
+        decoder: hpack::Decoder::new(),
+        requests: HashMap::new(),
+        continuing: None,
+        block: Vec::new(),
+        last_stream: 0,
+    };
+    let result = reader.run(&mut r);
+    let goaway = match result {
+        Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+            let mut goaway = reader.last_stream.to_be_bytes().to_vec();
+            goaway.extend_from_slice(&PROTOCOL_ERROR.to_be_bytes());
+            Some(goaway)
+        }
+        _ => None,
+    };
+    reader.send(Event::Close(goaway));
+    let _ = writer_thread.join();
+    result
+}
+
+/// Serves gRPC in front of a `Server`, so standard gRPC clients and tooling can call its
+/// handlers. A call to `/package.Service/Method` calls `package.Service/Method`, or the
+/// method `Options::methods` maps it onto, through the server's listeners, exactly like a
+/// message arriving on one of its connections would. The response status is sent back in
+/// the `grpc-status` and `grpc-message` trailers.
+///
+/// Only unary calls over cleartext HTTP/2 (h2c, with prior knowledge) are supported.
+/// `application/grpc+json` requests are handled with the JSON codec, and requests may be
+/// gzip compressed.
+pub struct GrpcGateway {
+    local_addr: SocketAddr,
+    _accept_thread: JoinHandle<()>,
+}
+
+impl GrpcGateway {
+    pub fn bind(addr: &SocketAddr, server: &server::Server, options: Options) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let calls = Arc::new(Calls::new(server));
+
+        let accept_thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("failed to accept: {}", e);
+                        continue;
+                    }
+                };
+                let calls = calls.clone();
+                let options = options.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, calls, options) {
+                        if e.kind() != ErrorKind::UnexpectedEof {
+                            println!("grpc connection failed: {}", e);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(GrpcGateway {
+            local_addr,
+            _accept_thread: accept_thread,
+        })
+    }
+
+    /// The address the gateway accepts connections on.
+    pub fn local_addr(self: &Self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::Api;
+    use crate::dispatcher::Dispatcher;
+    use crate::grpc::{
+        encode_message, method_name, parse_timeout, read_frame, strip, write_frame, Frame,
+        GrpcGateway, Options, Request, ACK, DATA, END_HEADERS, END_STREAM, HEADERS, PADDED,
+        PING, PREFACE, SETTINGS, WINDOW_UPDATE,
+    };
+    use crate::protos;
+    use crate::server;
+    use crate::status::{Code, Status};
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    struct EchoApi {}
+
+    impl Api<EchoApi> for EchoApi {
+        fn create_tls_api(self: &Self) -> EchoApi {
+            EchoApi {}
+        }
+    }
+
+    // Binds a gateway in front of a server whose dispatcher answers `Echo` with the
+    // request body. `rplay.Echoer/Echo` calls `Echo`.
+    fn gateway() -> GrpcGateway {
+        let mut server = server::Server::bind(&[], server::Options::default()).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(16);
+        server.add_listener(sender);
+        let mut options = Options::default();
+        options
+            .methods
+            .insert("rplay.Echoer/Echo".to_string(), "Echo".to_string());
+        let gateway =
+            GrpcGateway::bind(&"127.0.0.1:0".parse().unwrap(), &server, options).unwrap();
+        thread::spawn(move || {
+            let _dispatcher = Dispatcher::new(
+                receiver,
+                2,
+                |msg: &protos::Message, _: &mut EchoApi| -> protos::Message {
+                    if msg.get_method() != "Echo" {
+                        return Status::new(Code::Unimplemented, "no such method").to_message();
+                    }
+                    let mut m = protos::Message::new();
+                    m.set_body(msg.get_body().to_vec());
+                    m
+                },
+                &EchoApi {},
+            );
+            server.start();
+        });
+        gateway
+    }
+
+    // Just enough of an h2c client to make unary calls.
+    struct Client {
+        stream: TcpStream,
+        encoder: hpack::Encoder<'static>,
+        decoder: hpack::Decoder<'static>,
+    }
+
+    impl Client {
+        fn connect(addr: SocketAddr, settings: &[u8]) -> Self {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            stream.write_all(PREFACE).unwrap();
+            write_frame(&mut stream, SETTINGS, 0, 0, settings).unwrap();
+            Client {
+                stream,
+                encoder: hpack::Encoder::new(),
+                decoder: hpack::Decoder::new(),
+            }
+        }
+
+        fn send(self: &mut Self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+            write_frame(&mut self.stream, kind, flags, id, payload).unwrap();
+        }
+
+        fn call(self: &mut Self, id: u32, path: &str, message: &[u8]) {
+            let headers: Vec<(&[u8], &[u8])> = vec![
+                (b":method", b"POST"),
+                (b":scheme", b"http"),
+                (b":path", path.as_bytes()),
+                (b"content-type", b"application/grpc"),
+            ];
+            let block = self.encoder.encode(headers);
+            self.send(HEADERS, END_HEADERS, id, &block);
+            let mut data = vec![0];
+            data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            data.extend_from_slice(message);
+            self.send(DATA, END_STREAM, id, &data);
+        }
+
+        // The next frame that isn't about settings or the client's send windows.
+        fn next(self: &mut Self) -> Frame {
+            loop {
+                let f = read_frame(&mut self.stream).unwrap();
+                if f.kind != SETTINGS && f.kind != WINDOW_UPDATE {
+                    return f;
+                }
+            }
+        }
+
+        fn headers(self: &mut Self, f: &Frame) -> HashMap<String, String> {
+            self.decoder
+                .decode(&f.payload)
+                .unwrap()
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        String::from_utf8(name).unwrap(),
+                        String::from_utf8(value).unwrap(),
+                    )
+                })
+                .collect()
+        }
+
+        // Reads the rest of the response to a call, returning the data along with the
+        // headers and trailers.
+        fn response(self: &mut Self, id: u32) -> (Vec<u8>, HashMap<String, String>) {
+            let mut data = Vec::new();
+            let mut headers = HashMap::new();
+            loop {
+                let f = self.next();
+                assert_eq!(f.stream_id, id);
+                match f.kind {
+                    HEADERS => {
+                        headers.extend(self.headers(&f));
+                        if f.flags & END_STREAM != 0 {
+                            return (data, headers);
+                        }
+                    }
+                    DATA => data.extend_from_slice(&f.payload),
+                    kind => panic!("unexpected frame {}", kind),
+                }
+            }
+        }
+    }
+
+    fn request(content_type: &str, body: Vec<u8>) -> Request {
+        let mut headers = HashMap::new();
+        headers.insert(":method".to_string(), "POST".to_string());
+        headers.insert(":path".to_string(), "/rplay.Echoer/Echo".to_string());
+        headers.insert("content-type".to_string(), content_type.to_string());
+        headers.insert("grpc-timeout".to_string(), "250m".to_string());
+        Request { headers, body }
+    }
+
+    #[test]
+    fn verify_to_message() {
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let body = vec![0, 0, 0, 0, 2, b'{', b'}'];
+        let (m, timeout) = request("application/grpc+json", body.clone())
+            .to_message(&peer, &Options::default())
+            .unwrap();
+        assert_eq!(m.get_method(), "rplay.Echoer/Echo");
+        assert_eq!(m.get_body(), b"{}");
+        assert_eq!(m.get_annotations()["content-type"], "application/json");
+        assert_eq!(timeout, Duration::from_millis(250));
+
+        let mut options = Options::default();
+        options
+            .methods
+            .insert("rplay.Echoer/Echo".to_string(), "Echo".to_string());
+        let (m, _) = request("application/grpc+json", body)
+            .to_message(&peer, &options)
+            .unwrap();
+        assert_eq!(m.get_method(), "Echo");
+
+        let two_messages = vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let err = request("application/grpc", two_messages)
+            .to_message(&peer, &Options::default())
+            .unwrap_err();
+        assert_eq!(err.code, Code::Unimplemented);
+
+        let err = request("text/plain", vec![0, 0, 0, 0, 0])
+            .to_message(&peer, &Options::default())
+            .unwrap_err();
+        assert_eq!(err.code, Code::InvalidArgument);
+    }
+
+    #[test]
+    fn verify_helpers() {
+        assert_eq!(method_name("/pkg.Service/Method"), Some("pkg.Service/Method"));
+        assert_eq!(method_name("/Method"), None);
+        assert_eq!(method_name("pkg.Service/Method"), None);
+        assert_eq!(parse_timeout("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_timeout("5x"), None);
+        assert_eq!(encode_message("100% done\n"), "100%25 done%0A");
+    }
+
+    #[test]
+    fn verify_frame_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, DATA, PADDED, 3, &[2, b'h', b'i', 0, 0]).unwrap();
+        let f = read_frame(&mut &buf[..]).unwrap();
+        assert_eq!(f.stream_id, 3);
+        assert_eq!(strip(&f).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn verify_gateway() {
+        let gateway = gateway();
+        let mut client = Client::connect(gateway.local_addr(), &[]);
+        client.call(1, "/rplay.Echoer/Echo", b"hello");
+        let (data, trailers) = client.response(1);
+        assert_eq!(data, b"\0\0\0\0\x05hello".to_vec());
+        assert_eq!(trailers[":status"], "200");
+        assert_eq!(trailers["grpc-status"], "0");
+
+        // Methods that aren't mapped call the method with the full name.
+        client.call(3, "/rplay.Echoer/Missing", b"");
+        let (data, trailers) = client.response(3);
+        assert!(data.is_empty());
+        assert_eq!(trailers["grpc-status"], (Code::Unimplemented as u32).to_string());
+    }
+
+    #[test]
+    fn verify_flow_control() {
+        let gateway = gateway();
+        // Streams start out with a send window of two bytes.
+        let mut client = Client::connect(gateway.local_addr(), &[0, 4, 0, 0, 0, 2]);
+        client.call(1, "/rplay.Echoer/Echo", b"hello");
+        let f = client.next();
+        assert_eq!(f.kind, HEADERS);
+        client.headers(&f);
+        let f = client.next();
+        assert_eq!((f.kind, f.payload.len()), (DATA, 2));
+
+        // While the response waits for the window to open the connection keeps working.
+        client.send(PING, 0, 0, &[1; 8]);
+        let f = client.next();
+        assert_eq!((f.kind, f.flags, f.payload), (PING, ACK, vec![1; 8]));
+
+        client.send(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes());
+        let (data, trailers) = client.response(1);
+        assert_eq!(data, b"\0\0\x05hello".to_vec());
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+}
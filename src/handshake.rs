@@ -0,0 +1,149 @@
+use super::frame;
+use std::cmp;
+use std::io;
+use std::io::ErrorKind;
+
+/// The newest protocol version we speak.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The oldest protocol version we still speak.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Frames may be compressed, with one of the algorithms below.
+pub const FEATURE_COMPRESSION: u32 = 0x1;
+// 0x2 was set aside for streaming responses.
+pub const FEATURE_GZIP: u32 = 0x4;
+pub const FEATURE_LZ4: u32 = 0x8;
+pub const FEATURE_ZSTD: u32 = 0x10;
+/// Every frame carries a checksum.
+pub const FEATURE_CHECKSUM: u32 = 0x20;
+
+/// The features this build implements.
+pub const SUPPORTED_FEATURES: u32 =
+    FEATURE_COMPRESSION | FEATURE_GZIP | FEATURE_LZ4 | FEATURE_ZSTD | FEATURE_CHECKSUM;
+
+// Starts every preface, so that we fail clearly when talking to something else entirely.
+const MAGIC: &[u8; 4] = b"RPLY";
+const HELLO_LEN: usize = 16;
+
+/// The preface both ends send as the first frame on a connection, describing what they
+/// support.
+///
+/// The server waits for the client's preface and answers with its own, after which both
+/// ends independently agree on the same settings with `negotiate`. If there is nothing
+/// to agree on, the server closes the connection right after sending its preface so the
+/// client can report why.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub features: u32,
+    pub max_frame_size: u32,
+}
+
+impl Hello {
+    /// Creates our preface. Only features this build implements are advertised.
+    pub fn new(features: u32, max_frame_size: usize) -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: features & SUPPORTED_FEATURES,
+            max_frame_size: cmp::min(max_frame_size, u32::MAX as usize) as u32,
+        }
+    }
+
+    /// Encodes the preface as a frame.
+    pub fn encode(self: &Self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(HELLO_LEN);
+        payload.extend_from_slice(MAGIC);
+        payload.extend_from_slice(&self.min_version.to_be_bytes());
+        payload.extend_from_slice(&self.max_version.to_be_bytes());
+        payload.extend_from_slice(&self.features.to_be_bytes());
+        payload.extend_from_slice(&self.max_frame_size.to_be_bytes());
+        frame::encode_payload(frame::FLAG_HELLO, &payload)
+    }
+
+    /// Reads a preface from the first frame received on a connection.
+    pub fn decode(f: &frame::Frame) -> io::Result<Self> {
+        let p = &f.payload;
+        if !f.is_hello() || p.len() < HELLO_LEN || &p[..4] != MAGIC {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "peer didn't start with an rplay protocol preface",
+            ));
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([p[i], p[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([p[i], p[i + 1], p[i + 2], p[i + 3]]);
+        Ok(Hello {
+            min_version: u16_at(4),
+            max_version: u16_at(6),
+            features: u32_at(8),
+            max_frame_size: u32_at(12),
+        })
+    }
+}
+
+/// The settings both ends of a connection agreed on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: u32,
+    pub max_frame_size: usize,
+}
+
+impl Negotiated {
+    pub fn supports(self: &Self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+/// Agrees on the newest version and the features both ends support, and the smaller of
+/// the two maximum frame sizes.
+pub fn negotiate(local: &Hello, remote: &Hello) -> io::Result<Negotiated> {
+    let version = cmp::min(local.max_version, remote.max_version);
+    if version < cmp::max(local.min_version, remote.min_version) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "incompatible protocol versions: we speak {}-{}, the peer speaks {}-{}",
+                local.min_version, local.max_version, remote.min_version, remote.max_version
+            ),
+        ));
+    }
+    Ok(Negotiated {
+        version,
+        features: local.features & remote.features,
+        max_frame_size: cmp::min(local.max_frame_size, remote.max_frame_size) as usize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame::Decoder;
+    use crate::handshake::{negotiate, Hello, FEATURE_COMPRESSION, FEATURE_ZSTD};
+
+    #[test]
+    fn verify_round_trip() {
+        let hello = Hello::new(FEATURE_COMPRESSION, 1024);
+        let mut decoder = Decoder::new(1024);
+        decoder.extend(&hello.encode());
+        let f = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(Hello::decode(&f).unwrap(), hello);
+    }
+
+    #[test]
+    fn verify_negotiate() {
+        let local = Hello::new(FEATURE_COMPRESSION | FEATURE_ZSTD, 1024);
+        let mut remote = Hello::new(FEATURE_COMPRESSION, 512);
+        remote.max_version += 1;
+
+        let n = negotiate(&local, &remote).unwrap();
+        assert_eq!(n.version, local.max_version);
+        assert!(n.supports(FEATURE_COMPRESSION));
+        assert!(!n.supports(FEATURE_ZSTD));
+        assert_eq!(n.max_frame_size, 512);
+
+        remote.min_version = local.max_version + 1;
+        let err = negotiate(&local, &remote).unwrap_err();
+        assert!(err.to_string().contains("incompatible protocol versions"));
+    }
+}
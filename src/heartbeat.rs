@@ -0,0 +1,100 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Settings for heartbeats, sent by both ends of a connection to detect dead peers.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How often to ping a peer we haven't heard from.
+    pub interval: Duration,
+    /// How many pings in a row may go unanswered before the peer is considered dead.
+    pub max_missed: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            interval: Duration::from_secs(10),
+            max_missed: 3,
+        }
+    }
+}
+
+impl Options {
+    /// How often to check whether a ping is due.
+    pub fn tick(self: &Self) -> Duration {
+        cmp::max(self.interval / 4, Duration::from_millis(10))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Wait,
+    SendPing,
+    /// The peer missed too many pings, the connection should be closed.
+    Dead,
+}
+
+/// Tracks whether the peer of a single connection is still alive. Anything received from
+/// the peer counts as a sign of life, not just pongs.
+pub struct Tracker {
+    options: Options,
+    next_ping: Instant,
+    missed: u32,
+}
+
+impl Tracker {
+    pub fn new(options: Options, now: Instant) -> Self {
+        Tracker {
+            next_ping: now + options.interval,
+            options,
+            missed: 0,
+        }
+    }
+
+    /// Records that something was received from the peer.
+    pub fn received(self: &mut Self, now: Instant) {
+        self.missed = 0;
+        self.next_ping = now + self.options.interval;
+    }
+
+    pub fn poll(self: &mut Self, now: Instant) -> Action {
+        if now < self.next_ping {
+            return Action::Wait;
+        }
+        if self.missed >= self.options.max_missed {
+            return Action::Dead;
+        }
+        self.missed += 1;
+        self.next_ping = now + self.options.interval;
+        Action::SendPing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heartbeat::{Action, Options, Tracker};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn verify_tracker() {
+        let start = Instant::now();
+        let at = |s| start + Duration::from_secs(s);
+        let mut tracker = Tracker::new(
+            Options {
+                interval: Duration::from_secs(10),
+                max_missed: 2,
+            },
+            start,
+        );
+
+        assert_eq!(tracker.poll(at(5)), Action::Wait);
+        assert_eq!(tracker.poll(at(10)), Action::SendPing);
+
+        // Hearing back resets the count.
+        tracker.received(at(12));
+        assert_eq!(tracker.poll(at(21)), Action::Wait);
+        assert_eq!(tracker.poll(at(22)), Action::SendPing);
+        assert_eq!(tracker.poll(at(32)), Action::SendPing);
+        assert_eq!(tracker.poll(at(42)), Action::Dead);
+    }
+}
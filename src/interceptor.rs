@@ -0,0 +1,168 @@
+use super::protos;
+use super::status::Status;
+
+/// Hooks that run around every handler invocation.
+///
+/// `before` sees the request before the handler runs and may reject it by returning a
+/// status, in which case the handler is skipped and the status is sent back instead.
+/// `after` sees the request together with the response and may modify the response.
+/// Use `Status::of` to inspect the status of the response.
+pub trait Interceptor: Send + Sync {
+    fn before(self: &Self, _request: &protos::Message) -> Result<(), Status> {
+        Ok(())
+    }
+
+    fn after(self: &Self, _request: &protos::Message, _response: &mut protos::Message) {}
+}
+
+/// An ordered list of interceptors.
+///
+/// `before` hooks run in the order the interceptors were added and `after` hooks run in
+/// reverse order. If an interceptor rejects a request, the remaining `before` hooks and
+/// the handler are skipped, but the `after` hooks of the interceptors that already ran
+/// still see the rejection.
+#[derive(Default)]
+pub struct Chain {
+    interceptors: Vec<Box<dyn Interceptor>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Chain {
+            interceptors: Vec::new(),
+        }
+    }
+
+    pub fn with<I: Interceptor + 'static>(mut self: Self, interceptor: I) -> Self {
+        self.push(interceptor);
+        self
+    }
+
+    pub fn push<I: Interceptor + 'static>(self: &mut Self, interceptor: I) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Runs the request through the chain, calling `handler` if no interceptor rejects it.
+    pub fn intercept<F>(self: &Self, request: &protos::Message, handler: F) -> protos::Message
+    where
+        F: FnOnce() -> protos::Message,
+    {
+        let (ran, rejected) = self.run_before(request);
+        let mut response = match rejected {
+            Some(r) => r,
+            None => handler(),
+        };
+        self.run_after(ran, request, &mut response);
+        response
+    }
+
+    /// Runs the `before` hooks, stopping at the first rejection. Returns how many hooks
+    /// accepted the request along with the response to send if it was rejected.
+    pub fn run_before(self: &Self, request: &protos::Message) -> (usize, Option<protos::Message>) {
+        for (ran, i) in self.interceptors.iter().enumerate() {
+            if let Err(status) = i.before(request) {
+                return (ran, Some(status.to_message()));
+            }
+        }
+        (self.interceptors.len(), None)
+    }
+
+    /// Runs the `after` hooks of the first `ran` interceptors, in reverse order.
+    pub fn run_after(
+        self: &Self,
+        ran: usize,
+        request: &protos::Message,
+        response: &mut protos::Message,
+    ) {
+        for i in self.interceptors[..ran].iter().rev() {
+            i.after(request, response);
+        }
+    }
+}
+
+/// Logs every request along with the status of its response.
+pub struct Logger;
+
+impl Interceptor for Logger {
+    fn after(self: &Self, request: &protos::Message, response: &mut protos::Message) {
+        println!("handled {}: {}", request.get_method(), Status::of(response));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interceptor::{Chain, Interceptor};
+    use crate::protos;
+    use crate::status::{Code, Status};
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder {
+        name: &'static str,
+        reject: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Interceptor for Recorder {
+        fn before(self: &Self, _request: &protos::Message) -> Result<(), Status> {
+            self.log.lock().unwrap().push(format!("before {}", self.name));
+            if self.reject {
+                Err(Status::new(Code::PermissionDenied, self.name))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn after(self: &Self, _request: &protos::Message, response: &mut protos::Message) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("after {} {}", self.name, Status::of(response).code));
+        }
+    }
+
+    fn recorder(name: &'static str, reject: bool, log: &Arc<Mutex<Vec<String>>>) -> Recorder {
+        Recorder {
+            name,
+            reject,
+            log: log.clone(),
+        }
+    }
+
+    #[test]
+    fn verify_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::new()
+            .with(recorder("a", false, &log))
+            .with(recorder("b", false, &log));
+
+        let handler_log = log.clone();
+        chain.intercept(&protos::Message::new(), move || {
+            handler_log.lock().unwrap().push("handler".to_string());
+            protos::Message::new()
+        });
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "handler", "after b OK", "after a OK"]
+        );
+    }
+
+    #[test]
+    fn verify_rejection() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::new()
+            .with(recorder("a", false, &log))
+            .with(recorder("b", true, &log))
+            .with(recorder("c", false, &log));
+
+        let response = chain.intercept(&protos::Message::new(), || {
+            panic!("handler should not run");
+        });
+
+        assert_eq!(Status::of(&response), Status::new(Code::PermissionDenied, "b"));
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "after a PERMISSION_DENIED"]
+        );
+    }
+}
@@ -0,0 +1,447 @@
+extern crate base64;
+extern crate serde_json;
+
+use self::serde_json::{Map, Number, Value};
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label,
+    FieldDescriptorProto_Type,
+};
+use protobuf::reflect::{FieldDescriptor, MessageDescriptor, ProtobufValueRef, ReflectFieldRef};
+use protobuf::{CodedOutputStream, Message};
+use std::str::FromStr;
+
+// The name of a field in the JSON mapping, lowerCamelCase unless the descriptor says
+// otherwise.
+fn json_name(field: &FieldDescriptorProto) -> String {
+    if field.has_json_name() {
+        return field.get_json_name().to_string();
+    }
+    let mut name = String::new();
+    let mut upper = false;
+    for c in field.get_name().chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            name.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+fn float(v: f64) -> Value {
+    match Number::from_f64(v) {
+        Some(n) => Value::Number(n),
+        None if v.is_nan() => Value::String("NaN".to_string()),
+        None if v > 0. => Value::String("Infinity".to_string()),
+        None => Value::String("-Infinity".to_string()),
+    }
+}
+
+// Enums are written by name when `enum_names` says they can be read back, otherwise as
+// numbers.
+fn value_of(v: ProtobufValueRef, enum_names: bool) -> Value {
+    match v {
+        ProtobufValueRef::U32(v) => Value::from(v),
+        ProtobufValueRef::I32(v) => Value::from(v),
+        // 64 bit integers don't fit in a JSON number without losing precision.
+        ProtobufValueRef::U64(v) => Value::String(v.to_string()),
+        ProtobufValueRef::I64(v) => Value::String(v.to_string()),
+        ProtobufValueRef::F32(v) => float(f64::from(v)),
+        ProtobufValueRef::F64(v) => float(v),
+        ProtobufValueRef::Bool(v) => Value::Bool(v),
+        ProtobufValueRef::String(v) => Value::String(v.to_string()),
+        ProtobufValueRef::Bytes(v) => Value::String(base64::encode(v)),
+        ProtobufValueRef::Enum(v) if enum_names => Value::String(v.name().to_string()),
+        ProtobufValueRef::Enum(v) => Value::from(v.value()),
+        ProtobufValueRef::Message(m) => to_value(m),
+    }
+}
+
+fn key_of(v: ProtobufValueRef) -> String {
+    match value_of(v, false) {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// Converts a message to the protobuf JSON mapping. Fields holding their default value
+/// are left out, 64 bit integers are written as strings and bytes as base64. Enums are
+/// written by name if they are declared in the message using them, and as numbers
+/// otherwise, so that `merge_from_value` can always read them back.
+pub fn to_value(msg: &dyn Message) -> Value {
+    let mut object = Map::new();
+    let parent = msg.descriptor().get_proto();
+    for field in msg.descriptor().fields() {
+        let names = enum_names(parent, field.proto());
+        let value = match field.get_reflect(msg) {
+            ReflectFieldRef::Optional(Some(v)) => {
+                if !v.is_non_zero() {
+                    continue;
+                }
+                value_of(v, names)
+            }
+            ReflectFieldRef::Repeated(r) if r.len() > 0 => Value::Array(
+                r.reflect_iter()
+                    .map(|v| value_of(v.as_ref(), names))
+                    .collect(),
+            ),
+            ReflectFieldRef::Map(m) if m.len() > 0 => Value::Object(
+                m.reflect_iter()
+                    .map(|(k, v)| (key_of(k.as_ref()), value_of(v.as_ref(), names)))
+                    .collect(),
+            ),
+            _ => continue,
+        };
+        object.insert(json_name(field.proto()), value);
+    }
+    Value::Object(object)
+}
+
+// What the values of a field refer to, for message and enum fields.
+enum Nested<'a> {
+    Message(&'static MessageDescriptor),
+    Enum(Option<&'a EnumDescriptorProto>),
+    None,
+}
+
+fn short_name(type_name: &str) -> &str {
+    type_name.rsplit('.').next().unwrap_or(type_name)
+}
+
+// The synthesized entry type of a map field, which is always nested in the message
+// declaring the field.
+fn map_entry<'a>(
+    parent: &'a DescriptorProto,
+    field: &FieldDescriptorProto,
+) -> Option<&'a DescriptorProto> {
+    if field.get_field_type() != FieldDescriptorProto_Type::TYPE_MESSAGE {
+        return None;
+    }
+    let name = short_name(field.get_type_name());
+    parent
+        .get_nested_type()
+        .iter()
+        .find(|t| t.get_name() == name && t.get_options().get_map_entry())
+}
+
+// Enums can only be looked up by name when they are declared in the message using them,
+// otherwise they have to be given as numbers.
+fn enum_type<'a>(
+    parent: &'a DescriptorProto,
+    field: &FieldDescriptorProto,
+) -> Option<&'a EnumDescriptorProto> {
+    let name = short_name(field.get_type_name());
+    parent.get_enum_type().iter().find(|e| e.get_name() == name)
+}
+
+// Whether the enum values of a field, or of a map field's values, can be looked up by
+// name when decoding.
+fn enum_names(parent: &DescriptorProto, field: &FieldDescriptorProto) -> bool {
+    let value = match map_entry(parent, field) {
+        Some(entry) => entry.get_field().iter().find(|f| f.get_number() == 2),
+        None => Some(field),
+    };
+    value.is_some_and(|v| enum_type(parent, v).is_some())
+}
+
+fn message_of(v: ProtobufValueRef) -> Option<&'static MessageDescriptor> {
+    match v {
+        ProtobufValueRef::Message(m) => Some(m.descriptor()),
+        _ => None,
+    }
+}
+
+// protobuf 2 can't tell the type of a message field before it is set, so we merge an empty
+// one into a scratch message and look at what we got.
+fn message_type(
+    parent: &'static MessageDescriptor,
+    field: &FieldDescriptor,
+    is_map: bool,
+) -> Result<&'static MessageDescriptor, String> {
+    // A map entry holding a default key and an empty value.
+    let entry: &[u8] = if is_map { &[0x12, 0x00] } else { &[] };
+    let mut bytes = Vec::new();
+    {
+        let mut out = CodedOutputStream::vec(&mut bytes);
+        out.write_bytes(field.proto().get_number() as u32, entry)
+            .and_then(|_| out.flush())
+            .map_err(|e| e.to_string())?;
+    }
+    let mut scratch = parent.new_instance();
+    scratch
+        .merge_from_bytes(&bytes)
+        .map_err(|e| e.to_string())?;
+    let found = match field.get_reflect(&*scratch) {
+        ReflectFieldRef::Optional(Some(v)) => message_of(v),
+        ReflectFieldRef::Repeated(r) => {
+            r.reflect_iter().next().and_then(|v| message_of(v.as_ref()))
+        }
+        ReflectFieldRef::Map(m) => {
+            m.reflect_iter().next().and_then(|(_, v)| message_of(v.as_ref()))
+        }
+        ReflectFieldRef::Optional(None) => None,
+    };
+    found.ok_or_else(|| format!("can't find the type of {}", field.name()))
+}
+
+fn nested(
+    parent: &'static MessageDescriptor,
+    field: &FieldDescriptor,
+    value: &FieldDescriptorProto,
+    is_map: bool,
+) -> Result<Nested<'static>, String> {
+    match value.get_field_type() {
+        FieldDescriptorProto_Type::TYPE_MESSAGE => {
+            message_type(parent, field, is_map).map(Nested::Message)
+        }
+        FieldDescriptorProto_Type::TYPE_ENUM => {
+            Ok(Nested::Enum(enum_type(parent.get_proto(), value)))
+        }
+        _ => Ok(Nested::None),
+    }
+}
+
+fn integer<T: FromStr>(value: &Value) -> Result<T, String> {
+    let parsed = match *value {
+        Value::Number(ref n) => n.to_string().parse().ok(),
+        Value::String(ref s) => s.parse().ok(),
+        _ => None,
+    };
+    parsed.ok_or_else(|| format!("expected an integer, got {}", value))
+}
+
+fn floating(value: &Value) -> Result<f64, String> {
+    let parsed = match *value {
+        Value::Number(ref n) => n.as_f64(),
+        Value::String(ref s) => match s.as_str() {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        },
+        _ => None,
+    };
+    parsed.ok_or_else(|| format!("expected a number, got {}", value))
+}
+
+fn text(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("expected a string, got {}", value))
+}
+
+fn enum_number(value: &Value, nested: &Nested) -> Result<i32, String> {
+    if let (Value::String(ref name), Nested::Enum(Some(e))) = (value, nested) {
+        return e
+            .get_value()
+            .iter()
+            .find(|v| v.get_name() == name)
+            .map(|v| v.get_number())
+            .ok_or_else(|| format!("unknown enum value {}", name));
+    }
+    integer(value)
+}
+
+// Writes a single value of a field in the protobuf binary encoding.
+fn encode_value(
+    ty: FieldDescriptorProto_Type,
+    number: u32,
+    value: &Value,
+    nested: &Nested,
+    out: &mut CodedOutputStream,
+) -> Result<(), String> {
+    use protobuf::descriptor::FieldDescriptorProto_Type::*;
+
+    let written = match ty {
+        TYPE_DOUBLE => out.write_double(number, floating(value)?),
+        TYPE_FLOAT => out.write_float(number, floating(value)? as f32),
+        TYPE_INT64 => out.write_int64(number, integer(value)?),
+        TYPE_UINT64 => out.write_uint64(number, integer(value)?),
+        TYPE_INT32 => out.write_int32(number, integer(value)?),
+        TYPE_FIXED64 => out.write_fixed64(number, integer(value)?),
+        TYPE_FIXED32 => out.write_fixed32(number, integer(value)?),
+        TYPE_UINT32 => out.write_uint32(number, integer(value)?),
+        TYPE_SFIXED32 => out.write_sfixed32(number, integer(value)?),
+        TYPE_SFIXED64 => out.write_sfixed64(number, integer(value)?),
+        TYPE_SINT32 => out.write_sint32(number, integer(value)?),
+        TYPE_SINT64 => out.write_sint64(number, integer(value)?),
+        TYPE_BOOL => match *value {
+            Value::Bool(b) => out.write_bool(number, b),
+            _ => return Err(format!("expected a boolean, got {}", value)),
+        },
+        TYPE_STRING => out.write_string(number, text(value)?),
+        TYPE_BYTES => {
+            let s = text(value)?;
+            let bytes = base64::decode(s)
+                .or_else(|_| base64::decode_config(s, base64::URL_SAFE))
+                .map_err(|e| format!("invalid base64: {}", e))?;
+            out.write_bytes(number, &bytes)
+        }
+        TYPE_ENUM => out.write_enum(number, enum_number(value, nested)?),
+        TYPE_MESSAGE => match *nested {
+            Nested::Message(descriptor) => {
+                out.write_bytes(number, &encode_message(descriptor, value)?)
+            }
+            _ => return Err("unknown message type".to_string()),
+        },
+        TYPE_GROUP => return Err("groups aren't supported".to_string()),
+    };
+    written.map_err(|e| e.to_string())
+}
+
+// Parses a JSON object key into the JSON value of a map key.
+fn map_key(key: &str, ty: FieldDescriptorProto_Type) -> Value {
+    match ty {
+        FieldDescriptorProto_Type::TYPE_BOOL => Value::Bool(key == "true"),
+        _ => Value::String(key.to_string()),
+    }
+}
+
+fn encode_field(
+    parent: &'static MessageDescriptor,
+    field: &FieldDescriptor,
+    value: &Value,
+    out: &mut CodedOutputStream,
+) -> Result<(), String> {
+    let proto = field.proto();
+    let number = proto.get_number() as u32;
+    if proto.get_label() != FieldDescriptorProto_Label::LABEL_REPEATED {
+        let nested = nested(parent, field, proto, false)?;
+        return encode_value(proto.get_field_type(), number, value, &nested, out);
+    }
+
+    if let Some(entry) = map_entry(parent.get_proto(), proto) {
+        let object = value
+            .as_object()
+            .ok_or_else(|| format!("expected an object for {}", field.name()))?;
+        let key = entry.get_field().iter().find(|f| f.get_number() == 1);
+        let val = entry.get_field().iter().find(|f| f.get_number() == 2);
+        let (key, val) = match (key, val) {
+            (Some(key), Some(val)) => (key, val),
+            _ => return Err(format!("invalid map entry for {}", field.name())),
+        };
+        let nested = nested(parent, field, val, true)?;
+        for (k, v) in object {
+            let mut bytes = Vec::new();
+            {
+                let mut e = CodedOutputStream::vec(&mut bytes);
+                let k = map_key(k, key.get_field_type());
+                encode_value(key.get_field_type(), 1, &k, &Nested::None, &mut e)?;
+                encode_value(val.get_field_type(), 2, v, &nested, &mut e)?;
+                e.flush().map_err(|e| e.to_string())?;
+            }
+            out.write_bytes(number, &bytes)
+                .map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+
+    let values = value
+        .as_array()
+        .ok_or_else(|| format!("expected an array for {}", field.name()))?;
+    let nested = nested(parent, field, proto, false)?;
+    for v in values {
+        encode_value(proto.get_field_type(), number, v, &nested, out)?;
+    }
+    Ok(())
+}
+
+fn encode_message(
+    descriptor: &'static MessageDescriptor,
+    value: &Value,
+) -> Result<Vec<u8>, String> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| format!("expected an object for {}", descriptor.name()))?;
+    let mut bytes = Vec::new();
+    {
+        let mut out = CodedOutputStream::vec(&mut bytes);
+        for (name, value) in object {
+            if value.is_null() {
+                continue;
+            }
+            let field = descriptor
+                .fields()
+                .iter()
+                .find(|f| f.name() == name || json_name(f.proto()) == *name)
+                .ok_or_else(|| format!("unknown field {} in {}", name, descriptor.name()))?;
+            encode_field(descriptor, field, value, &mut out)?;
+        }
+        out.flush().map_err(|e| e.to_string())?;
+    }
+    Ok(bytes)
+}
+
+/// Merges the protobuf JSON mapping of a message into `into`. Fields may be named by
+/// their JSON or their original name, unknown fields are an error.
+pub fn merge_from_value(into: &mut dyn Message, value: &Value) -> Result<(), String> {
+    let bytes = encode_message(into.descriptor(), value)?;
+    into.merge_from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serde_json::json;
+    use crate::json;
+    use crate::protos;
+    use protobuf::well_known_types::{Field, Field_Kind, Syntax, Type};
+
+    #[test]
+    fn verify_round_trip() {
+        let mut m = protos::Message::new();
+        m.set_session(1 << 40);
+        m.set_method("Echo".to_string());
+        m.set_body(b"\x00\xff".to_vec());
+        m.mut_annotations()
+            .insert("content-type".to_string(), "application/json".to_string());
+
+        let value = json::to_value(&m);
+        assert_eq!(
+            value,
+            json!({
+                "session": "1099511627776",
+                "method": "Echo",
+                "body": "AP8=",
+                "annotations": {"content-type": "application/json"},
+            })
+        );
+        let mut decoded = protos::Message::new();
+        json::merge_from_value(&mut decoded, &value).unwrap();
+        assert_eq!(decoded, m);
+    }
+
+    #[test]
+    fn verify_enums() {
+        let mut field = Field::new();
+        field.set_kind(Field_Kind::TYPE_STRING);
+        field.set_name("name".to_string());
+        let mut t = Type::new();
+        t.set_name("Person".to_string());
+        t.mut_fields().push(field);
+        // `Syntax` is declared outside of `Type`.
+        t.set_syntax(Syntax::SYNTAX_PROTO3);
+
+        let value = json::to_value(&t);
+        assert_eq!(value["syntax"], json!(1));
+        assert_eq!(value["fields"][0]["kind"], json!("TYPE_STRING"));
+        let mut decoded = Type::new();
+        json::merge_from_value(&mut decoded, &value).unwrap();
+        assert_eq!(decoded, t);
+    }
+
+    #[test]
+    fn verify_decode() {
+        let mut m = protos::Message::new();
+        // Numbers are accepted for 64 bit integers, and null for any field.
+        let value = json!({"session": 7, "method": null, "annotations": {}});
+        json::merge_from_value(&mut m, &value).unwrap();
+        assert_eq!(m.get_session(), 7);
+
+        assert!(json::merge_from_value(&mut m, &json!({"missing": 1})).is_err());
+        assert!(json::merge_from_value(&mut m, &json!({"session": "x"})).is_err());
+        assert!(json::merge_from_value(&mut m, &json!([])).is_err());
+    }
+}
@@ -1,15 +1,49 @@
+
 extern crate mio;
 extern crate protobuf;
+mod acl;
 mod api;
+mod auth;
 mod client;
+mod codec;
+mod compression;
 mod dispatcher;
+mod executor;
+mod filter;
+mod frame;
+mod gateway;
+mod grpc;
+mod handshake;
+mod heartbeat;
+mod interceptor;
+mod json;
+#[cfg(test)]
+mod memory;
+mod net;
 mod protos;
+mod ratelimit;
 mod redis_api;
 mod server;
+mod shedding;
+mod status;
+mod tls;
+mod udp;
+mod websocket;
 
+use protobuf::Message;
+use std::cell::RefCell;
 use std::env;
+use std::fs;
+use std::future;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
 
+// Decodes the request with the codec named by its content type, calls the handler and
+// encodes the response with the same codec.
 fn handler<'a, F, S, T, A>(msg: &protos::Message, f: F, api: &'a mut A) -> protos::Message
 where
     F: Fn(&S, &'a A) -> T,
@@ -17,16 +51,29 @@ where
     T: protobuf::Message,
     A: 'a,
 {
+    let codec = match codec::of(msg) {
+        Ok(codec) => codec,
+        Err(status) => return status.to_message(),
+    };
     let mut request = S::new();
-    let mut cos = protobuf::CodedInputStream::from_bytes(msg.get_body());
-    request.merge_from(&mut cos).unwrap();
+    if let Err(e) = codec.decode(msg.get_body(), &mut request) {
+        let message = format!("failed to decode request: {}", e);
+        return status::Status::new(status::Code::InvalidArgument, &message).to_message();
+    }
 
+    let response = f(&request, api);
     let mut m = protos::Message::new();
-    {
-        let response = f(&request, api);
-        m.set_body(response.write_to_bytes().unwrap());
+    match codec.encode(&response) {
+        Ok(body) => m.set_body(body),
+        Err(e) => {
+            let message = format!("failed to encode response: {}", e);
+            return status::Status::new(status::Code::Internal, &message).to_message();
+        }
     }
-
+    m.mut_annotations().insert(
+        codec::CONTENT_TYPE_ANNOTATION.to_string(),
+        codec.content_type().to_string(),
+    );
     m
 }
 
@@ -46,31 +93,428 @@ macro_rules! handle {
 fn main() {
     let args: Vec<String> = env::args().collect();
 
+    // Issues a token accepted by a server started with `--hmac-key <key>`.
+    if args[1] == "sign-token" {
+        let ttl = match args[4].parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(e) => {
+                println!("invalid ttl {}: {}", args[4], e);
+                return;
+            }
+        };
+        println!("{}", auth::HmacTokens::new(args[2].as_bytes()).sign(&args[3], ttl));
+    }
+
     if args[1] == "client" {
+        // `--tls-ca <pem>` connects over TLS, trusting the CA certificates in the file and
+        // verifying the server certificate against `--tls-name <name>`, localhost unless
+        // given. `--tls-cert <pem>` and `--tls-key <pem>` present a client certificate,
+        // `--tls-alpn <protocols>` offers the comma separated ALPN protocols and
+        // `--token <token>` attaches a bearer token to the message. `--udp` sends the
+        // message in a datagram instead and waits up to a second for the response, or
+        // doesn't wait for one at all with `--one-way`. `--once` exits after the first
+        // response rather than printing responses until the connection is lost.
+        let mut options = client::Options::default();
+        let mut once = false;
+        let mut udp = false;
+        let mut one_way = false;
+        let mut tls_ca = None;
+        let mut tls_name = "localhost".to_string();
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut tls_alpn = None;
+        let mut iter = args[3..].iter();
+        while let Some(a) = iter.next() {
+            if a == "--tls-ca" {
+                tls_ca = iter.next().cloned();
+            } else if a == "--tls-name" {
+                tls_name = iter.next().cloned().unwrap_or(tls_name);
+            } else if a == "--tls-cert" {
+                tls_cert = iter.next().cloned();
+            } else if a == "--tls-key" {
+                tls_key = iter.next().cloned();
+            } else if a == "--tls-alpn" {
+                tls_alpn = iter.next().cloned();
+            } else if a == "--token" {
+                options.token = iter.next().cloned();
+            } else if a == "--once" {
+                once = true;
+            } else if a == "--udp" {
+                udp = true;
+            } else if a == "--one-way" {
+                one_way = true;
+            }
+        }
+        if let Some(ca) = tls_ca {
+            let tls = tls::ClientTls::from_pem(Path::new(&ca), &tls_name).and_then(|mut t| {
+                if let (Some(cert), Some(key)) = (&tls_cert, &tls_key) {
+                    t.set_client_cert_pem(Path::new(cert), Path::new(key))?;
+                }
+                if let Some(ref protocols) = tls_alpn {
+                    t.set_alpn(&protocols.split(',').collect::<Vec<_>>());
+                }
+                Ok(t)
+            });
+            match tls {
+                Ok(t) => options.tls = Some(t),
+                Err(e) => {
+                    println!("failed to load tls settings: {}", e);
+                    return;
+                }
+            }
+        }
+
         let mut ping = protos::Ping::new();
         ping.set_data("hello server".to_string());
 
-        client::Client::send(&args[2], &String::from("call"), &ping);
+        if udp {
+            let addr = match args[2].parse::<std::net::SocketAddr>() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let mut m = protos::Message::new();
+            m.set_method("Echo".to_string());
+            m.set_body(ping.write_to_bytes().unwrap());
+            if let Some(ref token) = options.token {
+                auth::attach_token(&mut m, token);
+            }
+            let result = udp::UdpClient::connect(&addr).and_then(|mut c| {
+                if one_way {
+                    c.send(m).map(|_| None)
+                } else {
+                    c.call(m, Duration::from_secs(1)).map(Some)
+                }
+            });
+            match result {
+                Ok(Some(response)) => {
+                    let status = status::Status::of(&response);
+                    println!("received {} response to call", status.code.as_str());
+                }
+                Ok(None) => {}
+                Err(e) => println!("udp call to {} failed: {}", addr, e),
+            }
+            return;
+        }
+        if once {
+            let stream = match args[2].parse().and_then(|a| net::Stream::connect(&a)) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("failed to connect to {}: {}", args[2], e);
+                    return;
+                }
+            };
+            let name = String::from("Echo");
+            match client::Client::call(Box::new(stream), &name, &ping, &options) {
+                Ok(response) => {
+                    let status = status::Status::of(&response);
+                    println!("received {} response to call", status.code.as_str());
+                }
+                Err(e) => println!("call to {} failed: {}", args[2], e),
+            }
+            return;
+        }
+        client::Client::send_with_options(&args[2], &String::from("Echo"), &ping, &options);
     }
 
     if args[1] == "server" {
-        let mut server = server::Server::new(&args[2]);
-        let (s, r) = mpsc::channel();
+        // `--http <addr>` and `--grpc <addr>` additionally serve the HTTP/JSON and gRPC
+        // gateways on those addresses, and `--udp <addr>` accepts datagrams. `--async`
+        // runs the handlers on each worker's executor, and `--work-stealing` lets idle
+        // workers take requests without a session from busy ones. `--tls-cert <pem>` and
+        // `--tls-key <pem>` serve TLS with the given certificate chain and private key, and
+        // `--tls-client-ca <pem>` requires clients to present a certificate signed by one
+        // of the CAs in the file. `--tls-sni <hostname>=<cert pem>,<key pem>` presents
+        // another certificate to clients asking for the hostname, and `--tls-alpn
+        // <protocols>` offers the comma separated ALPN protocols. Messages have to carry
+        // a bearer token when any of `--token <token>=<identity>`, `--hmac-key <key>`,
+        // `--jwt-secret <secret>` or `--jwt-public-key <pem>` is given. Tokens are checked
+        // against the static ones if there are any, otherwise against the last key given.
+        // `--acl <toml>` only lets callers call the methods the file grants them, and
+        // picks up changes to it. `--client-rate-limit <n>` lets each client make n calls
+        // per second and `--method-rate-limit <pattern>=<n>` allows n calls per second to
+        // each method matching the pattern. The limits are shared through Redis when
+        // `--redis-rate-limit <url>` is given.
+        let mut async_handlers = false;
+        let mut mode = dispatcher::Mode::Session;
+        let mut http = None;
+        let mut grpc = None;
+        let mut udp = None;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut tls_client_ca = None;
+        let mut tls_sni = Vec::new();
+        let mut tls_alpn = None;
+        let mut tokens = None;
+        let mut authenticator: Option<Arc<dyn auth::Authenticator>> = None;
+        let mut acl = None;
+        let mut client_rate = None;
+        let mut method_rates = Vec::new();
+        let mut redis_rate_limit = None;
+        let mut rest = Vec::new();
+        let mut iter = args[2..].iter();
+        while let Some(a) = iter.next() {
+            if a == "--http" {
+                http = iter.next().cloned();
+            } else if a == "--grpc" {
+                grpc = iter.next().cloned();
+            } else if a == "--udp" {
+                udp = iter.next().cloned();
+            } else if a == "--tls-cert" {
+                tls_cert = iter.next().cloned();
+            } else if a == "--tls-key" {
+                tls_key = iter.next().cloned();
+            } else if a == "--tls-client-ca" {
+                tls_client_ca = iter.next().cloned();
+            } else if a == "--tls-sni" {
+                let sni = iter.next().and_then(|s| s.split_once('='));
+                match sni.map(|(hostname, files)| (hostname, files.split_once(','))) {
+                    Some((hostname, Some((cert, key)))) => {
+                        tls_sni.push((hostname.to_string(), cert.to_string(), key.to_string()))
+                    }
+                    Some((_, None)) => {
+                        println!("invalid sni certificate, expected <hostname>=<cert>,<key>");
+                        return;
+                    }
+                    None => {}
+                }
+            } else if a == "--tls-alpn" {
+                tls_alpn = iter.next().cloned();
+            } else if a == "--token" {
+                if let Some((token, identity)) = iter.next().and_then(|t| t.split_once('=')) {
+                    let t = tokens.take().unwrap_or_else(auth::StaticTokens::new);
+                    tokens = Some(t.with(token, identity));
+                }
+            } else if a == "--hmac-key" {
+                if let Some(key) = iter.next() {
+                    authenticator = Some(Arc::new(auth::HmacTokens::new(key.as_bytes())));
+                }
+            } else if a == "--jwt-secret" {
+                if let Some(secret) = iter.next() {
+                    authenticator = Some(Arc::new(auth::Jwt::hs256(secret.as_bytes())));
+                }
+            } else if a == "--jwt-public-key" {
+                let jwt = iter.next().map(|path| match fs::read(path) {
+                    Ok(pem) => auth::Jwt::rs256_pem(&pem),
+                    Err(e) => Err(e.to_string()),
+                });
+                match jwt {
+                    Some(Ok(jwt)) => authenticator = Some(Arc::new(jwt)),
+                    Some(Err(e)) => {
+                        println!("failed to load jwt public key: {}", e);
+                        return;
+                    }
+                    None => {}
+                }
+            } else if a == "--acl" {
+                acl = iter.next().cloned();
+            } else if a == "--client-rate-limit" {
+                match iter.next().map(|n| n.parse::<u32>()) {
+                    Some(Ok(n)) => client_rate = Some(n),
+                    Some(Err(e)) => {
+                        println!("invalid rate limit: {}", e);
+                        return;
+                    }
+                    None => {}
+                }
+            } else if a == "--method-rate-limit" {
+                let limit = iter.next().and_then(|l| l.split_once('='));
+                match limit.map(|(pattern, n)| (pattern, n.parse::<u32>())) {
+                    Some((pattern, Ok(n))) => method_rates.push((pattern.to_string(), n)),
+                    Some((_, Err(e))) => {
+                        println!("invalid rate limit: {}", e);
+                        return;
+                    }
+                    None => {}
+                }
+            } else if a == "--redis-rate-limit" {
+                redis_rate_limit = iter.next().cloned();
+            } else if a == "--async" {
+                async_handlers = true;
+            } else if a == "--work-stealing" {
+                mode = dispatcher::Mode::WorkStealing;
+            } else {
+                rest.push(a);
+            }
+        }
+        let addrs: io::Result<Vec<net::Address>> = rest.iter().map(|a| a.parse()).collect();
+        let addrs = match addrs {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        let mut server_options = server::Options::default();
+        if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+            let tls = tls::ServerTls::from_pem(Path::new(&cert), Path::new(&key)).and_then(|mut t| {
+                if let Some(ref ca) = tls_client_ca {
+                    t.require_client_cert(Path::new(ca))?;
+                }
+                for (hostname, cert, key) in tls_sni.iter() {
+                    t.add_sni_pem(hostname, Path::new(cert), Path::new(key))?;
+                }
+                if let Some(ref protocols) = tls_alpn {
+                    t.set_alpn(&protocols.split(',').collect::<Vec<_>>());
+                }
+                Ok(t)
+            });
+            match tls {
+                Ok(t) => server_options.tls = Some(t),
+                Err(e) => {
+                    println!("failed to load tls settings: {}", e);
+                    return;
+                }
+            }
+        }
+        server_options.authenticator = match tokens {
+            Some(t) => Some(Arc::new(t)),
+            None => authenticator,
+        };
+        let mut server_interceptors = interceptor::Chain::new();
+        let _acl_watcher = match acl.map(|path| acl::AclStore::load(Path::new(&path))) {
+            Some(Ok(store)) => {
+                server_interceptors = server_interceptors.with(store.clone());
+                Some(acl::AclStore::watch(&store, Duration::from_secs(5)))
+            }
+            Some(Err(e)) => {
+                println!("failed to load acl: {}", e);
+                return;
+            }
+            None => None,
+        };
+        if client_rate.is_some() || !method_rates.is_empty() {
+            let mut limiter = match redis_rate_limit {
+                Some(url) => match ratelimit::RedisBuckets::connect(&url) {
+                    Ok(buckets) => ratelimit::RateLimiter::with_backend(buckets),
+                    Err(e) => {
+                        println!("failed to connect to redis for rate limits: {}", e);
+                        return;
+                    }
+                },
+                None => ratelimit::RateLimiter::new(),
+            };
+            for (pattern, n) in method_rates {
+                limiter = limiter.per_method(&pattern, ratelimit::Quota::per_second(n));
+            }
+            if let Some(n) = client_rate {
+                limiter = limiter.per_client("*", ratelimit::Quota::per_second(n));
+            }
+            server_interceptors = server_interceptors.with(limiter);
+        }
+        server_options.interceptors = Arc::new(server_interceptors);
+        let mut server = match server::Server::bind(&addrs, server_options) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("failed to bind: {}", e);
+                return;
+            }
+        };
+        for addr in server.local_addrs() {
+            println!("listening on {}", addr);
+        }
+        let (s, r) = mpsc::sync_channel(1024);
         server.add_listener(s);
 
+        let _gateway = match http.map(|a| a.parse::<std::net::SocketAddr>()) {
+            Some(Ok(addr)) => {
+                match gateway::HttpGateway::bind(&addr, &server, gateway::Options::default()) {
+                    Ok(g) => {
+                        println!("http gateway listening on {}", g.local_addr());
+                        Some(g)
+                    }
+                    Err(e) => {
+                        println!("failed to bind http gateway: {}", e);
+                        return;
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                println!("{}", e);
+                return;
+            }
+            None => None,
+        };
+        let _grpc = match grpc.map(|a| a.parse::<std::net::SocketAddr>()) {
+            Some(Ok(addr)) => {
+                match grpc::GrpcGateway::bind(&addr, &server, grpc::Options::default()) {
+                    Ok(g) => {
+                        println!("grpc gateway listening on {}", g.local_addr());
+                        Some(g)
+                    }
+                    Err(e) => {
+                        println!("failed to bind grpc gateway: {}", e);
+                        return;
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                println!("{}", e);
+                return;
+            }
+            None => None,
+        };
+
         let api = redis_api::RedisApi {
             addr: "127.0.0.1:6379".to_string(),
         };
-        let _ = dispatcher::Dispatcher::new(
-            r,
-            4,
-            handle!{
-                redis_api::RedisTlsApi,
-                "Echo" => |_request: &protos::Ping, _api| -> protos::Pong { protos::Pong::new() },
-                "Bara" => |_request: &protos::Ping, _api| -> protos::Pong { protos::Pong::new() }
-            },
-            &api,
-        );
+        let handler = handle!{
+            redis_api::RedisTlsApi,
+            "Echo" => |_request: &protos::Ping, _api| -> protos::Pong { protos::Pong::new() },
+            "Bara" => |_request: &protos::Ping, _api| -> protos::Pong { protos::Pong::new() }
+        };
+        let interceptors = Arc::new(interceptor::Chain::new().with(interceptor::Logger));
+        let options = dispatcher::Options {
+            mode,
+            interceptors: interceptors.clone(),
+            ..Default::default()
+        };
+        let _ = if async_handlers {
+            let async_handler = move |msg: Arc<protos::Message>, api: Rc<RefCell<_>>| {
+                let response = handler(&msg, &mut api.borrow_mut());
+                Box::pin(future::ready(response)) as dispatcher::HandlerFuture
+            };
+            dispatcher::Dispatcher::new_async(r, 4, async_handler, &api, options)
+        } else {
+            dispatcher::Dispatcher::with_options(r, 4, handler, &api, options)
+        };
+
+        let _udp = match udp.map(|a| a.parse::<std::net::SocketAddr>()) {
+            Some(Ok(addr)) => {
+                let mut udp_server = match udp::UdpServer::bind(&addr, udp::Options::default()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        println!("failed to bind udp: {}", e);
+                        return;
+                    }
+                };
+                match udp_server.local_addr() {
+                    Ok(addr) => println!("udp listening on {}", addr),
+                    Err(e) => println!("failed to get udp address: {}", e),
+                }
+                let (s, r) = mpsc::sync_channel(1024);
+                udp_server.add_listener(s);
+                let dispatcher = dispatcher::Dispatcher::with_options(
+                    r,
+                    1,
+                    handler,
+                    &api,
+                    dispatcher::Options {
+                        interceptors,
+                        ..Default::default()
+                    },
+                );
+                std::thread::spawn(move || udp_server.start());
+                Some(dispatcher)
+            }
+            Some(Err(e)) => {
+                println!("{}", e);
+                return;
+            }
+            None => None,
+        };
         server.start();
     }
 }
@@ -0,0 +1,311 @@
+use super::net::{Accepted, Connection, Transport};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+
+// The bytes flowing in one direction of a connection.
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    // The writing end shut down, so reads see EOF once the buffer is drained.
+    write_closed: bool,
+    // The reading end shut down, so writes fail.
+    read_closed: bool,
+}
+
+// Every clone of an end is registered with its own poll, so each one has its own readiness.
+type Readiness = Arc<Mutex<Vec<SetReadiness>>>;
+
+// Tells an end's polls whether there is anything for it to read. Writing never blocks.
+fn update(readiness: &Readiness, incoming: &Pipe) {
+    let mut ready = Ready::writable();
+    if !incoming.buf.is_empty() || incoming.write_closed || incoming.read_closed {
+        ready |= Ready::readable();
+    }
+    for r in readiness.lock().unwrap().iter() {
+        let _ = r.set_readiness(ready);
+    }
+}
+
+// One end of a connection, shared by all of its clones.
+struct End {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    readiness: Readiness,
+    // The other end's readiness, updated whenever we write to it.
+    peer: Readiness,
+}
+
+impl End {
+    fn shutdown(self: &Self, how: Shutdown) {
+        if how != Shutdown::Read {
+            let mut outgoing = self.outgoing.lock().unwrap();
+            outgoing.write_closed = true;
+            update(&self.peer, &outgoing);
+        }
+        if how != Shutdown::Write {
+            let mut incoming = self.incoming.lock().unwrap();
+            incoming.read_closed = true;
+            incoming.buf.clear();
+            update(&self.readiness, &incoming);
+        }
+    }
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.shutdown(Shutdown::Both);
+    }
+}
+
+/// One end of an in-process connection. Clones share the same end, which is closed once
+/// all of them are dropped.
+pub struct MemoryStream {
+    end: Arc<End>,
+    registration: Registration,
+}
+
+impl MemoryStream {
+    fn new(end: Arc<End>) -> Self {
+        let (registration, readiness) = Registration::new2();
+        let incoming = end.incoming.lock().unwrap();
+        end.readiness.lock().unwrap().push(readiness);
+        update(&end.readiness, &incoming);
+        drop(incoming);
+        MemoryStream { end, registration }
+    }
+}
+
+impl Clone for MemoryStream {
+    fn clone(&self) -> Self {
+        MemoryStream::new(self.end.clone())
+    }
+}
+
+/// Creates two connected streams.
+pub fn pair() -> (MemoryStream, MemoryStream) {
+    let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+    let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+    let a_readiness: Readiness = Arc::new(Mutex::new(Vec::new()));
+    let b_readiness: Readiness = Arc::new(Mutex::new(Vec::new()));
+    let a = End {
+        incoming: b_to_a.clone(),
+        outgoing: a_to_b.clone(),
+        readiness: a_readiness.clone(),
+        peer: b_readiness.clone(),
+    };
+    let b = End {
+        incoming: a_to_b,
+        outgoing: b_to_a,
+        readiness: b_readiness,
+        peer: a_readiness,
+    };
+    (
+        MemoryStream::new(Arc::new(a)),
+        MemoryStream::new(Arc::new(b)),
+    )
+}
+
+impl Connection for MemoryStream {
+    fn try_clone(self: &Self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn shutdown(self: &Self, how: Shutdown) -> io::Result<()> {
+        self.end.shutdown(how);
+        Ok(())
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.end.incoming.lock().unwrap();
+        if incoming.buf.is_empty() {
+            if incoming.write_closed || incoming.read_closed {
+                return Ok(0);
+            }
+            return Err(io::Error::from(ErrorKind::WouldBlock));
+        }
+        let size = cmp::min(buf.len(), incoming.buf.len());
+        for (dst, src) in buf.iter_mut().zip(incoming.buf.drain(..size)) {
+            *dst = src;
+        }
+        update(&self.end.readiness, &incoming);
+        Ok(size)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.end.outgoing.lock().unwrap();
+        if outgoing.write_closed || outgoing.read_closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        outgoing.buf.extend(buf.iter());
+        update(&self.end.peer, &outgoing);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for MemoryStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+/// Accepts the connections opened through its `MemoryConnector`s, without any sockets.
+pub struct MemoryTransport {
+    backlog: Arc<Mutex<VecDeque<MemoryStream>>>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+/// Opens connections to a `MemoryTransport`.
+#[derive(Clone)]
+pub struct MemoryConnector {
+    backlog: Arc<Mutex<VecDeque<MemoryStream>>>,
+    readiness: SetReadiness,
+}
+
+/// Creates a transport along with a way to connect to it, e.g. to hand the transport to
+/// `Server::bind_with_transports` and connect clients in the same process.
+pub fn listen() -> (MemoryTransport, MemoryConnector) {
+    let backlog = Arc::new(Mutex::new(VecDeque::new()));
+    let (registration, readiness) = Registration::new2();
+    (
+        MemoryTransport {
+            backlog: backlog.clone(),
+            registration,
+            readiness: readiness.clone(),
+        },
+        MemoryConnector { backlog, readiness },
+    )
+}
+
+impl MemoryConnector {
+    /// Opens a connection, which is accepted the next time the transport is polled.
+    pub fn connect(self: &Self) -> MemoryStream {
+        let (client, server) = pair();
+        self.backlog.lock().unwrap().push_back(server);
+        let _ = self.readiness.set_readiness(Ready::readable());
+        client
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn accept(self: &Self) -> io::Result<Option<Accepted>> {
+        let mut backlog = self.backlog.lock().unwrap();
+        match backlog.pop_front() {
+            Some(s) => Ok(Some((Box::new(s), None))),
+            None => {
+                let _ = self.readiness.set_readiness(Ready::empty());
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Evented for MemoryTransport {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory;
+    use crate::net::{Connection, Transport};
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::Shutdown;
+    use std::time::Duration;
+
+    #[test]
+    fn verify_pair() {
+        let (mut a, mut b) = memory::pair();
+        let mut buf = [0; 16];
+        assert_eq!(b.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        let poll = Poll::new().unwrap();
+        poll.register(&b, Token(1), Ready::readable(), PollOpt::edge())
+            .unwrap();
+        a.write_all(b"hello").unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert!(events
+            .iter()
+            .any(|e| e.token() == Token(1) && e.readiness().is_readable()));
+        assert_eq!(b.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // Clones share the end, so shutting one of them down closes the connection.
+        a.try_clone().unwrap().shutdown(Shutdown::Write).unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        drop(a);
+        assert!(b.write_all(b"late").is_err());
+    }
+
+    #[test]
+    fn verify_transport() {
+        let (transport, connector) = memory::listen();
+        assert!(transport.accept().unwrap().is_none());
+
+        let mut client = connector.connect();
+        let (mut server, addr) = transport.accept().unwrap().unwrap();
+        assert_eq!(addr, None);
+        assert!(transport.accept().unwrap().is_none());
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
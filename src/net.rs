@@ -0,0 +1,301 @@
+extern crate mio;
+extern crate mio_uds;
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use self::mio_uds::{UnixListener, UnixStream};
+use super::tls::PeerIdentity;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const UNIX_PREFIX: &str = "unix:";
+const WEBSOCKET_PREFIX: &str = "ws://";
+
+/// An address to listen on or connect to. TCP addresses are written as `host:port` and
+/// Unix domain sockets as `unix:/path/to/socket`. Servers can also accept WebSocket
+/// connections on `ws://host:port`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    WebSocket(SocketAddr),
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Address> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        let (addr, websocket) = match s.strip_prefix(WEBSOCKET_PREFIX) {
+            Some(addr) => (addr, true),
+            None => (s, false),
+        };
+        let addr = addr.parse().map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid address {}: {}", s, e),
+            )
+        })?;
+        if websocket {
+            Ok(Address::WebSocket(addr))
+        } else {
+            Ok(Address::Tcp(addr))
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref a) => write!(f, "{}", a),
+            Address::Unix(ref p) => write!(f, "{}{}", UNIX_PREFIX, p.display()),
+            Address::WebSocket(ref a) => write!(f, "{}{}", WEBSOCKET_PREFIX, a),
+        }
+    }
+}
+
+/// A connected, non-blocking byte stream that can be polled. Clones share the underlying
+/// connection, so one thread can read while another one writes.
+pub trait Connection: Read + Write + Evented + Send {
+    fn try_clone(self: &Self) -> io::Result<Box<dyn Connection>>;
+
+    fn shutdown(self: &Self, how: Shutdown) -> io::Result<()>;
+
+    /// The identity the peer proved while connecting, e.g. with a TLS client certificate.
+    fn peer_identity(self: &Self) -> Option<PeerIdentity> {
+        None
+    }
+}
+
+/// A connection accepted by a `Transport`, along with the peer's address if it has one.
+pub type Accepted = (Box<dyn Connection>, Option<SocketAddr>);
+
+/// A source of connections that can be polled, like a listening socket.
+pub trait Transport: Evented + Send {
+    /// Accepts a pending connection, returning `None` once there are none left. The peer
+    /// address is only known for connections over the network.
+    fn accept(self: &Self) -> io::Result<Option<Accepted>>;
+
+    /// Whether the connections speak WebSocket rather than rplay frames.
+    fn is_websocket(self: &Self) -> bool {
+        false
+    }
+
+    /// The address connections are accepted on, for transports listening on the network.
+    fn local_addr(self: &Self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// A connected, non-blocking socket.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(addr: &Address) -> io::Result<Stream> {
+        match *addr {
+            Address::Tcp(ref a) => TcpStream::connect(a).map(Stream::Tcp),
+            Address::Unix(ref p) => UnixStream::connect(p).map(Stream::Unix),
+            Address::WebSocket(_) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "WebSocket addresses can only be listened on",
+            )),
+        }
+    }
+}
+
+impl Connection for Stream {
+    fn try_clone(self: &Self) -> io::Result<Box<dyn Connection>> {
+        let stream = match *self {
+            Stream::Tcp(ref s) => Stream::Tcp(s.try_clone()?),
+            Stream::Unix(ref s) => Stream::Unix(s.try_clone()?),
+        };
+        Ok(Box::new(stream))
+    }
+
+    fn shutdown(self: &Self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.shutdown(how),
+            Stream::Unix(ref s) => s.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl Evented for Stream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.register(poll, token, interest, opts),
+            Stream::Unix(ref s) => s.register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.reregister(poll, token, interest, opts),
+            Stream::Unix(ref s) => s.reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.deregister(poll),
+            Stream::Unix(ref s) => s.deregister(poll),
+        }
+    }
+}
+
+/// A listening socket.
+pub enum Acceptor {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    /// A TCP socket whose connections speak WebSocket.
+    WebSocket(TcpListener),
+}
+
+impl Acceptor {
+    pub fn bind(addr: &Address) -> io::Result<Acceptor> {
+        match *addr {
+            Address::Tcp(ref a) => TcpListener::bind(a).map(Acceptor::Tcp),
+            Address::Unix(ref p) => {
+                remove_stale_socket(p)?;
+                UnixListener::bind(p).map(Acceptor::Unix)
+            }
+            Address::WebSocket(ref a) => TcpListener::bind(a).map(Acceptor::WebSocket),
+        }
+    }
+}
+
+// Removes the socket file a previous server left behind at `path`, so it can be bound
+// again. Fails if another server is still listening there or the path isn't a socket.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("another server is listening on {}", path.display()),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+impl Transport for Acceptor {
+    fn accept(self: &Self) -> io::Result<Option<Accepted>> {
+        let accepted = match *self {
+            Acceptor::Tcp(ref l) | Acceptor::WebSocket(ref l) => match l.accept() {
+                Ok((s, a)) => Some((Stream::Tcp(s), Some(a))),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => None,
+                Err(e) => return Err(e),
+            },
+            Acceptor::Unix(ref l) => l.accept()?.map(|(s, _)| (Stream::Unix(s), None)),
+        };
+        Ok(accepted.map(|(s, a)| (Box::new(s) as Box<dyn Connection>, a)))
+    }
+
+    fn is_websocket(self: &Self) -> bool {
+        matches!(*self, Acceptor::WebSocket(_))
+    }
+
+    fn local_addr(self: &Self) -> Option<SocketAddr> {
+        match *self {
+            Acceptor::Tcp(ref l) | Acceptor::WebSocket(ref l) => l.local_addr().ok(),
+            Acceptor::Unix(_) => None,
+        }
+    }
+}
+
+impl Evented for Acceptor {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Acceptor::Tcp(ref l) | Acceptor::WebSocket(ref l) => {
+                l.register(poll, token, interest, opts)
+            }
+            Acceptor::Unix(ref l) => l.register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Acceptor::Tcp(ref l) | Acceptor::WebSocket(ref l) => {
+                l.reregister(poll, token, interest, opts)
+            }
+            Acceptor::Unix(ref l) => l.reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Acceptor::Tcp(ref l) | Acceptor::WebSocket(ref l) => l.deregister(poll),
+            Acceptor::Unix(ref l) => l.deregister(poll),
+        }
+    }
+}
@@ -1,3 +1,11 @@
+#[allow(
+    unknown_lints,
+    renamed_and_removed_lints,
+    bare_trait_objects,
+    static_mut_refs,
+    mismatched_lifetime_syntaxes,
+    clippy::all
+)]
 mod wire;
 
 pub use self::wire::Message;
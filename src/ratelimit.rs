@@ -0,0 +1,458 @@
+extern crate redis;
+
+use super::acl::ANONYMOUS;
+use super::auth;
+use super::filter::matches_pattern;
+use super::interceptor::Interceptor;
+use super::protos;
+use super::server::PEER_ADDR_ANNOTATION;
+use super::status::{Code, Status};
+use super::tls::PeerIdentity;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Set on responses rejected by a rate limit to the number of milliseconds the client
+/// should wait before retrying.
+pub const RETRY_AFTER_ANNOTATION: &str = "retry-after-ms";
+
+// Local buckets, and the Redis buckets known to be empty, are pruned once there are this
+// many of them.
+const MAX_LOCAL_BUCKETS: usize = 4096;
+
+/// The size and refill rate of a token bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    /// Tokens added per second.
+    pub rate: f64,
+    /// The most tokens the bucket holds, i.e. the largest burst of calls allowed.
+    pub burst: u32,
+}
+
+impl Quota {
+    /// Allows `n` calls per second, in bursts of up to `n` calls.
+    pub fn per_second(n: u32) -> Self {
+        Quota {
+            rate: f64::from(n),
+            burst: n,
+        }
+    }
+
+    /// Allows `n` calls per minute, in bursts of up to `n` calls.
+    #[cfg(test)]
+    pub fn per_minute(n: u32) -> Self {
+        Quota {
+            rate: f64::from(n) / 60.0,
+            burst: n,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_burst(mut self: Self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// Storage for token buckets.
+pub trait Backend: Send + Sync {
+    /// Takes a token from the bucket named `key`, or returns how long it will take until
+    /// a token is available.
+    fn acquire(self: &Self, key: &str, quota: &Quota) -> Result<(), Duration>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(self: &mut Self, quota: &Quota, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * quota.rate).min(f64::from(quota.burst));
+        self.updated = now;
+    }
+}
+
+/// Buckets kept in memory, so limits only apply to this process.
+#[derive(Default)]
+pub struct LocalBuckets {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl LocalBuckets {
+    pub fn new() -> Self {
+        LocalBuckets::default()
+    }
+}
+
+impl Backend for LocalBuckets {
+    fn acquire(self: &Self, key: &str, quota: &Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_LOCAL_BUCKETS && !buckets.contains_key(key) {
+            // Buckets that have been idle for a while are full again, forgetting them
+            // doesn't change any limits.
+            let max_idle = Duration::from_secs(600);
+            buckets.retain(|_, b| now.duration_since(b.updated) < max_idle);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: f64::from(quota.burst),
+            updated: now,
+        });
+        bucket.refill(quota, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / quota.rate;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+// Refills and takes from a bucket stored as a hash, using the clock of the Redis server
+// so that every instance agrees on the time. Returns the milliseconds to wait, or 0 if a
+// token was taken.
+const REDIS_BUCKET_SCRIPT: &str = r"
+redis.replicate_commands()
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + (now - updated) * rate / 1000)
+local wait = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+else
+  wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+return wait
+";
+
+// How many calls may wait to be counted in Redis before further ones go uncounted.
+const REDIS_QUEUE: usize = 1024;
+
+// How long to wait before reconnecting to Redis after losing the connection, doubled
+// after every failed attempt up to the maximum.
+const REDIS_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const REDIS_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Buckets stored in Redis, so limits hold across every rplay instance using the same
+/// Redis server.
+///
+/// Interceptors run on the server's reactor threads, so calls are never held up waiting
+/// on Redis. Each call is decided from what Redis last said about its bucket and then
+/// counted in Redis by a background thread. Once Redis reports a bucket empty, calls are
+/// rejected locally until it refills. As a result a client can exceed its limit by the
+/// calls it makes within one Redis round trip of emptying its bucket.
+///
+/// If Redis can't be reached calls are let through rather than failing every request,
+/// while the background thread keeps trying to reconnect.
+pub struct RedisBuckets {
+    // When each bucket that Redis reported empty has a token again.
+    empty_until: Arc<Mutex<HashMap<String, Instant>>>,
+    counter: SyncSender<(String, Quota)>,
+    // Set while calls go uncounted because the queue is full.
+    behind: AtomicBool,
+}
+
+impl RedisBuckets {
+    pub fn connect(addr: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(addr)?;
+        let connection = client.get_connection()?;
+        let empty_until = Arc::new(Mutex::new(HashMap::new()));
+        let (counter, calls) = mpsc::sync_channel(REDIS_QUEUE);
+        let buckets = RedisBuckets {
+            empty_until: empty_until.clone(),
+            counter,
+            behind: AtomicBool::new(false),
+        };
+        thread::spawn(move || count_calls(&client, connection, &calls, &empty_until));
+        Ok(buckets)
+    }
+}
+
+// Takes a token from the bucket of every call in Redis, noting the buckets it found
+// empty. Calls made while Redis is unavailable aren't counted, and the connection is
+// re-established with backoff. Stops once the `RedisBuckets` is dropped.
+fn count_calls(
+    client: &redis::Client,
+    connection: redis::Connection,
+    calls: &Receiver<(String, Quota)>,
+    empty_until: &Mutex<HashMap<String, Instant>>,
+) {
+    let script = redis::Script::new(REDIS_BUCKET_SCRIPT);
+    let mut connection = Some(connection);
+    let mut backoff = REDIS_MIN_BACKOFF;
+    let mut retry_at = Instant::now();
+    let mut available = true;
+    for (key, quota) in calls.iter() {
+        if connection.is_none() && Instant::now() >= retry_at {
+            match client.get_connection() {
+                Ok(c) => {
+                    connection = Some(c);
+                    backoff = REDIS_MIN_BACKOFF;
+                }
+                Err(_) => {
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(REDIS_MAX_BACKOFF);
+                }
+            }
+        }
+        let c = match connection {
+            Some(ref c) => c,
+            None => continue,
+        };
+
+        let wait: redis::RedisResult<u64> = script
+            .key(format!("rplay:ratelimit:{}", key))
+            .arg(quota.rate)
+            .arg(quota.burst)
+            .invoke(c);
+        match wait {
+            Ok(ms) => {
+                if !available {
+                    println!("rate limiter available again");
+                    available = true;
+                }
+                if ms == 0 {
+                    continue;
+                }
+                let now = Instant::now();
+                let mut empty_until = empty_until.lock().unwrap();
+                if empty_until.len() >= MAX_LOCAL_BUCKETS {
+                    empty_until.retain(|_, until| *until > now);
+                }
+                empty_until.insert(key, now + Duration::from_millis(ms));
+            }
+            Err(e) => {
+                if available {
+                    println!("rate limiter unavailable, allowing calls: {}", e);
+                    available = false;
+                }
+                if e.is_io_error() || !c.is_open() {
+                    connection = None;
+                    retry_at = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+impl Backend for RedisBuckets {
+    fn acquire(self: &Self, key: &str, quota: &Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        if let Some(until) = self.empty_until.lock().unwrap().get(key) {
+            if *until > now {
+                return Err(until.duration_since(now));
+            }
+        }
+        if self.counter.try_send((key.to_string(), *quota)).is_err() {
+            if !self.behind.swap(true, Ordering::Relaxed) {
+                println!("rate limiter falling behind, allowing calls without counting them");
+            }
+        } else if self.behind.load(Ordering::Relaxed) {
+            self.behind.store(false, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+enum Scope {
+    // One bucket per method.
+    Method,
+    // One bucket per client, shared by every method matching the rule.
+    Client,
+}
+
+struct Rule {
+    scope: Scope,
+    pattern: String,
+    quota: Quota,
+}
+
+/// Identifies the client that sent a message for per client limits: the identity it
+/// authenticated as, its client certificate's subject, or else its IP address.
+pub fn client_id(msg: &protos::Message) -> String {
+    let annotations = msg.get_annotations();
+    if let Some(identity) = annotations.get(auth::IDENTITY_ANNOTATION) {
+        return format!("identity:{}", identity);
+    }
+    if let Some(peer) = PeerIdentity::of(msg) {
+        return format!("cert:{}", peer.subject);
+    }
+    let addr = annotations
+        .get(PEER_ADDR_ANNOTATION)
+        .and_then(|a| a.parse::<SocketAddr>().ok());
+    match addr {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => ANONYMOUS.to_string(),
+    }
+}
+
+/// Token bucket rate limits on calls, per method and per client.
+///
+/// Implements `Interceptor`, rejecting calls over a limit with RESOURCE_EXHAUSTED and a
+/// `retry-after-ms` annotation. Add it to the server's interceptors so calls are
+/// rejected before they are dispatched. Every rule matching a call takes a token, even
+/// when a later rule rejects it.
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    backend: Box<dyn Backend>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new()
+    }
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter keeping its buckets in memory.
+    pub fn new() -> Self {
+        RateLimiter::with_backend(LocalBuckets::new())
+    }
+
+    pub fn with_backend<B: Backend + 'static>(backend: B) -> Self {
+        RateLimiter {
+            rules: Vec::new(),
+            backend: Box::new(backend),
+        }
+    }
+
+    /// Limits the calls to each method matching `pattern`, across all clients.
+    pub fn per_method(mut self: Self, pattern: &str, quota: Quota) -> Self {
+        self.rules.push(Rule {
+            scope: Scope::Method,
+            pattern: pattern.to_string(),
+            quota,
+        });
+        self
+    }
+
+    /// Limits the calls each client makes to the methods matching `pattern`, taken
+    /// together.
+    pub fn per_client(mut self: Self, pattern: &str, quota: Quota) -> Self {
+        self.rules.push(Rule {
+            scope: Scope::Client,
+            pattern: pattern.to_string(),
+            quota,
+        });
+        self
+    }
+}
+
+impl Interceptor for RateLimiter {
+    fn before(self: &Self, request: &protos::Message) -> Result<(), Status> {
+        let method = request.get_method();
+        for rule in self.rules.iter() {
+            if !matches_pattern(&rule.pattern, method) {
+                continue;
+            }
+            let key = match rule.scope {
+                Scope::Method => format!("method:{}", method),
+                Scope::Client => format!("client:{}:{}", rule.pattern, client_id(request)),
+            };
+            if let Err(wait) = self.backend.acquire(&key, &rule.quota) {
+                let wait_ms = wait.as_secs() * 1000 + u64::from(wait.subsec_millis());
+                return Err(Status::new(
+                    Code::ResourceExhausted,
+                    &format!("rate limit exceeded for {}", method),
+                )
+                .with_annotation(RETRY_AFTER_ANNOTATION, &wait_ms.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth;
+    use crate::interceptor::Interceptor;
+    use crate::protos;
+    use crate::ratelimit::{Backend, Quota, RateLimiter, RedisBuckets, RETRY_AFTER_ANNOTATION};
+    use crate::status::Code;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn request(method: &str, identity: &str) -> protos::Message {
+        let mut m = protos::Message::new();
+        m.set_method(method.to_string());
+        m.mut_annotations()
+            .insert(auth::IDENTITY_ANNOTATION.to_string(), identity.to_string());
+        m
+    }
+
+    #[test]
+    fn verify_per_method() {
+        let limiter = RateLimiter::new().per_method("Get*", Quota::per_minute(1).with_burst(2));
+
+        assert!(limiter.before(&request("GetA", "alice")).is_ok());
+        assert!(limiter.before(&request("GetA", "bob")).is_ok());
+        let status = limiter.before(&request("GetA", "alice")).unwrap_err();
+        assert_eq!(status.code, Code::ResourceExhausted);
+        let retry: u64 = status.annotations[RETRY_AFTER_ANNOTATION].parse().unwrap();
+        assert!(retry > 0 && retry <= 60_000);
+
+        // Every method has its own bucket and other methods aren't limited.
+        assert!(limiter.before(&request("GetB", "alice")).is_ok());
+        assert!(limiter.before(&request("Put", "alice")).is_ok());
+    }
+
+    #[test]
+    fn verify_per_client() {
+        let limiter = RateLimiter::new().per_client("*", Quota::per_minute(1));
+
+        assert!(limiter.before(&request("GetA", "alice")).is_ok());
+        // The bucket is shared by all methods.
+        assert!(limiter.before(&request("GetB", "alice")).is_err());
+        assert!(limiter.before(&request("GetA", "bob")).is_ok());
+    }
+
+    #[test]
+    fn verify_redis_decided_locally() {
+        let (counter, calls) = mpsc::sync_channel(1);
+        let buckets = RedisBuckets {
+            empty_until: Arc::new(Mutex::new(HashMap::new())),
+            counter,
+            behind: AtomicBool::new(false),
+        };
+        let quota = Quota::per_second(1);
+
+        // Calls are let through right away and counted in the background.
+        assert!(buckets.acquire("a", &quota).is_ok());
+        assert_eq!(calls.try_recv().unwrap().0, "a");
+
+        // Once Redis said a bucket is empty, calls to it are rejected until it refills.
+        let until = Instant::now() + Duration::from_secs(60);
+        buckets.empty_until.lock().unwrap().insert("a".to_string(), until);
+        let wait = buckets.acquire("a", &quota).unwrap_err();
+        assert!(wait > Duration::from_secs(59));
+        assert!(calls.try_recv().is_err());
+        assert!(buckets.acquire("b", &quota).is_ok());
+
+        // Calls still go through when the queue is full, and it's noted until one fits.
+        assert!(buckets.acquire("c", &quota).is_ok());
+        assert!(buckets.behind.load(Ordering::Relaxed));
+        assert_eq!(calls.try_recv().unwrap().0, "b");
+        assert!(buckets.acquire("c", &quota).is_ok());
+        assert!(!buckets.behind.load(Ordering::Relaxed));
+    }
+}
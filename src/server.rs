@@ -1,23 +1,49 @@
 extern crate mio;
+extern crate net2;
 extern crate protobuf;
 
+use super::auth;
+use super::compression;
+use super::filter::Filter;
+use super::frame;
+use super::handshake;
+use super::heartbeat;
+use super::interceptor;
+use super::net::{self, Acceptor, Address, Transport};
 use super::protos;
-use mio::net::{TcpListener, TcpStream};
+use super::status::{Code, Status};
+use super::tls;
+use super::websocket;
+use mio::net::TcpListener;
 use mio::*;
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
 use protobuf::Message;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::io::ErrorKind;
-use std::io::Result;
-use std::net::SocketAddr;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr};
 use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::SendError;
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-const SERVER_TOKEN: Token = Token(0);
+/// The address of the peer that sent a message, set by the server on every message
+/// received over a TCP connection.
+pub const PEER_ADDR_ANNOTATION: &str = "peer-addr";
+
+// The token a reactor's poll is woken up with when capacity may have freed up for its
+// paused connections.
+const REACTOR_WAKEUP: Token = Token(usize::MAX - 1);
 
 pub trait MessageSender {
     fn send(
@@ -27,8 +53,38 @@ pub trait MessageSender {
 }
 
 pub struct SendMessage {
-    sender: Sender<WriterEvent>,
+    sender: WriterSender,
     token: Token,
+    // Shared by the senders handed to every listener of the request.
+    in_flight: Option<Arc<InFlight>>,
+}
+
+// Counts a request against its connection's outstanding requests until every listener is
+// done with it, no matter how many times they answer it.
+struct InFlight {
+    outstanding: Arc<AtomicUsize>,
+    // The reactors to wake up once the request is done, as their paused connections may
+    // be waiting on it or on the room it leaves in a listener.
+    wakeups: Arc<Vec<SetReadiness>>,
+}
+
+impl InFlight {
+    fn new(outstanding: &Arc<AtomicUsize>, wakeups: &Arc<Vec<SetReadiness>>) -> Self {
+        outstanding.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            outstanding: outstanding.clone(),
+            wakeups: wakeups.clone(),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+        for w in self.wakeups.iter() {
+            let _ = w.set_readiness(Ready::readable());
+        }
+    }
 }
 
 impl MessageSender for SendMessage {
@@ -51,169 +107,1865 @@ impl Clone for SendMessage {
         SendMessage {
             token: self.token,
             sender: self.sender.clone(),
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+/// Removes the identities a client may have put on a message, so that handlers only see
+/// the ones the server verified itself.
+pub fn strip_identities(m: &mut protos::Message) {
+    tls::PeerIdentity::strip(m);
+    m.mut_annotations().remove(auth::IDENTITY_ANNOTATION);
+}
+
+/// Authenticates a message and runs the interceptors' `before` hooks on it, returning the
+/// response to send instead of dispatching it if it was rejected.
+pub fn admit(
+    authenticator: Option<&Arc<dyn auth::Authenticator>>,
+    interceptors: &interceptor::Chain,
+    m: &mut protos::Message,
+) -> result::Result<(), protos::Message> {
+    if let Some(authenticator) = authenticator {
+        if let Err(status) = auth::authenticate(&**authenticator, m) {
+            return Err(status.to_message());
+        }
+    }
+    match interceptors.run_before(m) {
+        (_, Some(rejected)) => Err(rejected),
+        (_, None) => Ok(()),
+    }
+}
+
+// Binds a listener that shares its port with the listeners of the other reactors, leaving
+// it to the kernel to balance new connections between them.
+fn bind_reuse_port(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    builder.reuse_port(true)?;
+    builder.bind(addr)?;
+    TcpListener::from_std(builder.listen(1024)?)
+}
+
+enum WriterEvent {
+    NewConnection((Token, Box<dyn net::Connection>, Option<SocketAddr>)),
+    // A response to a dispatched request.
+    WriteData((Token, Arc<protos::Message>)),
+    // A message sent by the reactor itself, which doesn't answer a dispatched request.
+    Reply((Token, Arc<protos::Message>)),
+    // An encoded control frame.
+    Control((Token, Vec<u8>)),
+    // How to encode the frames sent from now on, as agreed on in the handshake.
+    Framing((Token, Framing)),
+    CloseConnection(Token),
+}
+
+// Sends events to the writer thread, waking up its poll loop.
+#[derive(Clone)]
+struct WriterSender {
+    sender: Sender<WriterEvent>,
+    // Unset when the events are read off the channel directly, as with inlets.
+    readiness: Option<SetReadiness>,
+}
+
+impl WriterSender {
+    fn send(self: &Self, event: WriterEvent) -> result::Result<(), SendError<WriterEvent>> {
+        self.sender.send(event)?;
+        if let Some(ref readiness) = self.readiness {
+            let _ = readiness.set_readiness(Ready::readable());
         }
+        Ok(())
     }
 }
 
-fn next_token(sessions: &HashMap<Token, (TcpStream, SocketAddr)>) -> Token {
-    let mut t = 1;
+type ListenerSender = Arc<SyncSender<(Arc<protos::Message>, SendMessage)>>;
+
+#[derive(Clone)]
+struct Listener {
+    sender: ListenerSender,
+    filter: Filter,
+}
+
+// The listeners, shared by the reactors and inlets. Every message is matched against a
+// snapshot, which is only replaced when listeners are added or removed.
+#[derive(Clone, Default)]
+struct Listeners {
+    current: Arc<RwLock<Arc<Vec<Listener>>>>,
+}
 
-    loop {
-        if sessions.contains_key(&Token::from(t)) {
-            t = t + 1;
+impl Listeners {
+    // The listeners interested in a message.
+    fn matching(self: &Self, msg: &protos::Message) -> VecDeque<ListenerSender> {
+        let snapshot = self.current.read().unwrap().clone();
+        snapshot
+            .iter()
+            .filter(|l| l.filter.matches(msg))
+            .map(|l| l.sender.clone())
+            .collect()
+    }
+
+    fn add(self: &Self, listener: Listener) {
+        let mut current = self.current.write().unwrap();
+        let mut listeners = (**current).clone();
+        listeners.push(listener);
+        *current = Arc::new(listeners);
+    }
+
+    fn remove(self: &Self, sender: &ListenerSender) {
+        println!("removing closed listener");
+        let mut current = self.current.write().unwrap();
+        let listeners = current
+            .iter()
+            .filter(|l| !Arc::ptr_eq(&l.sender, sender))
+            .cloned()
+            .collect();
+        *current = Arc::new(listeners);
+    }
+}
+
+// How the frames sent on a connection are encoded.
+#[derive(Default)]
+struct Framing {
+    compressor: Option<compression::Compressor>,
+    checksums: bool,
+    // Whether messages are sent as WebSocket messages rather than frames.
+    websocket: bool,
+}
+
+impl Framing {
+    fn finish(self: &Self, frame: Vec<u8>) -> Vec<u8> {
+        if self.checksums {
+            frame::add_checksum(frame)
         } else {
-            break;
+            frame
         }
     }
 
-    return Token::from(t);
+    fn encode(self: &Self, msg: &protos::Message) -> Vec<u8> {
+        if self.websocket {
+            return websocket::binary(&msg.write_to_bytes().unwrap());
+        }
+        self.finish(compression::encode(msg, self.compressor.as_ref()))
+    }
 }
 
-fn read_chunk(buffer: &mut [u8], stream: &mut TcpStream) -> Result<(usize)> {
-    let mut buffers: [&mut IoVec; 1] = [buffer.into()];
+// A message that couldn't be handed to all of its listeners because one of them was full.
+struct Blocked {
+    msg: Arc<protos::Message>,
+    // The listeners that still have to receive it, starting with the full one.
+    targets: VecDeque<ListenerSender>,
+    in_flight: Arc<InFlight>,
+}
 
-    match stream.read_bufs(&mut buffers) {
-        Ok(size) => Ok(size),
-        Err(e) => Err(e),
+/// Limits protecting the server from misbehaving clients.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Maximum number of open connections across the whole server.
+    pub max_connections: Option<usize>,
+    /// Maximum number of open connections from a single IP address.
+    pub max_connections_per_ip: Option<usize>,
+    /// Connections that haven't sent anything for this long are closed.
+    pub idle_timeout: Option<Duration>,
+    /// Connections that take longer than this to send a frame once they've started
+    /// sending it are closed.
+    pub frame_timeout: Option<Duration>,
+    /// Connections sending a frame larger than this are closed.
+    pub max_frame_size: usize,
+    /// Once a connection has this many requests that listeners haven't finished with, the
+    /// server stops reading from it until some of them are.
+    pub max_outstanding_requests: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: None,
+            max_connections_per_ip: None,
+            idle_timeout: None,
+            frame_timeout: None,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            max_outstanding_requests: 128,
+        }
     }
 }
 
-enum WriterEvent {
-    NewConnection((Token, TcpStream, SocketAddr)),
-    WriteData((Token, Arc<protos::Message>)),
+impl Limits {
+    // How often the reactors check for timed out connections, if at all.
+    fn tick(self: &Self) -> Option<Duration> {
+        let shortest = match (self.idle_timeout, self.frame_timeout) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        shortest.map(|t| cmp::max(t / 4, Duration::from_millis(10)))
+    }
 }
 
-pub struct Server {
-    sessions: HashMap<Token, (TcpStream, SocketAddr)>,
-    tcp_listener: TcpListener,
-    listeners: Vec<Sender<(Arc<protos::Message>, SendMessage)>>,
-    poll: Poll,
-    writer_sender: Sender<WriterEvent>,
-    _writer_thread: JoinHandle<()>,
+/// Optional behaviour for a `Server`.
+pub struct Options {
+    /// Number of poll loops accepting and reading from connections, see `Server::bind`.
+    pub num_reactors: usize,
+    /// When set, every accepted connection is wrapped in a TLS session.
+    pub tls: Option<tls::ServerTls>,
+    /// When set, every message has to carry a bearer token accepted by the authenticator.
+    /// Messages that don't are answered with UNAUTHENTICATED without being dispatched.
+    pub authenticator: Option<Arc<dyn auth::Authenticator>>,
+    /// Interceptors run by the reactors after authentication and before a message is
+    /// dispatched, e.g. access control. Only their `before` hooks are used, a rejected
+    /// message is answered directly with the rejection.
+    pub interceptors: Arc<interceptor::Chain>,
+    pub limits: Limits,
+    /// When set, connections are pinged and closed once they stop answering.
+    pub heartbeat: Option<heartbeat::Options>,
+    /// When set, messages are compressed on connections whose client supports one of
+    /// the algorithms.
+    pub compression: Option<compression::Options>,
+    /// Whether to checksum every frame on connections whose client supports it.
+    pub checksums: bool,
+    /// Settings for connections accepted on `ws://` addresses.
+    pub websocket: websocket::Options,
 }
 
-impl Server {
-    pub fn new(addr: &String) -> Self {
-        let a = addr.parse().unwrap();
-        let (writer_sender, writer_receiver) = mpsc::channel();
-
-        let ss = HashMap::new();
-        let s = Server {
-            sessions: ss,
-            tcp_listener: TcpListener::bind(&a).unwrap(),
-            listeners: Vec::new(),
-            poll: Poll::new().unwrap(),
-            writer_sender: writer_sender,
-            _writer_thread: thread::spawn(move || {
-                let mut sessions = HashMap::new();
-                loop {
-                    match writer_receiver.recv().unwrap() {
-                        WriterEvent::NewConnection((token, stream, addr)) => {
-                            println!("got connetion {:?}", token);
-                            sessions.insert(token, (stream, addr));
-                        }
-                        WriterEvent::WriteData((token, msg)) => {
-                            println!("got data {:?}", token);
-                            let (ref mut stream, _) = sessions.get_mut(&token).unwrap();
-                            stream
-                                .write_bufs(&[msg.write_to_bytes().unwrap().as_slice().into()])
-                                .unwrap();
-                        }
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            num_reactors: 1,
+            tls: None,
+            authenticator: None,
+            interceptors: Arc::new(interceptor::Chain::new()),
+            limits: Limits::default(),
+            heartbeat: None,
+            compression: None,
+            checksums: false,
+            websocket: websocket::Options::default(),
+        }
+    }
+}
+
+// The parts of the options every reactor needs.
+#[derive(Clone)]
+struct ReactorConfig {
+    tls: Option<Arc<rustls::ServerConfig>>,
+    authenticator: Option<Arc<dyn auth::Authenticator>>,
+    interceptors: Arc<interceptor::Chain>,
+    limits: Limits,
+    heartbeat: Option<heartbeat::Options>,
+    compression: Option<compression::Options>,
+    checksums: bool,
+    websocket: websocket::Options,
+}
+
+impl ReactorConfig {
+    fn admit(self: &Self, m: &mut protos::Message) -> result::Result<(), protos::Message> {
+        admit(self.authenticator.as_ref(), &self.interceptors, m)
+    }
+
+    // The features to advertise in the handshake.
+    fn features(self: &Self) -> u32 {
+        let compression = self.compression.as_ref().map_or(0, |c| c.features());
+        if self.checksums {
+            compression | handshake::FEATURE_CHECKSUM
+        } else {
+            compression
+        }
+    }
+
+    // How often the reactors check on their connections, if at all.
+    fn tick(self: &Self) -> Option<Duration> {
+        match (self.limits.tick(), self.heartbeat.as_ref().map(|h| h.tick())) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+// Open connection counts, shared between all reactors to enforce the connection limits.
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionCounts {
+    // Counts a new connection, unless that would exceed the limits.
+    fn try_add(self: &mut Self, ip: Option<IpAddr>, limits: &Limits) -> bool {
+        if limits.max_connections.is_some_and(|max| self.total >= max) {
+            return false;
+        }
+        if let Some(ip) = ip {
+            let count = self.per_ip.entry(ip).or_insert(0);
+            if limits.max_connections_per_ip.is_some_and(|max| *count >= max) {
+                return false;
+            }
+            *count += 1;
+        }
+        self.total += 1;
+        true
+    }
+
+    fn remove(self: &mut Self, ip: Option<IpAddr>) {
+        self.total -= 1;
+        if let Some(ip) = ip {
+            let remaining = match self.per_ip.get_mut(&ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                }
+                None => return,
+            };
+            if remaining == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+// The result of reading from a connection.
+enum Progress {
+    Read,
+    WouldBlock,
+    Closed,
+}
+
+// What the client speaks on a connection.
+enum Protocol {
+    // Frames, starting with the handshake.
+    Rplay,
+    // A WebSocket connection whose upgrade request we are still receiving.
+    Upgrading(Vec<u8>),
+    // WebSocket messages, each carrying an envelope.
+    WebSocket(websocket::Decoder),
+}
+
+// A connection owned by a reactor.
+struct Connection {
+    stream: Box<dyn net::Connection>,
+    addr: Option<SocketAddr>,
+    // The identity of the peer, once it has been verified through a client certificate.
+    peer: Option<tls::PeerIdentity>,
+    protocol: Protocol,
+    decoder: frame::Decoder,
+    last_activity: Instant,
+    // When we started receiving the frame that is currently buffered, if any.
+    frame_started: Option<Instant>,
+    // Messages that have been read but not handled yet.
+    pending: VecDeque<protos::Message>,
+    blocked: Option<Blocked>,
+    // Requests handed to listeners that they haven't finished with yet, see `InFlight`.
+    outstanding: Arc<AtomicUsize>,
+    // Whether we stopped reading from the connection until there is capacity again.
+    paused: bool,
+    heartbeat: Option<heartbeat::Tracker>,
+    // Our preface, and what we agreed on with the client once it has sent its own.
+    hello: handshake::Hello,
+    negotiated: Option<handshake::Negotiated>,
+    // The algorithm compressed messages from the client use.
+    algorithm: Option<compression::Algorithm>,
+    compress_threshold: usize,
+}
+
+impl Connection {
+    fn peer(self: &mut Self) -> Option<&tls::PeerIdentity> {
+        if self.peer.is_none() {
+            self.peer = self.stream.peer_identity();
+        }
+        self.peer.as_ref()
+    }
+
+    // Reads a chunk from the connection and queues up the messages it completes. Pings
+    // are answered right away.
+    fn fill(
+        self: &mut Self,
+        token: Token,
+        writer: &WriterSender,
+        config: &ReactorConfig,
+    ) -> io::Result<Progress> {
+        let mut buffer = [0; 4096];
+        let size = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(Progress::Closed),
+                Ok(size) => break size,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Progress::WouldBlock),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let now = Instant::now();
+        self.last_activity = now;
+        if let Some(ref mut h) = self.heartbeat {
+            h.received(now);
+        }
+
+        let completed = match self.protocol {
+            Protocol::Rplay => {
+                self.decoder.extend(&buffer[..size]);
+                self.read_frames(token, writer)?
+            }
+            _ => match self.read_websocket(token, &buffer[..size], writer, config)? {
+                Some(completed) => completed,
+                None => return Ok(Progress::Closed),
+            },
+        };
+
+        self.frame_started = if !self.has_partial() {
+            None
+        } else if completed {
+            Some(now)
+        } else {
+            self.frame_started.or(Some(now))
+        };
+        Ok(Progress::Read)
+    }
+
+    // Queues up the messages in the frames received so far, returning whether there were
+    // any frames.
+    fn read_frames(self: &mut Self, token: Token, writer: &WriterSender) -> io::Result<bool> {
+        let mut completed = false;
+        while let Some(f) = self.decoder.next_frame()? {
+            completed = true;
+            if self.negotiated.is_none() {
+                self.negotiate(token, &f, writer)?;
+                continue;
+            }
+            if f.is_hello() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected protocol preface",
+                ));
+            }
+            if f.is_ping() {
+                let _ = writer.send(WriterEvent::Control((token, frame::pong(&f.payload))));
+                continue;
+            }
+            if f.is_pong() {
+                continue;
+            }
+
+            let max_size = self.decoder.max_frame_size();
+            let m = compression::decode_message(&f, self.algorithm, max_size)?;
+            self.received(m);
+        }
+        Ok(completed)
+    }
+
+    // Answers the upgrade request, then queues up the envelopes in the WebSocket messages
+    // received so far. Returns whether there were any messages, or None once the
+    // connection should be closed.
+    fn read_websocket(
+        self: &mut Self,
+        token: Token,
+        data: &[u8],
+        writer: &WriterSender,
+        config: &ReactorConfig,
+    ) -> io::Result<Option<bool>> {
+        let upgraded = match self.protocol {
+            Protocol::Upgrading(ref mut buf) => {
+                buf.extend_from_slice(data);
+                match websocket::upgrade(buf, &config.websocket) {
+                    websocket::Upgrade::Partial => return Ok(Some(false)),
+                    websocket::Upgrade::Rejected(response) => {
+                        let _ = writer.send(WriterEvent::Control((token, response)));
+                        return Ok(None);
                     }
+                    websocket::Upgrade::Accepted { len, response } => {
+                        let _ = writer.send(WriterEvent::Control((token, response)));
+                        let framing = Framing {
+                            websocket: true,
+                            ..Default::default()
+                        };
+                        let _ = writer.send(WriterEvent::Framing((token, framing)));
+                        let mut decoder = websocket::Decoder::new(config.limits.max_frame_size);
+                        decoder.extend(&buf[len..]);
+                        Some(decoder)
+                    }
+                }
+            }
+            Protocol::WebSocket(ref mut decoder) => {
+                decoder.extend(data);
+                None
+            }
+            Protocol::Rplay => None,
+        };
+        if let Some(decoder) = upgraded {
+            self.protocol = Protocol::WebSocket(decoder);
+        }
+
+        let mut completed = false;
+        loop {
+            let next = match self.protocol {
+                Protocol::WebSocket(ref mut decoder) => decoder.next(),
+                _ => return Ok(Some(completed)),
+            };
+            let message = match next {
+                Ok(Some(message)) => message,
+                Ok(None) => return Ok(Some(completed)),
+                Err(e) => {
+                    let close = websocket::close(websocket::CLOSE_PROTOCOL_ERROR);
+                    let _ = writer.send(WriterEvent::Control((token, close)));
+                    return Err(e);
+                }
+            };
+            completed = true;
+            match message {
+                websocket::Message::Binary(data) => {
+                    let m = frame::decode_message(&data)?;
+                    self.received(m);
+                }
+                websocket::Message::Ping(payload) => {
+                    let pong = websocket::pong(&payload);
+                    let _ = writer.send(WriterEvent::Control((token, pong)));
+                }
+                websocket::Message::Pong(_) => {}
+                websocket::Message::Close => {
+                    let close = websocket::close(websocket::CLOSE_NORMAL);
+                    let _ = writer.send(WriterEvent::Control((token, close)));
+                    return Ok(None);
                 }
+            }
+        }
+    }
+
+    // Annotates a message received from the client and queues it up.
+    fn received(self: &mut Self, mut m: protos::Message) {
+        // Only identities we verified ourselves are passed on.
+        strip_identities(&mut m);
+        m.mut_annotations().remove(PEER_ADDR_ANNOTATION);
+        if let Some(peer) = self.peer() {
+            peer.annotate(&mut m);
+        }
+        if let Some(addr) = self.addr {
+            m.mut_annotations()
+                .insert(PEER_ADDR_ANNOTATION.to_string(), addr.to_string());
+        }
+        self.pending.push_back(m);
+    }
+
+    fn has_partial(self: &Self) -> bool {
+        match self.protocol {
+            Protocol::Rplay => self.decoder.has_partial(),
+            Protocol::Upgrading(ref buf) => !buf.is_empty(),
+            Protocol::WebSocket(ref decoder) => decoder.has_partial(),
+        }
+    }
+
+    // Whether the handshake is done, after which the client expects heartbeats.
+    fn ready(self: &Self) -> bool {
+        match self.protocol {
+            Protocol::Rplay => self.negotiated.is_some(),
+            Protocol::Upgrading(_) => false,
+            Protocol::WebSocket(_) => true,
+        }
+    }
+
+    fn ping(self: &Self) -> Vec<u8> {
+        match self.protocol {
+            Protocol::WebSocket(_) => websocket::ping(&[]),
+            _ => frame::ping(&[]),
+        }
+    }
+
+    // Answers the client's preface with ours and agrees on the connection's settings.
+    // On failure the connection is closed once our preface has been written, so the
+    // client learns why.
+    fn negotiate(
+        self: &mut Self,
+        token: Token,
+        f: &frame::Frame,
+        writer: &WriterSender,
+    ) -> io::Result<()> {
+        let remote = handshake::Hello::decode(f)?;
+        let _ = writer.send(WriterEvent::Control((token, self.hello.encode())));
+        let negotiated = handshake::negotiate(&self.hello, &remote)?;
+        self.decoder.set_max_frame_size(negotiated.max_frame_size);
+        self.negotiated = Some(negotiated);
+
+        let checksums = negotiated.supports(handshake::FEATURE_CHECKSUM);
+        self.decoder.set_require_checksums(checksums);
+        self.algorithm = compression::choose(negotiated.features);
+        let framing = Framing {
+            compressor: self.algorithm.map(|algorithm| compression::Compressor {
+                algorithm,
+                threshold: self.compress_threshold,
             }),
+            checksums,
+            // Only connections speaking rplay frames go through the handshake.
+            websocket: false,
         };
-        s.poll
-            .register(
-                &s.tcp_listener,
-                SERVER_TOKEN,
-                Ready::readable(),
-                PollOpt::edge(),
-            )
-            .unwrap();
-        s
+        let _ = writer.send(WriterEvent::Framing((token, framing)));
+        Ok(())
     }
 
-    fn insert_with_next_token(self: &mut Self, (session, addr): (TcpStream, SocketAddr)) -> Token {
-        let token = next_token(&self.sessions);
+    fn timed_out(self: &Self, now: Instant, limits: &Limits) -> bool {
+        // A paused connection is waiting on us, not the other way around.
+        if self.paused {
+            return false;
+        }
+        let idle = match limits.idle_timeout {
+            Some(t) => now.duration_since(self.last_activity) > t,
+            None => false,
+        };
+        let slow = match (limits.frame_timeout, self.frame_started) {
+            (Some(t), Some(started)) => now.duration_since(started) > t,
+            _ => false,
+        };
+        idle || slow
+    }
+}
 
-        // Register events to poll for, and notify the sender thread about this connection.
-        self.poll
-            .register(&session, token, Ready::readable(), PollOpt::edge())
-            .unwrap();
-        self.writer_sender
-            .send(WriterEvent::NewConnection((
-                token,
-                session.try_clone().unwrap(),
-                addr,
-            )))
-            .unwrap();
+// The writer thread's side of a connection.
+struct Outgoing {
+    stream: Box<dyn net::Connection>,
+    _addr: Option<SocketAddr>,
+    framing: Framing,
+    // Data the connection didn't take yet, written once it becomes writable again.
+    pending: Vec<u8>,
+    // The reactor closed the connection, it is shut down once pending is written.
+    closing: bool,
+}
 
-        self.sessions.insert(token, (session, addr));
-        token
+impl Outgoing {
+    // Queues data behind whatever is still waiting to be written and writes as much of it
+    // as the connection takes without blocking.
+    fn write(self: &mut Self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        self.flush()
     }
 
-    pub fn start(self: &mut Self) {
+    fn flush(self: &mut Self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Connections like TLS ones keep a buffer of their own.
+        match self.stream.flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            r => r,
+        }
+    }
+}
+
+// The token the writer's poll is woken up with when there are events for it.
+const WRITER_WAKEUP: Token = Token(usize::MAX - 1);
+
+// Owns the writing side of every connection. Writes that don't fit into a connection's
+// socket are buffered and finished once its socket becomes writable, so a slow client
+// never blocks the others.
+struct Writer {
+    poll: Poll,
+    receiver: mpsc::Receiver<WriterEvent>,
+    readiness: SetReadiness,
+    _registration: Registration,
+    sessions: HashMap<Token, Outgoing>,
+}
+
+impl Writer {
+    fn new() -> io::Result<(Self, WriterSender)> {
+        let poll = Poll::new()?;
+        let (registration, readiness) = Registration::new2();
+        poll.register(
+            &registration,
+            WRITER_WAKEUP,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        let (sender, receiver) = mpsc::channel();
+        let writer_sender = WriterSender {
+            sender,
+            readiness: Some(readiness.clone()),
+        };
+        let writer = Writer {
+            poll,
+            receiver,
+            readiness,
+            _registration: registration,
+            sessions: HashMap::new(),
+        };
+        Ok((writer, writer_sender))
+    }
+
+    fn run(self: &mut Self) {
         let mut events = Events::with_capacity(1024);
         loop {
             self.poll.poll(&mut events, None).unwrap();
-
             for event in events.iter() {
-                if event.token() == SERVER_TOKEN {
-                    let a = self.tcp_listener.accept();
-                    match a {
-                        Ok((s, a)) => {
-                            self.insert_with_next_token((s, a));
-                        }
-                        Err(e) => println!("failed to accept: {}", e),
+                if event.token() != WRITER_WAKEUP {
+                    self.flush(event.token());
+                }
+            }
+
+            // Cleared before draining, so events sent from now on wake us up again.
+            let _ = self.readiness.set_readiness(Ready::empty());
+            loop {
+                match self.receiver.try_recv() {
+                    Ok(event) => self.handle(event),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                }
+            }
+        }
+    }
+
+    fn handle(self: &mut Self, event: WriterEvent) {
+        let (token, msg) = match event {
+            WriterEvent::NewConnection((token, stream, addr)) => {
+                println!("got connetion {:?}", token);
+                let registered =
+                    self.poll
+                        .register(&*stream, token, Ready::writable(), PollOpt::edge());
+                if let Err(e) = registered {
+                    println!("failed to register connection {:?}: {}", token, e);
+                }
+                self.sessions.insert(
+                    token,
+                    Outgoing {
+                        stream,
+                        _addr: addr,
+                        framing: Framing::default(),
+                        pending: Vec::new(),
+                        closing: false,
+                    },
+                );
+                return;
+            }
+            WriterEvent::CloseConnection(token) => {
+                if let Some(conn) = self.sessions.get_mut(&token) {
+                    conn.closing = true;
+                }
+                self.flush(token);
+                return;
+            }
+            WriterEvent::Control((token, data)) => {
+                if let Some(conn) = self.sessions.get_mut(&token) {
+                    let data = conn.framing.finish(data);
+                    if let Err(e) = conn.write(&data) {
+                        println!("error writing: {}", e);
                     }
+                }
+                return;
+            }
+            WriterEvent::Framing((token, framing)) => {
+                if let Some(conn) = self.sessions.get_mut(&token) {
+                    conn.framing = framing;
+                }
+                return;
+            }
+            WriterEvent::WriteData((token, msg)) | WriterEvent::Reply((token, msg)) => (token, msg),
+        };
+
+        println!("got data {:?}", token);
+        let conn = match self.sessions.get_mut(&token) {
+            Some(conn) => conn,
+            None => {
+                println!("dropping data for closed connection {:?}", token);
+                return;
+            }
+        };
+        let data = conn.framing.encode(&msg);
+        match conn.write(&data) {
+            Ok(_) => {}
+            Err(e) => println!("error writing: {}", e),
+        }
+    }
+
+    // Writes whatever the connection has pending, shutting it down once everything is
+    // written if it is closing.
+    fn flush(self: &mut Self, token: Token) {
+        let done = match self.sessions.get_mut(&token) {
+            Some(conn) => match conn.flush() {
+                Ok(()) => conn.closing && conn.pending.is_empty(),
+                Err(e) => {
+                    println!("error writing: {}", e);
+                    conn.closing
+                }
+            },
+            None => false,
+        };
+        if done {
+            if let Some(conn) = self.sessions.remove(&token) {
+                let _ = self.poll.deregister(&*conn.stream);
+                let _ = conn.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+// A single poll loop accepting and reading from its own share of the connections.
+struct Reactor {
+    poll: Poll,
+    // The transport at index i is registered with Token(i).
+    acceptors: Vec<Box<dyn net::Transport>>,
+    sessions: HashMap<Token, Connection>,
+    listeners: Listeners,
+    writer_sender: WriterSender,
+    config: ReactorConfig,
+    counts: Arc<Mutex<ConnectionCounts>>,
+    // Shared between all reactors so tokens are unique across the whole server, which
+    // is what the writer thread uses to look up connections.
+    next_token: Arc<AtomicUsize>,
+    wakeup: Wakeup,
+}
+
+// How a reactor is woken up when requests finish, registered with REACTOR_WAKEUP.
+struct Wakeup {
+    registration: Registration,
+    readiness: SetReadiness,
+    // Every reactor's readiness, as a request finishing may free up room in a listener
+    // that connections on any of them are waiting for.
+    all: Arc<Vec<SetReadiness>>,
+}
+
+impl Wakeup {
+    // Creates the wakeups for `n` reactors.
+    fn for_reactors(n: usize) -> Vec<Wakeup> {
+        let pairs: Vec<(Registration, SetReadiness)> =
+            (0..n).map(|_| Registration::new2()).collect();
+        let all = Arc::new(pairs.iter().map(|p| p.1.clone()).collect());
+        pairs
+            .into_iter()
+            .map(|(registration, readiness)| Wakeup {
+                registration,
+                readiness,
+                all: Arc::clone(&all),
+            })
+            .collect()
+    }
+}
+
+impl Reactor {
+    fn new(
+        acceptors: Vec<Box<dyn net::Transport>>,
+        listeners: Listeners,
+        writer_sender: WriterSender,
+        config: ReactorConfig,
+        counts: Arc<Mutex<ConnectionCounts>>,
+        next_token: Arc<AtomicUsize>,
+        wakeup: Wakeup,
+    ) -> io::Result<Self> {
+        let r = Reactor {
+            poll: Poll::new()?,
+            acceptors,
+            sessions: HashMap::new(),
+            listeners,
+            writer_sender,
+            config,
+            counts,
+            next_token,
+            wakeup,
+        };
+        for (i, a) in r.acceptors.iter().enumerate() {
+            r.poll
+                .register(&**a, Token(i), Ready::readable(), PollOpt::edge())?;
+        }
+        r.poll.register(
+            &r.wakeup.registration,
+            REACTOR_WAKEUP,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        Ok(r)
+    }
+
+    // Fails when the connection can't be set up, e.g. because we ran out of file
+    // descriptors. The connection is shut down in that case.
+    fn insert_with_next_token(
+        self: &mut Self,
+        (session, addr): net::Accepted,
+        websocket: bool,
+    ) -> io::Result<Token> {
+        let token = Token::from(self.next_token.fetch_add(1, Ordering::Relaxed));
+
+        // Register events to poll for, and notify the sender thread about this connection.
+        // The writer's clone is taken first, as a registered socket can't be registered
+        // with the writer's poll as well.
+        let registered = session.try_clone().and_then(|outgoing| {
+            self.poll
+                .register(&*session, token, Ready::readable(), PollOpt::edge())?;
+            Ok(outgoing)
+        });
+        let outgoing = match registered {
+            Ok(outgoing) => outgoing,
+            Err(e) => {
+                let _ = session.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        };
+        let event = WriterEvent::NewConnection((token, outgoing, addr));
+        if self.writer_sender.send(event).is_err() {
+            let _ = self.poll.deregister(&*session);
+            let _ = session.shutdown(Shutdown::Both);
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "the writer thread is gone"));
+        }
+
+        self.sessions.insert(
+            token,
+            Connection {
+                stream: session,
+                addr,
+                peer: None,
+                protocol: if websocket {
+                    Protocol::Upgrading(Vec::new())
                 } else {
-                    let (ref mut stream, _) = self.sessions.get_mut(&event.token()).unwrap();
-                    let mut buffer = [0; 256];
-                    match read_chunk(&mut buffer, stream) {
-                        Ok(0) => println!("event with no output"),
-                        Ok(size) => match buffer.chunks(size).next() {
-                            Some(chunk) => {
-                                println!("received {} bytes", size);
-                                let mut cis = protobuf::stream::CodedInputStream::from_bytes(chunk);
-                                let mut m = protos::Message::new();
-                                match m.merge_from(&mut cis) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!("failed to parse message: {}", e);
-                                        println!("{:?}", chunk);
-                                    }
-                                }
+                    Protocol::Rplay
+                },
+                decoder: frame::Decoder::new(self.config.limits.max_frame_size),
+                last_activity: Instant::now(),
+                frame_started: None,
+                pending: VecDeque::new(),
+                blocked: None,
+                outstanding: Arc::new(AtomicUsize::new(0)),
+                paused: false,
+                heartbeat: self
+                    .config
+                    .heartbeat
+                    .clone()
+                    .map(|h| heartbeat::Tracker::new(h, Instant::now())),
+                hello: handshake::Hello::new(
+                    self.config.features(),
+                    self.config.limits.max_frame_size,
+                ),
+                negotiated: None,
+                algorithm: None,
+                compress_threshold: self
+                    .config
+                    .compression
+                    .as_ref()
+                    .map_or(0, |c| c.threshold),
+            },
+        );
+        Ok(token)
+    }
+
+    // Accepts every pending connection, as we only get a single edge triggered event.
+    fn accept_all(self: &mut Self, acceptor: usize) {
+        loop {
+            match self.acceptors[acceptor].accept() {
+                Ok(Some((stream, addr))) => {
+                    let ip = addr.map(|a| a.ip());
+                    if !self
+                        .counts
+                        .lock()
+                        .unwrap()
+                        .try_add(ip, &self.config.limits)
+                    {
+                        println!("rejecting connection from {:?}: too many connections", addr);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
 
-                                let marc = Arc::new(m);
-                                for l in self.listeners.iter() {
-                                    l.send((
-                                        marc.clone(),
-                                        SendMessage {
-                                            token: event.token(),
-                                            sender: self.writer_sender.clone(),
-                                        },
-                                    )).unwrap();
+                    let stream: Box<dyn net::Connection> = match self.config.tls {
+                        Some(ref config) => {
+                            match tls::TlsConnection::new(tls::TlsStream::server(stream, config)) {
+                                Ok(s) => Box::new(s),
+                                Err(e) => {
+                                    println!("failed to set up tls for {:?}: {}", addr, e);
+                                    self.counts.lock().unwrap().remove(ip);
+                                    continue;
                                 }
                             }
-                            None => println!("failed to read {} bytes", size),
-                        },
-                        Err(e) => {
-                            if e.kind() == ErrorKind::WouldBlock
-                                || e.kind() == ErrorKind::ConnectionReset
-                            {
-                                continue;
-                            } else {
-                                println!("error reading: {}", e);
-                            }
                         }
+                        None => stream,
+                    };
+                    let websocket = self.acceptors[acceptor].is_websocket();
+                    if let Err(e) = self.insert_with_next_token((stream, addr), websocket) {
+                        println!("failed to set up connection from {:?}: {}", addr, e);
+                        self.counts.lock().unwrap().remove(ip);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    println!("failed to accept: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn run(self: &mut Self) {
+        let mut events = Events::with_capacity(1024);
+        let tick = self.config.tick();
+        loop {
+            self.poll.poll(&mut events, tick).unwrap();
+
+            for event in events.iter() {
+                let token = event.token();
+                if token == REACTOR_WAKEUP {
+                    // Cleared before resuming, so requests finishing from now on wake us
+                    // up again.
+                    let _ = self.wakeup.readiness.set_readiness(Ready::empty());
+                } else if token.0 < self.acceptors.len() {
+                    self.accept_all(token.0);
+                } else {
+                    self.process(token);
+                }
+            }
+
+            self.resume_paused();
+            if tick.is_some() {
+                self.check_connections();
+            }
+        }
+    }
+
+    // Hands the connection's messages to the listeners, reading more from the socket for
+    // as long as there is capacity for it. Stops reading from the connection when there
+    // isn't.
+    fn process(self: &mut Self, token: Token) {
+        loop {
+            let (blocked, outstanding) = match self.sessions.get_mut(&token) {
+                Some(conn) => (conn.blocked.take(), conn.outstanding.clone()),
+                // The connection may have been closed while handling an earlier event.
+                None => return,
+            };
+
+            if let Some(b) = blocked {
+                if let Some(b) = self.deliver(token, b) {
+                    self.pause(token, Some(b));
+                    return;
+                }
+            }
+            if outstanding.load(Ordering::SeqCst) >= self.config.limits.max_outstanding_requests {
+                self.pause(token, None);
+                return;
+            }
+
+            let next = self.sessions.get_mut(&token).unwrap().pending.pop_front();
+            if let Some(m) = next {
+                if let Some(b) = self.handle_message(token, m, &outstanding) {
+                    self.pause(token, Some(b));
+                    return;
+                }
+                continue;
+            }
+
+            let read = self
+                .sessions
+                .get_mut(&token)
+                .unwrap()
+                .fill(token, &self.writer_sender, &self.config);
+            match read {
+                Ok(Progress::Read) => {}
+                Ok(Progress::WouldBlock) => {
+                    self.resume(token);
+                    return;
+                }
+                Ok(Progress::Closed) => {
+                    self.close(token);
+                    return;
+                }
+                Err(e) => {
+                    if e.kind() != ErrorKind::ConnectionReset {
+                        println!("closing connection {:?}: {}", token, e);
                     }
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Stops reading from a connection.
+    fn pause(self: &mut Self, token: Token, blocked: Option<Blocked>) {
+        let conn = self.sessions.get_mut(&token).unwrap();
+        conn.blocked = blocked;
+        if !conn.paused {
+            conn.paused = true;
+            let _ = self
+                .poll
+                .reregister(&*conn.stream, token, Ready::empty(), PollOpt::edge());
+        }
+    }
+
+    // Starts reading from a paused connection again. Only called once everything
+    // available has been read, so the edge triggered registration sees new data.
+    fn resume(self: &mut Self, token: Token) {
+        let conn = self.sessions.get_mut(&token).unwrap();
+        if conn.paused {
+            conn.paused = false;
+            let _ = self
+                .poll
+                .reregister(&*conn.stream, token, Ready::readable(), PollOpt::edge());
+        }
+    }
+
+    // Gives every paused connection a chance to continue, now that capacity might have
+    // freed up.
+    fn resume_paused(self: &mut Self) {
+        let paused: Vec<Token> = self
+            .sessions
+            .iter()
+            .filter(|&(_, c)| c.paused)
+            .map(|(t, _)| *t)
+            .collect();
+        for token in paused {
+            self.process(token);
+        }
+    }
+
+    // Authenticates and dispatches a message, returning it if a listener is full.
+    fn handle_message(
+        self: &Self,
+        token: Token,
+        mut m: protos::Message,
+        outstanding: &Arc<AtomicUsize>,
+    ) -> Option<Blocked> {
+        if let Err(rejected) = self.config.admit(&mut m) {
+            self.reply(token, rejected);
+            return None;
+        }
+
+        let targets = self.listeners.matching(&m);
+        self.deliver(
+            token,
+            Blocked {
+                msg: Arc::new(m),
+                targets,
+                in_flight: Arc::new(InFlight::new(outstanding, &self.wakeup.all)),
+            },
+        )
+    }
+
+    fn close(self: &mut Self, token: Token) {
+        if let Some(conn) = self.sessions.remove(&token) {
+            // The writer shuts the connection down once it has written everything queued
+            // up for it.
+            let _ = self.poll.deregister(&*conn.stream);
+            let _ = self.writer_sender.send(WriterEvent::CloseConnection(token));
+            self.counts
+                .lock()
+                .unwrap()
+                .remove(conn.addr.map(|a| a.ip()));
+        }
+    }
+
+    // Closes connections that timed out or stopped answering heartbeats, and pings the
+    // ones that are due.
+    fn check_connections(self: &mut Self) {
+        let now = Instant::now();
+        let mut dead = Vec::new();
+        for (token, conn) in self.sessions.iter_mut() {
+            if conn.timed_out(now, &self.config.limits) {
+                println!("closing connection {:?}: timed out", token);
+                dead.push(*token);
+                continue;
+            }
+            // While paused we aren't reading any pongs, and until the handshake is done
+            // the client doesn't expect any pings.
+            if conn.paused || !conn.ready() {
+                continue;
+            }
+            let action = match conn.heartbeat {
+                Some(ref mut h) => h.poll(now),
+                None => continue,
+            };
+            match action {
+                heartbeat::Action::Wait => {}
+                heartbeat::Action::SendPing => {
+                    let _ = self
+                        .writer_sender
+                        .send(WriterEvent::Control((*token, conn.ping())));
+                }
+                heartbeat::Action::Dead => {
+                    println!("closing connection {:?}: missed heartbeats", token);
+                    dead.push(*token);
+                }
+            }
+        }
+        for token in dead {
+            self.close(token);
+        }
+    }
+
+    // Answers a message directly from the reactor, without dispatching it.
+    fn reply(self: &Self, token: Token, msg: protos::Message) {
+        match self
+            .writer_sender
+            .send(WriterEvent::Reply((token, Arc::new(msg))))
+        {
+            Ok(_) => {}
+            Err(_) => println!("failed to send reply"),
+        }
+    }
+
+    // Hands a message to each of its listeners in turn. If one of them is full the rest
+    // is returned, to be retried once the connection resumes.
+    fn deliver(self: &Self, token: Token, mut b: Blocked) -> Option<Blocked> {
+        while let Some(target) = b.targets.pop_front() {
+            let sender = SendMessage {
+                token,
+                sender: self.writer_sender.clone(),
+                in_flight: Some(b.in_flight.clone()),
+            };
+            match target.try_send((b.msg.clone(), sender)) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    b.targets.push_front(target);
+                    return Some(b);
+                }
+                // Listeners whose receiver has gone away are dropped.
+                Err(TrySendError::Disconnected(_)) => self.listeners.remove(&target),
+            }
+        }
+        None
+    }
+}
+
+/// Hands messages that didn't arrive on one of the server's own connections, e.g. through
+/// a gateway, to the server's listeners. They are admitted exactly like messages from
+/// connections are.
+#[derive(Clone)]
+pub struct Inlet {
+    listeners: Listeners,
+    config: ReactorConfig,
+    sender: WriterSender,
+}
+
+impl Inlet {
+    /// Dispatches a message, waiting while listeners are full. Responses come back on
+    /// the `Responses` paired with this inlet, along with `id`. Rejected messages, and
+    /// messages no listener is interested in, are answered right away through the
+    /// returned error.
+    pub fn submit(
+        self: &Self,
+        id: usize,
+        mut m: protos::Message,
+    ) -> result::Result<(), protos::Message> {
+        strip_identities(&mut m);
+        self.config.admit(&mut m)?;
+
+        let targets = self.listeners.matching(&m);
+        let msg = Arc::new(m);
+        if targets.is_empty() {
+            let message = format!("nothing handles {}", msg.get_method());
+            return Err(Status::new(Code::Unimplemented, &message).to_message());
+        }
+        for target in targets {
+            let sender = SendMessage {
+                token: Token(id),
+                sender: self.sender.clone(),
+                in_flight: None,
+            };
+            if target.send((msg.clone(), sender)).is_err() {
+                self.listeners.remove(&target);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The responses to messages submitted through an `Inlet`.
+pub struct Responses {
+    receiver: mpsc::Receiver<WriterEvent>,
+}
+
+impl Responses {
+    /// Waits for the next response, returning None once every `Inlet` and every
+    /// `MessageSender` for its messages are gone.
+    pub fn recv(self: &Self) -> Option<(usize, Arc<protos::Message>)> {
+        loop {
+            match self.receiver.recv() {
+                Ok(WriterEvent::WriteData((token, msg))) => return Some((token.0, msg)),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+pub struct Server {
+    local_addrs: Vec<SocketAddr>,
+    listeners: Listeners,
+    config: ReactorConfig,
+    reactors: Vec<Reactor>,
+    reactor_threads: Vec<JoinHandle<()>>,
+    _writer_thread: JoinHandle<()>,
+}
+
+impl Server {
+    /// Creates a server accepting connections on all of `addrs`, running
+    /// `options.num_reactors` poll loops each on its own thread.
+    ///
+    /// With more than one reactor every reactor binds its own socket for each TCP address
+    /// using SO_REUSEPORT, the kernel spreads incoming connections across them and a
+    /// connection stays on the reactor that accepted it. For addresses with port 0 the
+    /// other reactors share the port the first one was given. Unix domain sockets can't
+    /// share an address, so they are only served by the first reactor. A socket file left
+    /// behind by a server that is gone is removed before binding.
+    pub fn bind(addrs: &[Address], options: Options) -> io::Result<Self> {
+        Server::bind_with_transports(addrs, Vec::new(), options)
+    }
+
+    /// Like `bind`, additionally accepting connections from `transports`. Transports are
+    /// only served by the first reactor, connections from all of them go through the same
+    /// handshake, framing and dispatch as the ones accepted on `addrs`.
+    pub fn bind_with_transports(
+        addrs: &[Address],
+        mut transports: Vec<Box<dyn net::Transport>>,
+        options: Options,
+    ) -> io::Result<Self> {
+        let num_reactors = options.num_reactors;
+        if num_reactors == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a server needs at least one reactor",
+            ));
+        }
+        let config = ReactorConfig {
+            tls: options.tls.map(|t| t.config()),
+            authenticator: options.authenticator,
+            interceptors: options.interceptors,
+            limits: options.limits,
+            heartbeat: options.heartbeat,
+            compression: options.compression,
+            checksums: options.checksums,
+            websocket: options.websocket,
+        };
+        let (mut writer, writer_sender) = Writer::new()?;
+
+        let listeners = Listeners::default();
+        let counts = Arc::new(Mutex::new(ConnectionCounts::default()));
+        let next_token = Arc::new(AtomicUsize::new(addrs.len() + transports.len()));
+        let mut reactors = Vec::new();
+        let mut wakeups = Wakeup::for_reactors(num_reactors).into_iter();
+        // The addresses the first reactor bound, so the others share the port the OS
+        // picked for addresses with port 0.
+        let mut bound: Vec<Option<SocketAddr>> = vec![None; addrs.len()];
+        for i in 0..num_reactors {
+            let mut acceptors: Vec<Box<dyn net::Transport>> = Vec::new();
+            for (j, addr) in addrs.iter().enumerate() {
+                let acceptor = match *addr {
+                    Address::Tcp(ref a) if num_reactors > 1 => {
+                        Acceptor::Tcp(bind_reuse_port(&bound[j].unwrap_or(*a))?)
+                    }
+                    Address::WebSocket(ref a) if num_reactors > 1 => {
+                        Acceptor::WebSocket(bind_reuse_port(&bound[j].unwrap_or(*a))?)
+                    }
+                    Address::Unix(_) if i > 0 => continue,
+                    _ => Acceptor::bind(addr)?,
+                };
+                bound[j] = acceptor.local_addr();
+                acceptors.push(Box::new(acceptor));
+            }
+            if i == 0 {
+                acceptors.append(&mut transports);
+            }
+            reactors.push(Reactor::new(
+                acceptors,
+                listeners.clone(),
+                writer_sender.clone(),
+                config.clone(),
+                counts.clone(),
+                next_token.clone(),
+                wakeups.next().unwrap(),
+            )?);
+        }
+
+        Ok(Server {
+            local_addrs: bound.into_iter().flatten().collect(),
+            listeners,
+            config,
+            reactors,
+            reactor_threads: Vec::new(),
+            _writer_thread: thread::spawn(move || writer.run()),
+        })
+    }
+
+    /// Runs the server. Every reactor but the first is moved onto its own thread, the
+    /// first one runs on the calling thread. Panics if the server was already started.
+    pub fn start(self: &mut Self) {
+        let mut reactors = mem::take(&mut self.reactors).into_iter();
+        let mut first = reactors.next().expect("server already started");
+        for mut r in reactors {
+            self.reactor_threads.push(thread::spawn(move || r.run()));
+        }
+        first.run();
+    }
+
+    /// The TCP and WebSocket addresses the server listens on, with the ports the OS picked
+    /// for addresses with port 0.
+    pub fn local_addrs(self: &Self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Creates a way to dispatch messages from outside the server's connections.
+    pub fn inlet(self: &Self) -> (Inlet, Responses) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            Inlet {
+                listeners: self.listeners.clone(),
+                config: self.config.clone(),
+                sender: WriterSender {
+                    sender,
+                    readiness: None,
+                },
+            },
+            Responses { receiver },
+        )
+    }
+
+    /// Adds a listener receiving every message. Listeners are bounded channels, while a
+    /// listener is full the server stops reading from the connection the message came
+    /// from until there is room again.
+    pub fn add_listener(self: &mut Self, l: SyncSender<(Arc<protos::Message>, SendMessage)>) {
+        self.add_filtered_listener(l, Filter::new());
+    }
+
+    /// Adds a listener that only receives the messages matched by `filter`. Every
+    /// matching listener receives a copy of the message.
+    pub fn add_filtered_listener(
+        self: &mut Self,
+        l: SyncSender<(Arc<protos::Message>, SendMessage)>,
+        filter: Filter,
+    ) {
+        self.listeners.add(Listener {
+            sender: Arc::new(l),
+            filter,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client;
+    use crate::frame;
+    use crate::handshake;
+    use crate::memory;
+    use crate::net::{Address, Connection, Stream};
+    use crate::protos;
+    use crate::server::{Framing, Limits, MessageSender, Options, Server, Writer, WriterEvent};
+    use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+    use protobuf::Message;
+    use std::cmp;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpStream};
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // A connection taking only as many bytes as the test allows it to.
+    #[derive(Clone)]
+    struct Choked {
+        // How many more bytes it takes, everything written so far and whether it was shut
+        // down.
+        state: Arc<Mutex<(usize, Vec<u8>, bool)>>,
+        registration: Arc<Registration>,
+        readiness: SetReadiness,
+    }
+
+    impl Choked {
+        fn new() -> Self {
+            let (registration, readiness) = Registration::new2();
+            Choked {
+                state: Arc::new(Mutex::new((0, Vec::new(), false))),
+                registration: Arc::new(registration),
+                readiness,
+            }
+        }
+
+        fn allow(self: &Self, size: usize) {
+            self.state.lock().unwrap().0 += size;
+            self.readiness.set_readiness(Ready::writable()).unwrap();
+        }
+
+        // Waits for the written data to reach `size` bytes.
+        fn written(self: &Self, size: usize) -> Vec<u8> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let state = self.state.lock().unwrap();
+                if state.1.len() >= size || Instant::now() > deadline {
+                    return state.1.clone();
+                }
+                drop(state);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        fn is_shut_down(self: &Self) -> bool {
+            self.state.lock().unwrap().2
+        }
+    }
+
+    impl Connection for Choked {
+        fn try_clone(self: &Self) -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn shutdown(self: &Self, _how: Shutdown) -> io::Result<()> {
+            self.state.lock().unwrap().2 = true;
+            Ok(())
+        }
+    }
+
+    impl Read for Choked {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(ErrorKind::WouldBlock))
+        }
+    }
+
+    impl Write for Choked {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.state.lock().unwrap();
+            let size = cmp::min(state.0, buf.len());
+            if size == 0 {
+                let _ = self.readiness.set_readiness(Ready::empty());
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            state.0 -= size;
+            state.1.extend_from_slice(&buf[..size]);
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Evented for Choked {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.registration.register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            self.registration.reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            Evented::deregister(&*self.registration, poll)
+        }
+    }
+
+    #[test]
+    fn verify_partial_writes() {
+        let (mut writer, sender) = Writer::new().unwrap();
+        thread::spawn(move || writer.run());
+
+        let conn = Choked::new();
+        let token = Token(7);
+        sender
+            .send(WriterEvent::NewConnection((token, Box::new(conn.clone()), None)))
+            .unwrap();
+
+        let mut m = protos::Message::new();
+        m.set_body(vec![7; 10000]);
+        let msg = Arc::new(m);
+        let frame = Framing::default().encode(&msg);
+        sender.send(WriterEvent::Reply((token, msg.clone()))).unwrap();
+        sender.send(WriterEvent::Reply((token, msg))).unwrap();
+        sender.send(WriterEvent::CloseConnection(token)).unwrap();
+
+        // The connection takes the frames a bit at a time, and is only shut down once
+        // both of them made it out whole.
+        conn.allow(frame.len() / 3);
+        assert_eq!(conn.written(frame.len() / 3).len(), frame.len() / 3);
+        assert!(!conn.is_shut_down());
+        conn.allow(2 * frame.len());
+        let written = conn.written(2 * frame.len());
+        assert_eq!(written, [frame.clone(), frame].concat());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !conn.is_shut_down() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(conn.is_shut_down());
+    }
+
+    // Reads frames off a memory stream until a message arrives, skipping the handshake.
+    fn next_message(
+        stream: &mut memory::MemoryStream,
+        decoder: &mut frame::Decoder,
+    ) -> protos::Message {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(f) = decoder.next_frame().unwrap() {
+                if !f.is_hello() {
+                    return frame::decode_message(&f.payload).unwrap();
                 }
+                continue;
+            }
+            assert!(Instant::now() < deadline, "no response");
+            match stream.read(&mut buf) {
+                Ok(size) => decoder.extend(&buf[..size]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("{}", e),
             }
         }
     }
 
-    pub fn add_listener(self: &mut Self, l: Sender<(Arc<protos::Message>, SendMessage)>) {
-        self.listeners.push(l);
+    #[test]
+    fn verify_outstanding_released() {
+        let (transport, connector) = memory::listen();
+        let options = Options {
+            limits: Limits {
+                max_outstanding_requests: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut server =
+            Server::bind_with_transports(&[], vec![Box::new(transport)], options).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(16);
+        server.add_listener(sender);
+        thread::spawn(move || server.start());
+        // Drops the first request without answering it, and answers the rest twice.
+        thread::spawn(move || {
+            for (i, (msg, sender)) in receiver.iter().enumerate() {
+                if i > 0 {
+                    sender.send(msg.clone()).unwrap();
+                    sender.send(msg).unwrap();
+                }
+            }
+        });
+
+        let mut stream = connector.connect();
+        stream
+            .write_all(&handshake::Hello::new(0, frame::DEFAULT_MAX_FRAME_SIZE).encode())
+            .unwrap();
+        let mut decoder = frame::Decoder::new(frame::DEFAULT_MAX_FRAME_SIZE);
+        // Each request only takes up the single slot until the listener is done with it.
+        for method in ["dropped", "first", "second"].iter() {
+            let mut m = protos::Message::new();
+            m.set_method(method.to_string());
+            stream.write_all(&frame::encode(&m)).unwrap();
+            if *method != "dropped" {
+                for _ in 0..2 {
+                    assert_eq!(next_message(&mut stream, &mut decoder).get_method(), *method);
+                }
+            }
+        }
+    }
+
+    // Answers every message with its own body.
+    fn echo(server: &mut Server) {
+        let (sender, receiver) = mpsc::sync_channel(16);
+        server.add_listener(sender);
+        thread::spawn(move || {
+            for (msg, sender) in receiver.iter() {
+                let mut response = protos::Message::new();
+                response.set_body(msg.get_body().to_vec());
+                let _ = sender.send(Arc::new(response));
+            }
+        });
+    }
+
+    #[test]
+    fn verify_reactors_share_port() {
+        let options = Options {
+            num_reactors: 3,
+            ..Default::default()
+        };
+        let server = Server::bind(&["127.0.0.1:0".parse().unwrap()], options).unwrap();
+        let addr = server.local_addrs()[0];
+        assert_ne!(addr.port(), 0);
+        for reactor in server.reactors.iter() {
+            assert_eq!(reactor.acceptors[0].local_addr(), Some(addr));
+        }
+    }
+
+    #[test]
+    fn verify_multiple_reactors() {
+        let options = Options {
+            num_reactors: 2,
+            ..Default::default()
+        };
+        let mut server = Server::bind(&["127.0.0.1:0".parse().unwrap()], options).unwrap();
+        let addr = Address::Tcp(server.local_addrs()[0]);
+        echo(&mut server);
+        thread::spawn(move || server.start());
+
+        let calls: Vec<_> = (0..8)
+            .map(|i| {
+                let addr = addr.clone();
+                thread::spawn(move || ping(&addr, format!("ping {}", i)))
+            })
+            .collect();
+        for call in calls {
+            call.join().unwrap();
+        }
+    }
+
+    // Calls the echo server at `addr` and checks the response.
+    fn ping(addr: &Address, data: String) {
+        let mut ping = protos::Ping::new();
+        ping.set_data(data);
+        let stream = Box::new(Stream::connect(addr).unwrap());
+        let options = client::Options::default();
+        let response =
+            client::Client::call(stream, &"echo".to_string(), &ping, &options).unwrap();
+        assert_eq!(response.get_body(), &ping.write_to_bytes().unwrap()[..]);
+    }
+
+    #[test]
+    fn verify_unix_socket() {
+        let path = env::temp_dir().join(format!("rplay-server-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        // A socket file left behind by a server that is gone.
+        drop(UnixListener::bind(&path).unwrap());
+
+        let addr = Address::Unix(path.clone());
+        let mut server = Server::bind(std::slice::from_ref(&addr), Options::default()).unwrap();
+        echo(&mut server);
+        thread::spawn(move || server.start());
+        ping(&addr, "ping".to_string());
+
+        let err = Server::bind(&[addr], Options::default()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn verify_bind_errors() {
+        let options = Options {
+            num_reactors: 0,
+            ..Default::default()
+        };
+        let err = Server::bind(&["127.0.0.1:0".parse().unwrap()], options).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let path = env::temp_dir().join(format!("rplay-server-{}.txt", process::id()));
+        fs::write(&path, "not a socket").unwrap();
+        let err = Server::bind(&[Address::Unix(path.clone())], Options::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Starts an echo server on a local TCP port with the given limits.
+    fn limited_server(limits: Limits) -> SocketAddr {
+        let options = Options {
+            limits,
+            ..Default::default()
+        };
+        let mut server = Server::bind(&["127.0.0.1:0".parse().unwrap()], options).unwrap();
+        let addr = server.local_addrs()[0];
+        echo(&mut server);
+        thread::spawn(move || server.start());
+        addr
+    }
+
+    // Connects and sends our preface. Returns the connection if the server answered with
+    // its own, or `None` if it closed the connection instead.
+    fn handshake(addr: &SocketAddr) -> Option<TcpStream> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let hello = handshake::Hello::new(0, frame::DEFAULT_MAX_FRAME_SIZE);
+        if stream.write_all(&hello.encode()).is_err() {
+            return None;
+        }
+        let mut decoder = frame::Decoder::new(frame::DEFAULT_MAX_FRAME_SIZE);
+        let mut buf = [0; 64];
+        loop {
+            if let Some(f) = decoder.next_frame().unwrap() {
+                assert!(f.is_hello());
+                return Some(stream);
+            }
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return None,
+                Ok(size) => decoder.extend(&buf[..size]),
+            }
+        }
+    }
+
+    // Waits for the server to close the connection, returning how long that took.
+    fn wait_closed(stream: &mut TcpStream) -> Duration {
+        let start = Instant::now();
+        let mut buf = [0; 64];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return start.elapsed(),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset => return start.elapsed(),
+                Err(e) => panic!("connection still open: {}", e),
+            }
+        }
+    }
+
+    // Checks that a second connection is turned away while the first one is open, and
+    // let in once it is closed.
+    fn verify_single_connection(limits: Limits) {
+        let addr = limited_server(limits);
+        let first = handshake(&addr).expect("first connection rejected");
+        assert!(handshake(&addr).is_none());
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handshake(&addr).is_none() {
+            assert!(Instant::now() < deadline, "closed connection still counted");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn verify_max_connections() {
+        verify_single_connection(Limits {
+            max_connections: Some(1),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn verify_max_connections_per_ip() {
+        verify_single_connection(Limits {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn verify_idle_timeout() {
+        let timeout = Duration::from_millis(100);
+        let addr = limited_server(Limits {
+            idle_timeout: Some(timeout),
+            ..Default::default()
+        });
+        let mut stream = handshake(&addr).unwrap();
+        assert!(wait_closed(&mut stream) >= timeout / 2);
+    }
+
+    #[test]
+    fn verify_frame_timeout() {
+        let timeout = Duration::from_millis(100);
+        let addr = limited_server(Limits {
+            frame_timeout: Some(timeout),
+            ..Default::default()
+        });
+        let mut stream = handshake(&addr).unwrap();
+        // Start a frame and never finish it.
+        let mut m = protos::Message::new();
+        m.set_method("slow".to_string());
+        stream.write_all(&frame::encode(&m)[..2]).unwrap();
+        assert!(wait_closed(&mut stream) >= timeout / 2);
     }
 }
@@ -0,0 +1,143 @@
+use super::protos;
+use super::status::{Code, Status};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Marks how important a request is: `low`, `normal` or `critical`. Requests without it
+/// are `normal`.
+pub const PRIORITY_ANNOTATION: &str = "priority";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Priority {
+    pub fn of(msg: &protos::Message) -> Self {
+        match msg.get_annotations().get(PRIORITY_ANNOTATION).map(|p| p.as_str()) {
+            Some("low") => Priority::Low,
+            Some("critical") => Priority::Critical,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Settings for load shedding, see `Shedder`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The time in queue we aim to stay below.
+    pub target: Duration,
+    /// How long the time in queue has to stay above the target before we consider
+    /// ourselves overloaded.
+    pub interval: Duration,
+    /// While overloaded, requests with a lower priority than this are shed.
+    pub shed_below: Priority,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            target: Duration::from_millis(5),
+            interval: Duration::from_millis(100),
+            shed_below: Priority::Normal,
+        }
+    }
+}
+
+struct State {
+    // When the time in queue first went above the target plus the interval, if it is
+    // above the target.
+    above_until: Option<Instant>,
+    overloaded: bool,
+}
+
+/// CoDel style load shedding.
+///
+/// Every request leaving the queue reports how long it waited. Once that time has stayed
+/// above the target for a whole interval we are overloaded, and requests below the shed
+/// priority are rejected, both as they leave the queue and as they enter it, until a
+/// request makes it through the queue within the target again. Short bursts are absorbed
+/// by the queue, while standing queues are drained by shedding the least important work.
+pub struct Shedder {
+    options: Options,
+    state: Mutex<State>,
+}
+
+impl Shedder {
+    pub fn new(options: Options) -> Self {
+        Shedder {
+            options,
+            state: Mutex::new(State {
+                above_until: None,
+                overloaded: false,
+            }),
+        }
+    }
+
+    pub fn overloaded(self: &Self) -> bool {
+        self.state.lock().unwrap().overloaded
+    }
+
+    /// Called as a request enters the queue. Returns whether it should be queued.
+    pub fn admit_early(self: &Self, msg: &protos::Message) -> bool {
+        !(self.overloaded() && Priority::of(msg) < self.options.shed_below)
+    }
+
+    /// Called as a request leaves the queue. Returns whether it should be handled.
+    pub fn admit(self: &Self, msg: &protos::Message, enqueued: Instant) -> bool {
+        let now = Instant::now();
+        self.dequeue(Priority::of(msg), now.duration_since(enqueued), now)
+    }
+
+    fn dequeue(self: &Self, priority: Priority, sojourn: Duration, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if sojourn < self.options.target {
+            state.above_until = None;
+            state.overloaded = false;
+        } else {
+            match state.above_until {
+                None => state.above_until = Some(now + self.options.interval),
+                Some(until) if now >= until => state.overloaded = true,
+                Some(_) => {}
+            }
+        }
+        !(state.overloaded && priority < self.options.shed_below)
+    }
+}
+
+/// The response sent for a shed request.
+pub fn rejection() -> protos::Message {
+    Status::new(Code::Unavailable, "server overloaded, try again later").to_message()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shedding::{Options, Priority, Shedder};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn verify_shedding() {
+        let shedder = Shedder::new(Options::default());
+        let slow = Duration::from_millis(20);
+        let fast = Duration::from_millis(1);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // A short burst above the target is tolerated.
+        assert!(shedder.dequeue(Priority::Low, slow, at(0)));
+        assert!(shedder.dequeue(Priority::Low, slow, at(50)));
+        assert!(!shedder.overloaded());
+
+        // Staying above the target for a whole interval sheds low priority work only.
+        assert!(!shedder.dequeue(Priority::Low, slow, at(100)));
+        assert!(shedder.overloaded());
+        assert!(shedder.dequeue(Priority::Normal, slow, at(110)));
+
+        // A request getting through within the target ends it.
+        assert!(shedder.dequeue(Priority::Normal, fast, at(120)));
+        assert!(!shedder.overloaded());
+        assert!(shedder.dequeue(Priority::Low, slow, at(130)));
+    }
+}
@@ -0,0 +1,157 @@
+use super::protos;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Annotation carrying the status code of a response.
+pub const CODE_ANNOTATION: &str = "status";
+/// Annotation carrying a human readable description of a non-OK status.
+pub const MESSAGE_ANNOTATION: &str = "status-message";
+
+/// Status codes attached to responses. These mirror the gRPC codes so they can be
+/// mapped onto other protocols without loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Code {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+const CODES: [(Code, &str); 17] = [
+    (Code::Ok, "OK"),
+    (Code::Cancelled, "CANCELLED"),
+    (Code::Unknown, "UNKNOWN"),
+    (Code::InvalidArgument, "INVALID_ARGUMENT"),
+    (Code::DeadlineExceeded, "DEADLINE_EXCEEDED"),
+    (Code::NotFound, "NOT_FOUND"),
+    (Code::AlreadyExists, "ALREADY_EXISTS"),
+    (Code::PermissionDenied, "PERMISSION_DENIED"),
+    (Code::ResourceExhausted, "RESOURCE_EXHAUSTED"),
+    (Code::FailedPrecondition, "FAILED_PRECONDITION"),
+    (Code::Aborted, "ABORTED"),
+    (Code::OutOfRange, "OUT_OF_RANGE"),
+    (Code::Unimplemented, "UNIMPLEMENTED"),
+    (Code::Internal, "INTERNAL"),
+    (Code::Unavailable, "UNAVAILABLE"),
+    (Code::DataLoss, "DATA_LOSS"),
+    (Code::Unauthenticated, "UNAUTHENTICATED"),
+];
+
+impl Code {
+    pub fn as_str(self: &Self) -> &'static str {
+        CODES[*self as usize].1
+    }
+
+    pub fn parse(s: &str) -> Option<Code> {
+        CODES.iter().find(|&&(_, name)| name == s).map(|&(c, _)| c)
+    }
+
+    /// The HTTP status code closest in meaning, following the mapping used by gRPC
+    /// gateways.
+    pub fn http_status(self: &Self) -> u16 {
+        match *self {
+            Code::Ok => 200,
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => 400,
+            Code::Unauthenticated => 401,
+            Code::PermissionDenied => 403,
+            Code::NotFound => 404,
+            Code::AlreadyExists | Code::Aborted => 409,
+            Code::ResourceExhausted => 429,
+            Code::Cancelled => 499,
+            Code::Unknown | Code::Internal | Code::DataLoss => 500,
+            Code::Unimplemented => 501,
+            Code::Unavailable => 503,
+            Code::DeadlineExceeded => 504,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The outcome of handling a request, carried on the response envelope as annotations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub code: Code,
+    pub message: String,
+    /// Extra annotations to attach to the response, e.g. retry hints.
+    pub annotations: HashMap<String, String>,
+}
+
+impl Status {
+    pub fn new(code: Code, message: &str) -> Self {
+        Status {
+            code,
+            message: message.to_string(),
+            annotations: HashMap::new(),
+        }
+    }
+
+    pub fn with_annotation(mut self: Self, key: &str, value: &str) -> Self {
+        self.annotations.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Reads the status of a response. Responses without a status annotation are OK.
+    pub fn of(msg: &protos::Message) -> Self {
+        let annotations = msg.get_annotations();
+        let code = annotations
+            .get(CODE_ANNOTATION)
+            .and_then(|c| Code::parse(c))
+            .unwrap_or(Code::Ok);
+        let message = annotations
+            .get(MESSAGE_ANNOTATION)
+            .cloned()
+            .unwrap_or_default();
+        Status {
+            code,
+            message,
+            annotations: HashMap::new(),
+        }
+    }
+
+    /// Writes this status onto an existing response.
+    pub fn apply(self: &Self, msg: &mut protos::Message) {
+        let annotations = msg.mut_annotations();
+        for (k, v) in self.annotations.iter() {
+            annotations.insert(k.clone(), v.clone());
+        }
+        annotations.insert(CODE_ANNOTATION.to_string(), self.code.as_str().to_string());
+        if !self.message.is_empty() {
+            annotations.insert(MESSAGE_ANNOTATION.to_string(), self.message.clone());
+        }
+    }
+
+    /// Creates an empty response carrying this status.
+    pub fn to_message(self: &Self) -> protos::Message {
+        let mut m = protos::Message::new();
+        self.apply(&mut m);
+        m
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.code)
+        } else {
+            write!(f, "{}: {}", self.code, self.message)
+        }
+    }
+}
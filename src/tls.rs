@@ -0,0 +1,563 @@
+extern crate rustls;
+extern crate webpki;
+extern crate x509_parser;
+
+use self::rustls::internal::pemfile;
+use self::rustls::sign::{self, CertifiedKey};
+use self::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientSession, NoClientAuth,
+    PrivateKey, ResolvesServerCert, RootCertStore, ServerConfig, ServerSession, Session,
+    SignatureScheme,
+};
+use self::x509_parser::extensions::GeneralName;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use super::net::Connection;
+use super::protos;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Annotation carrying the subject of a verified client certificate.
+pub const PEER_SUBJECT_ANNOTATION: &str = "peer-subject";
+/// Annotation carrying the comma separated subject alternative names of a verified client
+/// certificate. Commas and percent signs within a name are percent-encoded.
+pub const PEER_SAN_ANNOTATION: &str = "peer-san";
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Reads every certificate from a PEM file.
+pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() => Err(invalid_data(format!(
+            "no certificates found in {}",
+            path.display()
+        ))),
+        Ok(certs) => Ok(certs),
+        Err(_) => Err(invalid_data(format!("invalid PEM in {}", path.display()))),
+    }
+}
+
+/// Reads the first PKCS8 or RSA private key from a PEM file.
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", path.display())))
+}
+
+fn load_certified_key(cert_chain: &Path, private_key: &Path) -> io::Result<CertifiedKey> {
+    let certs = load_certs(cert_chain)?;
+    let key = load_private_key(private_key)?;
+    let signing_key = sign::any_supported_type(&key).map_err(|_| {
+        invalid_data(format!("unsupported private key in {}", private_key.display()))
+    })?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+// Picks the certificate to present based on the SNI hostname sent by the client, falling
+// back to the default certificate.
+struct SniResolver {
+    default: CertifiedKey,
+    by_name: HashMap<String, CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let by_name = server_name.and_then(|name| {
+            let name: &str = name.into();
+            self.by_name.get(&name.to_lowercase())
+        });
+        Some(by_name.unwrap_or(&self.default).clone())
+    }
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let mut reader = BufReader::new(File::open(path)?);
+    match roots.add_pem_file(&mut reader) {
+        Ok((added, _)) if added > 0 => Ok(roots),
+        _ => Err(invalid_data(format!(
+            "no CA certificates found in {}",
+            path.display()
+        ))),
+    }
+}
+
+// Keeps the separator of the SAN annotation out of the names in it.
+fn escape_san(name: &str) -> String {
+    name.replace('%', "%25").replace(',', "%2C")
+}
+
+fn unescape_san(name: &str) -> String {
+    name.replace("%2C", ",").replace("%25", "%")
+}
+
+/// The identity of a peer that presented a verified certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerIdentity {
+    pub subject: String,
+    /// DNS, URI and email subject alternative names.
+    pub sans: Vec<String>,
+}
+
+impl PeerIdentity {
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let sans = match cert.tbs_certificate.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|n| match *n {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    GeneralName::RFC822Name(email) => Some(email.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(PeerIdentity {
+            subject: cert.subject().to_string(),
+            sans,
+        })
+    }
+
+    /// Reads the identity of the peer from the end entity certificate of a session, once
+    /// the handshake has completed.
+    pub fn of_session(session: &dyn Session) -> Option<Self> {
+        if session.is_handshaking() {
+            return None;
+        }
+        session
+            .get_peer_certificates()
+            .and_then(|certs| certs.first().and_then(|c| PeerIdentity::from_der(&c.0)))
+    }
+
+    /// Reads the identity the server attached to a message.
+    pub fn of(msg: &protos::Message) -> Option<Self> {
+        let annotations = msg.get_annotations();
+        annotations.get(PEER_SUBJECT_ANNOTATION).map(|subject| PeerIdentity {
+            subject: subject.clone(),
+            sans: annotations
+                .get(PEER_SAN_ANNOTATION)
+                .map(|s| s.split(',').map(unescape_san).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn annotate(self: &Self, msg: &mut protos::Message) {
+        let annotations = msg.mut_annotations();
+        annotations.insert(PEER_SUBJECT_ANNOTATION.to_string(), self.subject.clone());
+        if !self.sans.is_empty() {
+            let sans: Vec<String> = self.sans.iter().map(|n| escape_san(n)).collect();
+            annotations.insert(PEER_SAN_ANNOTATION.to_string(), sans.join(","));
+        }
+    }
+
+    /// Removes any identity annotations from a message, so peers can't claim an identity
+    /// they weren't verified for.
+    pub fn strip(msg: &mut protos::Message) {
+        let annotations = msg.mut_annotations();
+        annotations.remove(PEER_SUBJECT_ANNOTATION);
+        annotations.remove(PEER_SAN_ANNOTATION);
+    }
+}
+
+/// Server side TLS settings.
+#[derive(Clone)]
+pub struct ServerTls {
+    default: CertifiedKey,
+    by_name: HashMap<String, CertifiedKey>,
+    alpn: Vec<Vec<u8>>,
+    client_roots: Option<RootCertStore>,
+}
+
+impl ServerTls {
+    /// Loads the default certificate chain and its private key from PEM files.
+    pub fn from_pem(cert_chain: &Path, private_key: &Path) -> io::Result<Self> {
+        Ok(ServerTls {
+            default: load_certified_key(cert_chain, private_key)?,
+            by_name: HashMap::new(),
+            alpn: Vec::new(),
+            client_roots: None,
+        })
+    }
+
+    /// Presents a different certificate to clients asking for `hostname` through SNI.
+    pub fn add_sni_pem(
+        self: &mut Self,
+        hostname: &str,
+        cert_chain: &Path,
+        private_key: &Path,
+    ) -> io::Result<()> {
+        let key = load_certified_key(cert_chain, private_key)?;
+        self.by_name.insert(hostname.to_lowercase(), key);
+        Ok(())
+    }
+
+    /// Sets the ALPN protocols offered to clients, in order of preference.
+    pub fn set_alpn(self: &mut Self, protocols: &[&str]) {
+        self.alpn = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+    }
+
+    /// Requires clients to present a certificate signed by one of the CAs in the given PEM
+    /// file. The verified identity is attached to every message from the client.
+    pub fn require_client_cert(self: &mut Self, ca_certs: &Path) -> io::Result<()> {
+        self.client_roots = Some(load_roots(ca_certs)?);
+        Ok(())
+    }
+
+    pub fn config(self: &Self) -> Arc<ServerConfig> {
+        let mut config = match self.client_roots {
+            Some(ref roots) => ServerConfig::new(AllowAnyAuthenticatedClient::new(roots.clone())),
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        config.cert_resolver = Arc::new(SniResolver {
+            default: self.default.clone(),
+            by_name: self.by_name.clone(),
+        });
+        config.set_protocols(&self.alpn);
+        Arc::new(config)
+    }
+}
+
+/// Client side TLS settings.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: ClientConfig,
+    server_name: String,
+}
+
+impl ClientTls {
+    /// Trusts the CA certificates in the given PEM file. `server_name` is sent through
+    /// SNI and is the name the server certificate is verified against.
+    pub fn from_pem(ca_certs: &Path, server_name: &str) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        config.root_store = load_roots(ca_certs)?;
+        webpki::DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| invalid_data(format!("invalid server name {}", server_name)))?;
+
+        Ok(ClientTls {
+            config,
+            server_name: server_name.to_string(),
+        })
+    }
+
+    /// Presents the given certificate chain to servers that ask for a client certificate.
+    pub fn set_client_cert_pem(
+        self: &mut Self,
+        cert_chain: &Path,
+        private_key: &Path,
+    ) -> io::Result<()> {
+        let certs = load_certs(cert_chain)?;
+        let key = load_private_key(private_key)?;
+        self.config.set_single_client_cert(certs, key);
+        Ok(())
+    }
+
+    /// Sets the ALPN protocols to offer to the server, in order of preference.
+    pub fn set_alpn(self: &mut Self, protocols: &[&str]) {
+        let protocols: Vec<Vec<u8>> = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        self.config.set_protocols(&protocols);
+    }
+
+    pub fn session(self: &Self) -> ClientSession {
+        // The name was validated when the settings were created.
+        let name = webpki::DNSNameRef::try_from_ascii_str(&self.server_name).unwrap();
+        ClientSession::new(&Arc::new(self.config.clone()), name)
+    }
+}
+
+/// A TLS session running over a non-blocking connection.
+///
+/// Reads return `WouldBlock` until decrypted data is available, writes are buffered by
+/// the session and flushed to the socket as far as it accepts them.
+pub struct TlsStream {
+    session: Box<dyn Session>,
+    sock: Box<dyn Connection>,
+    eof: bool,
+}
+
+impl TlsStream {
+    pub fn server(sock: Box<dyn Connection>, config: &Arc<ServerConfig>) -> Self {
+        TlsStream {
+            session: Box::new(ServerSession::new(config)),
+            sock,
+            eof: false,
+        }
+    }
+
+    pub fn client(sock: Box<dyn Connection>, tls: &ClientTls) -> Self {
+        TlsStream {
+            session: Box::new(tls.session()),
+            sock,
+            eof: false,
+        }
+    }
+
+    pub fn sock(self: &Self) -> &dyn Connection {
+        &*self.sock
+    }
+
+    pub fn session(self: &Self) -> &dyn Session {
+        &*self.session
+    }
+
+    // Writes as much buffered TLS data to the socket as it will take.
+    fn flush_tls(self: &mut Self) -> io::Result<()> {
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut self.sock) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Feeds everything available on the socket into the session.
+    fn fill(self: &mut Self) -> io::Result<()> {
+        while !self.eof && self.session.wants_read() {
+            match self.session.read_tls(&mut self.sock) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {
+                    self.session
+                        .process_new_packets()
+                        .map_err(|e| invalid_data(format!("tls error: {}", e)))?;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        // Handshake messages and alerts have to go out even if we never write any data.
+        self.flush_tls()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill()?;
+        match self.session.read(buf) {
+            Ok(0) if !self.eof => Err(io::Error::from(ErrorKind::WouldBlock)),
+            r => r,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.session.write(buf)?;
+        self.flush_tls()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        self.flush_tls()
+    }
+}
+
+/// A `TlsStream` usable as a `Connection`. Clones share the same session, so one thread
+/// can read while another one writes.
+pub struct TlsConnection {
+    stream: Arc<Mutex<TlsStream>>,
+    // The socket the connection is polled through. Every clone has its own, so the clones
+    // can be registered with different polls.
+    evented: Box<dyn Connection>,
+}
+
+impl TlsConnection {
+    pub fn new(stream: TlsStream) -> io::Result<Self> {
+        let evented = stream.sock().try_clone()?;
+        Ok(TlsConnection {
+            stream: Arc::new(Mutex::new(stream)),
+            evented,
+        })
+    }
+}
+
+impl Connection for TlsConnection {
+    fn try_clone(self: &Self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(TlsConnection {
+            stream: self.stream.clone(),
+            evented: self.stream.lock().unwrap().sock().try_clone()?,
+        }))
+    }
+
+    fn shutdown(self: &Self, how: Shutdown) -> io::Result<()> {
+        self.stream.lock().unwrap().sock().shutdown(how)
+    }
+
+    fn peer_identity(self: &Self) -> Option<PeerIdentity> {
+        PeerIdentity::of_session(self.stream.lock().unwrap().session())
+    }
+}
+
+impl Read for TlsConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for TlsConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.lock().unwrap().flush()
+    }
+}
+
+impl Evented for TlsConnection {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.evented.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.evented.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.evented.deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rcgen;
+
+    use self::rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use super::rustls::{ClientSession, ServerSession, Session};
+    use crate::protos;
+    use crate::tls::{ClientTls, PeerIdentity, ServerTls};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    // Writes a CA certificate and a leaf certificate for `hostname` signed by it into a
+    // fresh directory, returning (ca, cert, key) paths.
+    fn write_certs(name: &str, hostname: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("rplay-tls-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let mut leaf_params = CertificateParams::new(vec![hostname.to_string()]);
+        leaf_params
+            .distinguished_name
+            .push(DnType::CommonName, hostname);
+        let leaf = Certificate::from_params(leaf_params).unwrap();
+
+        let paths = (dir.join("ca.pem"), dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&paths.0, ca.serialize_pem().unwrap()).unwrap();
+        fs::write(&paths.1, leaf.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+        fs::write(&paths.2, leaf.serialize_private_key_pem()).unwrap();
+        paths
+    }
+
+    fn transfer(from: &mut dyn Session, to: &mut dyn Session) {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        to.read_tls(&mut &buf[..]).unwrap();
+        to.process_new_packets().unwrap();
+    }
+
+    fn handshake(server: &ServerTls, client: &ClientTls) -> (ServerSession, ClientSession) {
+        let mut s = ServerSession::new(&server.config());
+        let mut c = client.session();
+        while s.is_handshaking() || c.is_handshaking() {
+            transfer(&mut c, &mut s);
+            transfer(&mut s, &mut c);
+        }
+        (s, c)
+    }
+
+    #[test]
+    fn verify_sni_and_alpn() {
+        let (default_ca, default_cert, default_key) = write_certs("default", "localhost");
+        let (other_ca, other_cert, other_key) = write_certs("other", "other.test");
+
+        let mut server = ServerTls::from_pem(&default_cert, &default_key).unwrap();
+        server
+            .add_sni_pem("other.test", &other_cert, &other_key)
+            .unwrap();
+        server.set_alpn(&["rplay/1"]);
+
+        let mut client = ClientTls::from_pem(&default_ca, "localhost").unwrap();
+        client.set_alpn(&["rplay/1"]);
+        let (_, c) = handshake(&server, &client);
+        assert_eq!(c.get_alpn_protocol(), Some(&b"rplay/1"[..]));
+
+        // Only the SNI certificate is trusted, so the handshake completing means the server
+        // picked it based on the requested name.
+        let client = ClientTls::from_pem(&other_ca, "other.test").unwrap();
+        let (s, _) = handshake(&server, &client);
+        assert_eq!(s.get_sni_hostname(), Some("other.test"));
+    }
+
+    #[test]
+    fn verify_client_identity() {
+        let (server_ca, server_cert, server_key) = write_certs("mtls-server", "localhost");
+        let (client_ca, client_cert, client_key) = write_certs("mtls-client", "client.test");
+
+        let mut server = ServerTls::from_pem(&server_cert, &server_key).unwrap();
+        server.require_client_cert(&client_ca).unwrap();
+        let mut client = ClientTls::from_pem(&server_ca, "localhost").unwrap();
+        client
+            .set_client_cert_pem(&client_cert, &client_key)
+            .unwrap();
+
+        let (s, _) = handshake(&server, &client);
+        let identity = PeerIdentity::of_session(&s).unwrap();
+        assert_eq!(identity.subject, "CN=client.test");
+        assert_eq!(identity.sans, vec!["client.test".to_string()]);
+
+        // The identity survives a round trip through the message annotations, and can be
+        // stripped again.
+        let mut m = protos::Message::new();
+        identity.annotate(&mut m);
+        assert_eq!(PeerIdentity::of(&m), Some(identity));
+        let odd = PeerIdentity {
+            subject: "CN=odd".to_string(),
+            sans: vec!["mailto:a,b@test".to_string(), "100%".to_string()],
+        };
+        odd.annotate(&mut m);
+        assert_eq!(PeerIdentity::of(&m), Some(odd));
+        PeerIdentity::strip(&mut m);
+        assert_eq!(PeerIdentity::of(&m), None);
+    }
+}
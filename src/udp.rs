@@ -0,0 +1,374 @@
+use super::auth;
+use super::filter::Filter;
+use super::frame;
+use super::interceptor;
+use super::protos;
+use super::server;
+use super::server::MessageSender;
+use super::status::{Code, Status};
+use protobuf::Message;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::result;
+use std::sync::mpsc::{SendError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Marks a message that doesn't expect a response. Responses to it are dropped instead of
+/// being sent back.
+pub const ONE_WAY_ANNOTATION: &str = "one-way";
+/// Copied from a request onto its responses, so clients can match them up.
+pub const REQUEST_ID_ANNOTATION: &str = "request-id";
+
+/// The largest payload a UDP datagram can carry.
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Optional behaviour for a `UdpServer`.
+pub struct Options {
+    /// Larger datagrams are dropped, and responses that would be larger are replaced by a
+    /// RESOURCE_EXHAUSTED status.
+    pub max_datagram_size: usize,
+    /// When set, every message has to carry a bearer token accepted by the authenticator.
+    pub authenticator: Option<Arc<dyn auth::Authenticator>>,
+    /// Interceptors run before a message is dispatched, like the server's.
+    pub interceptors: Arc<interceptor::Chain>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            authenticator: None,
+            interceptors: Arc::new(interceptor::Chain::new()),
+        }
+    }
+}
+
+/// Sends responses back to the address a datagram came from.
+#[derive(Clone)]
+pub struct UdpSender {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    request_id: Option<String>,
+    one_way: bool,
+    max_datagram_size: usize,
+}
+
+fn encode(m: &protos::Message) -> io::Result<Vec<u8>> {
+    m.write_to_bytes()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+impl UdpSender {
+    fn reply(self: &Self, msg: &protos::Message) -> io::Result<()> {
+        let with_id = |mut m: protos::Message| {
+            if let Some(ref id) = self.request_id {
+                m.mut_annotations()
+                    .insert(REQUEST_ID_ANNOTATION.to_string(), id.clone());
+            }
+            m
+        };
+        let mut m = with_id(msg.clone());
+        if m.compute_size() as usize > self.max_datagram_size {
+            m = with_id(
+                Status::new(
+                    Code::ResourceExhausted,
+                    "the response doesn't fit in a datagram",
+                )
+                .to_message(),
+            );
+        }
+        self.socket.send_to(&encode(&m)?, self.peer).map(|_| ())
+    }
+}
+
+impl MessageSender for UdpSender {
+    // Datagrams may be lost anyway, so failing to send one isn't reported back.
+    fn send(
+        self: &Self,
+        msg: Arc<protos::Message>,
+    ) -> result::Result<(), SendError<Arc<protos::Message>>> {
+        if self.one_way {
+            return Ok(());
+        }
+        if let Err(e) = self.reply(&msg) {
+            println!("failed to send response to {}: {}", self.peer, e);
+        }
+        Ok(())
+    }
+}
+
+struct Listener {
+    sender: SyncSender<(Arc<protos::Message>, UdpSender)>,
+    filter: Filter,
+}
+
+/// Receives messages over UDP, one `protos::Message` per datagram, and hands them to its
+/// listeners along with a `UdpSender` answering the address they came from. Listeners are
+/// usually `Dispatcher`s, exactly like for a `Server`.
+///
+/// There is no backpressure: when a listener is full the message is dropped, and answered
+/// with UNAVAILABLE unless it is one-way.
+pub struct UdpServer {
+    socket: Arc<UdpSocket>,
+    listeners: Vec<Listener>,
+    options: Options,
+}
+
+impl UdpServer {
+    pub fn bind(addr: &SocketAddr, options: Options) -> io::Result<Self> {
+        Ok(UdpServer {
+            socket: Arc::new(UdpSocket::bind(addr)?),
+            listeners: Vec::new(),
+            options,
+        })
+    }
+
+    pub fn local_addr(self: &Self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn add_listener(self: &mut Self, l: SyncSender<(Arc<protos::Message>, UdpSender)>) {
+        self.add_filtered_listener(l, Filter::new());
+    }
+
+    /// Adds a listener that only receives the messages matched by `filter`.
+    pub fn add_filtered_listener(
+        self: &mut Self,
+        l: SyncSender<(Arc<protos::Message>, UdpSender)>,
+        filter: Filter,
+    ) {
+        self.listeners.push(Listener { sender: l, filter });
+    }
+
+    /// Receives datagrams on the calling thread, forever.
+    pub fn start(self: &mut Self) {
+        // One byte more than we accept, so oversized datagrams aren't silently truncated.
+        let mut buffer = vec![0; self.options.max_datagram_size + 1];
+        loop {
+            let (size, peer) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("failed to receive datagram: {}", e);
+                    continue;
+                }
+            };
+            if size > self.options.max_datagram_size {
+                println!("dropping oversized datagram from {}", peer);
+                continue;
+            }
+            match frame::decode_message(&buffer[..size]) {
+                Ok(m) => self.handle_message(m, peer),
+                Err(e) => println!("dropping datagram from {}: {}", peer, e),
+            }
+        }
+    }
+
+    fn handle_message(self: &mut Self, mut m: protos::Message, peer: SocketAddr) {
+        server::strip_identities(&mut m);
+        m.mut_annotations()
+            .insert(server::PEER_ADDR_ANNOTATION.to_string(), peer.to_string());
+        let sender = UdpSender {
+            socket: self.socket.clone(),
+            peer,
+            request_id: m.get_annotations().get(REQUEST_ID_ANNOTATION).cloned(),
+            one_way: m.get_annotations().contains_key(ONE_WAY_ANNOTATION),
+            max_datagram_size: self.options.max_datagram_size,
+        };
+        if let Err(rejected) = server::admit(
+            self.options.authenticator.as_ref(),
+            &self.options.interceptors,
+            &mut m,
+        ) {
+            let _ = sender.send(Arc::new(rejected));
+            return;
+        }
+
+        let msg = Arc::new(m);
+        let mut matched = false;
+        let mut delivered = false;
+        self.listeners.retain(|l| {
+            if !l.filter.matches(&msg) {
+                return true;
+            }
+            matched = true;
+            match l.sender.try_send((msg.clone(), sender.clone())) {
+                Ok(_) => delivered = true,
+                Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => {
+                    println!("removing closed listener");
+                    return false;
+                }
+            }
+            true
+        });
+
+        if !matched {
+            let message = format!("nothing handles {}", msg.get_method());
+            let status = Status::new(Code::Unimplemented, &message);
+            let _ = sender.send(Arc::new(status.to_message()));
+        } else if !delivered {
+            println!("dropping message from {}: listeners are full", peer);
+            let status = Status::new(Code::Unavailable, "the server is overloaded");
+            let _ = sender.send(Arc::new(status.to_message()));
+        }
+    }
+}
+
+/// Sends messages to a `UdpServer`.
+pub struct UdpClient {
+    socket: UdpSocket,
+    next_id: u64,
+}
+
+impl UdpClient {
+    pub fn connect(addr: &SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = match *addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(UdpClient { socket, next_id: 0 })
+    }
+
+    fn send_message(self: &Self, m: &protos::Message) -> io::Result<()> {
+        self.socket.send(&encode(m)?).map(|_| ())
+    }
+
+    /// Sends a message without waiting for, or receiving, a response.
+    pub fn send(self: &Self, mut m: protos::Message) -> io::Result<()> {
+        m.mut_annotations()
+            .insert(ONE_WAY_ANNOTATION.to_string(), "true".to_string());
+        self.send_message(&m)
+    }
+
+    /// Sends a request and waits for its response. Either of them may be lost, in which
+    /// case this times out and it is up to the caller whether to try again.
+    pub fn call(
+        self: &mut Self,
+        mut m: protos::Message,
+        timeout: Duration,
+    ) -> io::Result<protos::Message> {
+        let id = self.next_id.to_string();
+        self.next_id += 1;
+        m.mut_annotations()
+            .insert(REQUEST_ID_ANNOTATION.to_string(), id.clone());
+        self.send_message(&m)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "timed out waiting for a response",
+                ));
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let size = match self.socket.recv(&mut buffer) {
+                Ok(size) => size,
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            // Late responses to earlier calls are skipped.
+            match frame::decode_message(&buffer[..size]) {
+                Ok(response) => {
+                    if response.get_annotations().get(REQUEST_ID_ANNOTATION) == Some(&id) {
+                        return Ok(response);
+                    }
+                }
+                Err(e) => println!("dropping datagram: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth;
+    use crate::protos;
+    use crate::server::MessageSender;
+    use crate::status::{Code, Status};
+    use crate::udp::{Options, UdpClient, UdpServer, ONE_WAY_ANNOTATION};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn start(with_listener: bool) -> (UdpClient, Option<mpsc::Receiver<protos::Message>>) {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = UdpServer::bind(&addr, Options::default()).unwrap();
+        let client = UdpClient::connect(&server.local_addr().unwrap()).unwrap();
+
+        let mut received = None;
+        if with_listener {
+            let (sender, receiver) = mpsc::sync_channel(16);
+            server.add_listener(sender);
+            let (seen_sender, seen) = mpsc::channel();
+            received = Some(seen);
+            // Echoes every message back.
+            thread::spawn(move || {
+                for (msg, sender) in receiver.iter() {
+                    seen_sender.send((*msg).clone()).unwrap();
+                    let mut response = protos::Message::new();
+                    response.set_body(msg.get_body().to_vec());
+                    sender.send(Arc::new(response)).unwrap();
+                }
+            });
+        }
+        thread::spawn(move || server.start());
+        (client, received)
+    }
+
+    #[test]
+    fn verify_call() {
+        let (mut client, received) = start(true);
+        let mut m = protos::Message::new();
+        m.set_method("echo".to_string());
+        m.set_body(b"hello".to_vec());
+        m.mut_annotations()
+            .insert(auth::IDENTITY_ANNOTATION.to_string(), "admin".to_string());
+        let response = client.call(m, Duration::from_secs(5)).unwrap();
+        assert_eq!(response.get_body(), b"hello");
+
+        let request = received.unwrap().recv().unwrap();
+        assert!(request.get_annotations().contains_key("peer-addr"));
+        assert!(!request
+            .get_annotations()
+            .contains_key(auth::IDENTITY_ANNOTATION));
+    }
+
+    #[test]
+    fn verify_one_way() {
+        let (mut client, received) = start(true);
+        let mut m = protos::Message::new();
+        m.set_method("metric".to_string());
+        client.send(m.clone()).unwrap();
+
+        // Keep the receiver around, the echo thread panics once it is gone.
+        let received = received.unwrap();
+        let request = received.recv().unwrap();
+        assert!(request.get_annotations().contains_key(ONE_WAY_ANNOTATION));
+        // The echoed response to the one-way message is never sent, so the next call
+        // only sees its own.
+        m.set_body(b"second".to_vec());
+        let response = client.call(m, Duration::from_secs(5)).unwrap();
+        assert_eq!(response.get_body(), b"second");
+    }
+
+    #[test]
+    fn verify_unhandled() {
+        let (mut client, _) = start(false);
+        let mut m = protos::Message::new();
+        m.set_method("missing".to_string());
+        let response = client.call(m, Duration::from_secs(5)).unwrap();
+        assert_eq!(Status::of(&response).code, Code::Unimplemented);
+    }
+}
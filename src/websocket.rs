@@ -0,0 +1,366 @@
+extern crate base64;
+extern crate httparse;
+extern crate sha1;
+
+use self::sha1::{Digest, Sha1};
+use std::io;
+use std::io::ErrorKind;
+
+// Appended to the client's key to compute the accept key, per RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The subprotocol clients may ask for.
+pub const SUBPROTOCOL: &str = "rplay";
+
+// The most bytes we buffer for the upgrade request.
+const MAX_UPGRADE_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+const FIN: u8 = 0x80;
+const RSV: u8 = 0x70;
+const MASK: u8 = 0x80;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close code sent when the client violates the protocol.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Close code answering a clean close.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// Settings for WebSocket connections.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// When set, upgrade requests from browsers are only accepted from these origins,
+    /// e.g. `https://example.com`. Requests without an `Origin` header don't come from a
+    /// browser and are always accepted.
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+/// The key a server answers an upgrade request carrying `key` with.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input(key.trim().as_bytes());
+    sha1.input(GUID.as_bytes());
+    base64::encode(&sha1.result())
+}
+
+/// The outcome of reading an upgrade request.
+#[derive(Debug, PartialEq)]
+pub enum Upgrade {
+    /// The request hasn't been received completely yet.
+    Partial,
+    /// The request was accepted. It took up the first `len` bytes, and has to be answered
+    /// with `response` before any frames.
+    Accepted { len: usize, response: Vec<u8> },
+    /// The request was rejected with `response`, after which the connection is closed.
+    Rejected(Vec<u8>),
+}
+
+fn reject(status: &str, extra: &str) -> Upgrade {
+    Upgrade::Rejected(
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, extra
+        )
+        .into_bytes(),
+    )
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Reads the HTTP upgrade request a WebSocket connection starts with.
+pub fn upgrade(buf: &[u8], options: &Options) -> Upgrade {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) if buf.len() > MAX_UPGRADE_SIZE => {
+            return reject("431 Request Header Fields Too Large", "")
+        }
+        Ok(httparse::Status::Partial) => return Upgrade::Partial,
+        Err(_) => return reject("400 Bad Request", ""),
+    };
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+
+    if req.method != Some("GET")
+        || !has_token(header("upgrade"), "websocket")
+        || !has_token(header("connection"), "upgrade")
+    {
+        return reject("400 Bad Request", "");
+    }
+    if header("sec-websocket-version").map(|v| v.trim()) != Some("13") {
+        return reject("426 Upgrade Required", "Sec-WebSocket-Version: 13\r\n");
+    }
+    let key = match header("sec-websocket-key") {
+        Some(key) => key,
+        None => return reject("400 Bad Request", ""),
+    };
+    if let (Some(origin), Some(allowed)) = (header("origin"), &options.allowed_origins) {
+        if !allowed.iter().any(|a| a == origin) {
+            return reject("403 Forbidden", "");
+        }
+    }
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n",
+        accept_key(key)
+    );
+    if has_token(header("sec-websocket-protocol"), SUBPROTOCOL) {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", SUBPROTOCOL));
+    }
+    response.push_str("\r\n");
+    Upgrade::Accepted {
+        len,
+        response: response.into_bytes(),
+    }
+}
+
+/// A complete message received from a client.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Splits the bytes received from a client into messages, reassembling fragmented ones.
+pub struct Decoder {
+    buf: Vec<u8>,
+    max_message_size: usize,
+    // The opcode and data of a fragmented message we are still receiving.
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl Decoder {
+    pub fn new(max_message_size: usize) -> Self {
+        Decoder {
+            buf: Vec::new(),
+            max_message_size,
+            fragments: None,
+        }
+    }
+
+    pub fn extend(self: &mut Self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Whether part of a message has been received.
+    pub fn has_partial(self: &Self) -> bool {
+        !self.buf.is_empty() || self.fragments.is_some()
+    }
+
+    // Parses the header of the next frame, returning its length and the length of its
+    // payload.
+    fn header(self: &Self) -> io::Result<Option<(usize, usize)>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let (b0, b1) = (self.buf[0], self.buf[1]);
+        if b0 & RSV != 0 {
+            return Err(invalid_data("unexpected websocket extension bits"));
+        }
+        if b1 & MASK == 0 {
+            return Err(invalid_data("client websocket frames must be masked"));
+        }
+        let (ext, len) = match b1 & 0x7f {
+            126 if self.buf.len() >= 4 => {
+                (2, u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64)
+            }
+            127 if self.buf.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.buf[2..10]);
+                (8, u64::from_be_bytes(len))
+            }
+            126 | 127 => return Ok(None),
+            len => (0, len as u64),
+        };
+        if b0 & 0x08 != 0 && (b0 & FIN == 0 || len > 125) {
+            return Err(invalid_data("invalid websocket control frame"));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(invalid_data("websocket message too large"));
+        }
+        Ok(Some((2 + ext + 4, len as usize)))
+    }
+
+    /// Returns the next complete message, if any.
+    pub fn next(self: &mut Self) -> io::Result<Option<Message>> {
+        loop {
+            let (header_len, len) = match self.header()? {
+                Some(h) => h,
+                None => return Ok(None),
+            };
+            if self.buf.len() < header_len + len {
+                return Ok(None);
+            }
+            let b0 = self.buf[0];
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&self.buf[header_len - 4..header_len]);
+            let mut payload: Vec<u8> = self
+                .buf
+                .drain(..header_len + len)
+                .skip(header_len)
+                .collect();
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+
+            let fin = b0 & FIN != 0;
+            let opcode = b0 & 0x0f;
+            match opcode {
+                OP_PING => return Ok(Some(Message::Ping(payload))),
+                OP_PONG => return Ok(Some(Message::Pong(payload))),
+                OP_CLOSE => return Ok(Some(Message::Close)),
+                OP_CONTINUATION => {
+                    let (opcode, mut data) = match self.fragments.take() {
+                        Some(f) => f,
+                        None => return Err(invalid_data("unexpected websocket continuation")),
+                    };
+                    data.extend_from_slice(&payload);
+                    if data.len() > self.max_message_size {
+                        return Err(invalid_data("websocket message too large"));
+                    }
+                    if fin {
+                        return complete(opcode, data).map(Some);
+                    }
+                    self.fragments = Some((opcode, data));
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(invalid_data("interleaved websocket messages"));
+                    }
+                    if fin {
+                        return complete(opcode, payload).map(Some);
+                    }
+                    self.fragments = Some((opcode, payload));
+                }
+                _ => return Err(invalid_data("unknown websocket opcode")),
+            }
+        }
+    }
+}
+
+fn complete(opcode: u8, data: Vec<u8>) -> io::Result<Message> {
+    if opcode == OP_TEXT {
+        return Err(invalid_data(
+            "text websocket messages aren't supported, envelopes are sent as binary messages",
+        ));
+    }
+    Ok(Message::Binary(data))
+}
+
+// Encodes an unmasked frame, as sent by servers.
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(FIN | opcode);
+    if payload.len() < 126 {
+        out.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn binary(payload: &[u8]) -> Vec<u8> {
+    encode(OP_BINARY, payload)
+}
+
+pub fn ping(payload: &[u8]) -> Vec<u8> {
+    encode(OP_PING, payload)
+}
+
+pub fn pong(payload: &[u8]) -> Vec<u8> {
+    encode(OP_PONG, payload)
+}
+
+pub fn close(code: u16) -> Vec<u8> {
+    encode(OP_CLOSE, &code.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::{accept_key, upgrade, Decoder, Message, Options, Upgrade};
+
+    // Masks a frame the way a client would.
+    fn client_frame(b0: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut out = vec![b0, 0x80 | payload.len() as u8];
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    #[test]
+    fn verify_accept_key() {
+        // The example from RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn verify_upgrade() {
+        let request = b"GET /rplay HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                        Connection: keep-alive, Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\nOrigin: https://evil.example\r\n\r\n";
+        assert_eq!(upgrade(&request[..20], &Options::default()), Upgrade::Partial);
+        match upgrade(request, &Options::default()) {
+            Upgrade::Accepted { len, response } => {
+                assert_eq!(len, request.len());
+                let response = String::from_utf8(response).unwrap();
+                assert!(response.starts_with("HTTP/1.1 101"));
+                assert!(response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let options = Options {
+            allowed_origins: Some(vec!["https://example.com".to_string()]),
+        };
+        match upgrade(request, &options) {
+            Upgrade::Rejected(response) => assert!(response.starts_with(b"HTTP/1.1 403")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verify_fragmented_message() {
+        let mut decoder = Decoder::new(1024);
+        decoder.extend(&client_frame(0x02, b"hel"));
+        decoder.extend(&client_frame(0x89, b"p"));
+        assert_eq!(decoder.next().unwrap(), Some(Message::Ping(b"p".to_vec())));
+        assert_eq!(decoder.next().unwrap(), None);
+        assert!(decoder.has_partial());
+
+        decoder.extend(&client_frame(0x80, b"lo"));
+        assert_eq!(decoder.next().unwrap(), Some(Message::Binary(b"hello".to_vec())));
+        assert!(!decoder.has_partial());
+
+        decoder.extend(&client_frame(0x81, b"text"));
+        assert!(decoder.next().is_err());
+    }
+}